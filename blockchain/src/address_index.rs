@@ -0,0 +1,218 @@
+use block::Block;
+use events::ChainEvent;
+use events::ChainListener;
+use std::collections::HashMap;
+use undo::BlockUndo;
+
+/// Whether address got or gave away coins in transaction
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    Received,
+    Spent,
+}
+
+/// Transaction output which was paid to address or spent by it
+#[derive(Clone, PartialEq, Debug)]
+pub struct AddressRecord {
+    pub tx_hash: [u8; 32],
+    /// Height of active chain block which includes transaction
+    pub height: usize,
+    pub direction: Direction,
+    pub amount: f64,
+}
+
+/// Optional index of active chain transactions by address. It follows
+/// chain through chain events, so it should be subscribed to chain
+/// with `Blockchain::subscribe` before queries.
+pub struct AddressIndex {
+    /// Records of every address in order of active chain
+    records: HashMap<Vec<u8>, Vec<AddressRecord>>,
+}
+
+impl AddressIndex {
+    pub fn new() -> Self {
+        AddressIndex {
+            records: HashMap::new(),
+        }
+    }
+
+    /// Number of records of address
+    pub fn history_len(&self, address: &[u8]) -> usize {
+        self.records.get(address).map_or(0, |records| records.len())
+    }
+
+    /// Page of address records, newest first. Page 0 holds last
+    /// `page_size` records, empty page means there are no more records.
+    pub fn history(&self, address: &[u8], page: usize, page_size: usize) -> Vec<AddressRecord> {
+        match self.records.get(address) {
+            Some(records) => records
+                .iter()
+                .rev()
+                .skip(page * page_size)
+                .take(page_size)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn connect(&mut self, block: &Block, height: usize, undo: &BlockUndo) {
+        let coinbase = block.coinbase();
+
+        for tx_out in coinbase.get_outputs() {
+            self.push(&tx_out.address, coinbase.hash(), height, Direction::Received, tx_out.value);
+        }
+        for (tx, tx_undo) in block.txs().iter().zip(undo.txs.iter()) {
+            let tx_hash = tx.hash();
+
+            for spent in &tx_undo.spent {
+                let tx_out = &spent.entry.tx_out;
+                self.push(&tx_out.address, tx_hash, height, Direction::Spent, tx_out.value);
+            }
+            for tx_out in tx.get_outputs() {
+                self.push(&tx_out.address, tx_hash, height, Direction::Received, tx_out.value);
+            }
+        }
+    }
+
+    /// Drop records of block, they are the last records of every address it touched
+    fn disconnect(&mut self, block: &Block, height: usize, undo: &BlockUndo) {
+        let spent = undo.txs.iter().flat_map(|tx_undo| tx_undo.spent.iter());
+        let addresses = block
+            .coinbase()
+            .get_outputs()
+            .iter()
+            .chain(block.txs().iter().flat_map(|tx| tx.get_outputs().iter()))
+            .chain(spent.map(|spent| &spent.entry.tx_out))
+            .map(|tx_out| &tx_out.address);
+
+        for address in addresses {
+            let empty = match self.records.get_mut(address) {
+                Some(records) => {
+                    while records.last().map_or(false, |record| record.height == height) {
+                        records.pop();
+                    }
+                    records.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.records.remove(address);
+            }
+        }
+    }
+
+    fn push(
+        &mut self,
+        address: &Vec<u8>,
+        tx_hash: [u8; 32],
+        height: usize,
+        direction: Direction,
+        amount: f64,
+    ) {
+        self.records
+            .entry(address.clone())
+            .or_insert_with(Vec::new)
+            .push(AddressRecord {
+                tx_hash,
+                height,
+                direction,
+                amount,
+            });
+    }
+}
+
+impl ChainListener for AddressIndex {
+    fn on_event(&mut self, event: &ChainEvent) {
+        match *event {
+            ChainEvent::BlockConnected {
+                block,
+                height,
+                undo,
+            } => self.connect(block, height, undo),
+            ChainEvent::BlockDisconnected {
+                block,
+                height,
+                undo,
+            } => self.disconnect(block, height, undo),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod address_index_tests {
+    use super::*;
+    use blockchain::Blockchain;
+    use params::ChainParams;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use testutil::TestKey;
+    use transaction::Transaction;
+    use utxo::UTXO;
+
+    fn init_block(
+        prev_hash: &[u8; 32],
+        address: &[u8],
+        txs: Vec<Transaction>,
+        height: usize,
+    ) -> Block {
+        let mut block = Block::new(prev_hash.clone(), address.to_vec(), 25.0, height);
+
+        for tx in txs {
+            block.add_tx(tx);
+        }
+        block.finalize();
+        block
+    }
+
+    #[test]
+    fn reorg_test() {
+        let key = TestKey::new(0);
+        let alice = key.address.clone();
+        let bob = b"bob".to_vec();
+        let genesis = init_block(&[1; 32], &alice, Vec::new(), 0);
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 1;
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params);
+        let index = Rc::new(RefCell::new(AddressIndex::new()));
+        blockchain.subscribe(Box::new(index.clone())).unwrap();
+        assert_eq!(1, index.borrow().history_len(&alice));
+
+        // alice pays bob in block 1
+        let coin = UTXO::new(genesis.coinbase().hash(), 0);
+        let tx = key.spend(&[coin], &[(10.0, &bob[..]), (15.0, &alice[..])]);
+        let block1 = init_block(genesis.hash(), b"miner1", vec![tx.clone()], 1);
+        assert!(blockchain.add_block(block1.clone()));
+
+        let history = index.borrow().history(&alice, 0, 10);
+        assert_eq!(3, history.len());
+        assert_eq!(Direction::Received, history[0].direction);
+        assert_eq!(15.0, history[0].amount);
+        assert_eq!(Direction::Spent, history[1].direction);
+        assert_eq!(25.0, history[1].amount);
+        assert_eq!(tx.hash(), history[1].tx_hash);
+        assert_eq!(1, history[1].height);
+        assert_eq!(genesis.coinbase().hash(), history[2].tx_hash);
+        assert_eq!(1, index.borrow().history_len(&bob));
+
+        // paging
+        assert_eq!(history[..2].to_vec(), index.borrow().history(&alice, 0, 2));
+        assert_eq!(history[2..].to_vec(), index.borrow().history(&alice, 1, 2));
+        assert!(index.borrow().history(&alice, 2, 2).is_empty());
+
+        // longer fork from genesis without payment replaces block 1
+        let fork1 = init_block(genesis.hash(), b"miner2", Vec::new(), 1);
+        let fork2 = init_block(fork1.hash(), b"miner3", Vec::new(), 2);
+        assert!(blockchain.add_block(fork1.clone()));
+        assert!(blockchain.add_block(fork2.clone()));
+        assert_eq!(fork2.hash(), blockchain.get_max_height_block().hash());
+
+        assert_eq!(1, index.borrow().history_len(&alice));
+        assert_eq!(0, index.borrow().history_len(&bob));
+        assert_eq!(0, index.borrow().history_len(b"miner1"));
+        let history = index.borrow().history(b"miner3", 0, 10);
+        assert_eq!(vec![fork2.coinbase().hash()], vec![history[0].tx_hash]);
+        assert_eq!(2, history[0].height);
+    }
+}
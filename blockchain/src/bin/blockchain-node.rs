@@ -0,0 +1,36 @@
+extern crate blockchain;
+
+use blockchain::config::NodeConfig;
+use blockchain::node::Node;
+use std::env;
+use std::path::Path;
+use std::process;
+
+/// Run blockchain node: `blockchain-node [config.json]`.
+/// Node is stopped by RPC `stop` request.
+fn main() {
+    let config = match env::args().nth(1) {
+        Some(path) => NodeConfig::load(Path::new(&path)).unwrap_or_else(|err| {
+            eprintln!("Failed to load config {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => NodeConfig::new(),
+    };
+    let mut node = Node::open(config).unwrap_or_else(|err| {
+        eprintln!("Failed to start node: {}", err);
+        process::exit(1);
+    });
+    match node.rpc_addr() {
+        Ok(addr) => println!("RPC server listens on {}", addr),
+        Err(err) => eprintln!("RPC server address is unknown: {}", err),
+    }
+    match node.p2p_addr() {
+        Some(Ok(addr)) => println!("P2P layer listens on {}", addr),
+        Some(Err(err)) => eprintln!("P2P layer address is unknown: {}", err),
+        None => {}
+    }
+    if let Err(err) = node.run() {
+        eprintln!("Node failed: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,21 @@
+extern crate blockchain;
+
+use blockchain::simulator::SimConfig;
+use blockchain::simulator::Simulator;
+use std::env;
+use std::path::Path;
+use std::process;
+
+/// Run network simulation: `blockchain-sim [config.json]`.
+/// Report is printed as JSON.
+fn main() {
+    let config = match env::args().nth(1) {
+        Some(path) => SimConfig::load(Path::new(&path)).unwrap_or_else(|err| {
+            eprintln!("Failed to load config {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => SimConfig::new(4),
+    };
+    let report = Simulator::new(config).run();
+    println!("{}", report.to_json().pretty());
+}
@@ -1,25 +1,74 @@
+use codec::put_u32;
+use codec::put_u64;
+use codec::Decode;
+use codec::Encode;
+use codec::Reader;
 use crypto;
+use json_dump::AddressTable;
+use json_dump::FromJson;
+use json_dump::Json;
+use json_dump::ToJson;
+use params::ChainParams;
 use transaction::Transaction;
+use transaction::MIN_TX_SIZE;
 
-static COINBASE: f64 = 25.0;
+/// Block identity and link to parent, kept for blocks whose bodies are pruned.
+/// Hash is computed from header alone, so proof-of-work of header is checked
+/// without block body.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BlockHeader {
+    pub hash: [u8; 32],
+    pub prev_hash: [u8; 32],
+    /// Hash of block transactions, commits header to block body
+    pub tx_root: [u8; 32],
+    /// Number changed by miner until hash meets proof-of-work target
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn compute_hash(&self) -> [u8; 32] {
+        let mut data = self.prev_hash.to_vec();
+        data.extend(self.tx_root.iter());
+        put_u64(&mut data, self.nonce);
+        crypto::double_sha256(&data)
+    }
+}
 
+#[derive(Clone)]
 pub struct Block {
     hash: [u8; 32],
     prev_hash: [u8; 32],
+    nonce: u64,
     coinbase: Transaction,
     txs: Vec<Transaction>,
 }
 
 impl Block {
-    pub fn new(prev_hash: [u8; 32], address: Vec<u8>) -> Self {
+    /// Create block at `height` with coinbase paying `coinbase_value` to address.
+    /// Coinbase value should not exceed block subsidy plus transaction fees.
+    pub fn new(prev_hash: [u8; 32], address: Vec<u8>, coinbase_value: f64, height: usize) -> Self {
         Block {
             hash: [0; 32],
             prev_hash,
-            coinbase: Transaction::new_coinbase(COINBASE, address),
+            nonce: 0,
+            coinbase: Transaction::new_coinbase(coinbase_value, address, height),
             txs: Vec::new(),
         }
     }
 
+    /// Create finalized block from coinbase and transactions
+    pub fn from_parts(prev_hash: [u8; 32], coinbase: Transaction, txs: Vec<Transaction>) -> Self {
+        let mut block = Block {
+            hash: [0; 32],
+            prev_hash,
+            nonce: 0,
+            coinbase,
+            txs,
+        };
+        block.finalize();
+        block
+    }
+
     pub fn coinbase(&self) -> &Transaction {
         &self.coinbase
     }
@@ -32,6 +81,29 @@ impl Block {
         &self.prev_hash
     }
 
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            hash: self.hash.clone(),
+            prev_hash: self.prev_hash.clone(),
+            tx_root: self.tx_root(),
+            nonce: self.nonce,
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Set nonce found by miner and finalize block
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
+        self.finalize();
+    }
+
+    pub fn coinbase_value(&self) -> f64 {
+        self.coinbase.get_outputs().iter().map(|tx_out| tx_out.value).sum()
+    }
+
     pub fn txs(&self) -> &Vec<Transaction> {
         &self.txs
     }
@@ -44,16 +116,193 @@ impl Block {
         self.txs.push(tx);
     }
 
+    /// Canonical encoding of block transactions, hashed into transaction root
     pub fn raw_data(&self) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
-        data.extend(self.prev_hash.to_vec().iter().clone());
+        // blocks with same parent and transactions differ by coinbase
+        self.coinbase.encode(&mut data);
+        put_u32(&mut data, self.txs.len() as u32);
         for tx in &self.txs {
-            data.extend(&tx.raw_data());
+            tx.encode(&mut data);
         }
         data
     }
 
+    fn tx_root(&self) -> [u8; 32] {
+        crypto::double_sha256(&self.raw_data())
+    }
+
     pub fn finalize(&mut self) {
-        self.hash = crypto::double_sha256(&self.raw_data());
+        self.hash = self.header().compute_hash();
+    }
+
+    /// Finalize block with nonce which makes its hash meet proof-of-work target
+    pub fn mine(&mut self, params: &ChainParams) {
+        let mut header = self.header();
+
+        while !params.check_pow(&header.compute_hash()) {
+            header.nonce += 1;
+        }
+        self.set_nonce(header.nonce);
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, data: &mut Vec<u8>) {
+        data.extend(self.hash.iter());
+        data.extend(self.prev_hash.iter());
+        data.extend(self.tx_root.iter());
+        put_u64(data, self.nonce);
+    }
+}
+
+impl Decode for BlockHeader {
+    /// Header is rejected if its hash does not match its content
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let header = BlockHeader {
+            hash: reader.hash()?,
+            prev_hash: reader.hash()?,
+            tx_root: reader.hash()?,
+            nonce: reader.u64()?,
+        };
+        if header.compute_hash() != header.hash {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, data: &mut Vec<u8>) {
+        self.header().encode(data);
+        self.coinbase.encode(data);
+        put_u32(data, self.txs.len() as u32);
+
+        for tx in &self.txs {
+            tx.encode(data);
+        }
+    }
+}
+
+impl Decode for Block {
+    /// Block is rejected if its hash does not match its content
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let header = BlockHeader::decode(reader)?;
+        let coinbase = Transaction::decode(reader)?;
+        let mut txs = Vec::new();
+
+        for _ in 0..reader.count(MIN_TX_SIZE)? {
+            txs.push(Transaction::decode(reader)?);
+        }
+        let mut block = Block::from_parts(header.prev_hash, coinbase, txs);
+        block.set_nonce(header.nonce);
+
+        if block.hash != header.hash {
+            return None;
+        }
+        Some(block)
+    }
+}
+
+impl ToJson for Block {
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("hash", Json::hex(&self.hash)),
+            ("prev_hash", Json::hex(&self.prev_hash)),
+            // decimal string, JSON numbers lose nonces above 2^53
+            ("nonce", Json::String(self.nonce.to_string())),
+            ("coinbase", self.coinbase.to_json(addresses)),
+            ("txs", Json::Array(self.txs.iter().map(|tx| tx.to_json(addresses)).collect())),
+        ])
+    }
+}
+
+impl FromJson for Block {
+    /// Hash and nonce may be left out, if hash is given it must match block
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self> {
+        let prev_hash = json.get("prev_hash")?.as_hash()?;
+        let coinbase = Transaction::from_json(json.get("coinbase")?, addresses)?;
+        let mut txs = Vec::new();
+
+        if let Some(block_txs) = json.get("txs") {
+            for tx in block_txs.as_array()? {
+                txs.push(Transaction::from_json(tx, addresses)?);
+            }
+        }
+        let mut block = Block::from_parts(prev_hash, coinbase, txs);
+
+        if let Some(nonce) = json.get("nonce") {
+            block.set_nonce(nonce.as_str()?.parse().ok()?);
+        }
+        if let Some(hash) = json.get("hash") {
+            if hash.as_hash()? != block.hash {
+                return None;
+            }
+        }
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod block_tests {
+    use super::*;
+    use blockchain::Blockchain;
+    use json_dump::fingerprint;
+    use json_dump::to_hex;
+    use utxo::UTXOPool;
+
+    #[test]
+    fn dump_load_test() {
+        let mut tx = Transaction::new();
+        tx.add_input_tx([3; 32], 1);
+        tx.add_signature(vec![5; 256], 0);
+        tx.add_output_tx(2.5, b"-----BEGIN PUBLIC KEY-----\n".to_vec());
+        tx.finalize();
+        let coinbase = Transaction::new_coinbase(25.0, b"miner".to_vec(), 1);
+        let mut block = Block::from_parts([1; 32], coinbase, vec![tx]);
+        block.set_nonce(u64::max_value());
+
+        let dump = block.dump();
+        assert!(dump.contains(&fingerprint(b"miner")));
+        let loaded = Block::load(&dump).unwrap();
+        assert_eq!(block.hash(), loaded.hash());
+        assert_eq!(u64::max_value(), loaded.nonce());
+        assert_eq!(dump, loaded.dump());
+
+        let blockchain = Blockchain::new(block);
+        let pool = blockchain.get_max_height_utxo_pool();
+        assert_eq!(*pool, UTXOPool::load(&pool.dump()).unwrap());
+    }
+
+    #[test]
+    fn fixture_test() {
+        let address = format!(r#""{}": "{}""#, fingerprint(b"miner"), to_hex(b"miner"));
+        let genesis = format!(
+            r#"{{
+                "prev_hash": "{}",
+                "coinbase": {{
+                    "coinbase": true,
+                    "inputs": [],
+                    "outputs": [{{"value": 25, "address": "{}"}}]
+                }},
+                "addresses": {{{}}}
+            }}"#,
+            to_hex(&[1; 32]),
+            fingerprint(b"miner"),
+            address
+        );
+        let genesis = Block::load(&genesis).unwrap();
+        let block = genesis.dump().replace(&to_hex(genesis.hash()), "");
+        let block = block.replace(&to_hex(&[1; 32]), &to_hex(genesis.hash()));
+        // coinbase of block 1 differs from genesis coinbase by height
+        let block = block.replace(&to_hex(&genesis.coinbase().hash()), "");
+        let block = block.replace("\"height\": 0", "\"height\": 1");
+        // hash left empty does not match block
+        assert!(Block::load(&block).is_err());
+
+        let block = Block::load(&block.replace("\"hash\": \"\",\n", "")).unwrap();
+        let mut blockchain = Blockchain::new(genesis);
+        assert!(blockchain.add_block(block));
+        assert_eq!(1, blockchain.get_max_height());
     }
 }
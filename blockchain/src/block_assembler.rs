@@ -0,0 +1,195 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use transaction::Transaction;
+use transaction_pool::FeeRate;
+use transaction_pool::TransactionPool;
+
+/// Default limit for encoded size of block transactions in bytes
+static DEFAULT_MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Transactions selected for the next block, parents before children
+pub struct BlockTemplate {
+    pub txs: Vec<Transaction>,
+    /// Sum of selected transactions fees
+    pub fees: f64,
+    /// Encoded size of selected transactions
+    pub size: usize,
+}
+
+/// Pooled transaction together with its ancestors not yet in template
+struct Package {
+    ancestors: HashSet<[u8; 32]>,
+    /// Sum of fees of transaction and ancestors
+    fee: f64,
+    /// Sum of encoded sizes of transaction and ancestors
+    size: usize,
+}
+
+impl Package {
+    fn fee_rate(&self) -> FeeRate {
+        FeeRate(self.fee / self.size as f64)
+    }
+}
+
+/// Select pooled transactions for block by packages: transaction together
+/// with its unconfirmed ancestors. Packages ranked by combined fee rate,
+/// so high fee child pays for low fee parents (child-pays-for-parent).
+pub struct BlockAssembler {
+    max_block_size: usize,
+}
+
+impl BlockAssembler {
+    pub fn new() -> Self {
+        BlockAssembler::with_max_size(DEFAULT_MAX_BLOCK_SIZE)
+    }
+
+    pub fn with_max_size(max_block_size: usize) -> Self {
+        BlockAssembler { max_block_size }
+    }
+
+    /// Package of every pooled transaction is computed once, after package
+    /// enters template only packages of its descendants are updated
+    pub fn assemble(&self, tx_pool: &TransactionPool) -> BlockTemplate {
+        let mut template = BlockTemplate {
+            txs: Vec::new(),
+            fees: 0.0,
+            size: 0,
+        };
+        let mut packages = HashMap::new();
+        let mut descendants: HashMap<[u8; 32], Vec<[u8; 32]>> = HashMap::new();
+
+        for tx in tx_pool.iter() {
+            let hash = tx.hash();
+            let ancestors = tx_pool.ancestors(&hash);
+            let mut fee = 0.0;
+            let mut size = 0;
+
+            for ancestor in ancestors.iter().chain(Some(&hash)) {
+                let entry = tx_pool.get_entry(ancestor).unwrap();
+                fee += entry.fee();
+                size += entry.size();
+            }
+            for ancestor in ancestors.iter() {
+                descendants.entry(ancestor.clone()).or_insert_with(Vec::new).push(hash.clone());
+            }
+            packages.insert(hash, Package { ancestors, fee, size });
+        }
+        // candidates by package fee rate, highest last
+        let mut candidates: BTreeSet<(FeeRate, [u8; 32])> = packages
+            .iter()
+            .map(|(hash, package)| (package.fee_rate(), hash.clone()))
+            .collect();
+
+        while let Some(best) = candidates.iter().next_back().cloned() {
+            candidates.remove(&best);
+            let (_, hash) = best;
+
+            if template.size + packages[&hash].size > self.max_block_size {
+                // descendants packages include this one, so they are skipped later as well
+                continue;
+            }
+            let mut package: Vec<[u8; 32]> = packages[&hash].ancestors.iter().cloned().collect();
+            package.push(hash.clone());
+            // ancestor always has less ancestors than its descendant
+            package.sort_by_key(|hash| packages[hash].ancestors.len());
+
+            for hash in &package {
+                let entry = tx_pool.get_entry(hash).unwrap();
+                template.txs.push(entry.tx().clone());
+                template.fees += entry.fee();
+                template.size += entry.size();
+                let included = packages.remove(hash).unwrap();
+                candidates.remove(&(included.fee_rate(), hash.clone()));
+            }
+            for hash in &package {
+                let entry = tx_pool.get_entry(hash).unwrap();
+                let hashes = match descendants.get(hash) {
+                    Some(hashes) => hashes,
+                    None => continue,
+                };
+                for descendant in hashes {
+                    let package = match packages.get_mut(descendant) {
+                        Some(package) => package,
+                        None => continue,
+                    };
+                    // skipped packages do not return to candidates
+                    let candidate = candidates.remove(&(package.fee_rate(), descendant.clone()));
+                    package.ancestors.remove(hash);
+                    package.fee -= entry.fee();
+                    package.size -= entry.size();
+
+                    if candidate {
+                        candidates.insert((package.fee_rate(), descendant.clone()));
+                    }
+                }
+            }
+        }
+        template
+    }
+}
+
+#[cfg(test)]
+mod block_assembler_tests {
+    use super::*;
+    use testutil::Funds;
+    use utxo::UTXO;
+
+    #[test]
+    fn package_test() {
+        let funds = Funds::new(2);
+        // low fee parent with high fee descendants, and unrelated transaction
+        // paying more than parent or child alone, but less than whole package
+        let parent = funds.spend(&[Funds::coin(0)], &[9.9]);
+        let child = funds.spend(&[UTXO::new(parent.hash(), 0)], &[9.0]);
+        let grandchild = funds.spend(&[UTXO::new(child.hash(), 0)], &[6.0]);
+        let other = funds.spend(&[Funds::coin(1)], &[9.0]);
+        let mut tx_pool = TransactionPool::new();
+
+        for tx in [&grandchild, &child, &parent, &other].iter().rev() {
+            assert!(funds.add(&mut tx_pool, tx));
+        }
+        let size = parent.size();
+
+        let template = BlockAssembler::new().assemble(&tx_pool);
+        let hashes: Vec<[u8; 32]> = template.txs.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(vec![parent.hash(), child.hash(), grandchild.hash(), other.hash()], hashes);
+        assert!((template.fees - 5.0).abs() < 1e-9);
+        assert_eq!(4 * size, template.size);
+
+        // grandchild pays for its ancestors, unrelated transaction does not fit
+        let template = BlockAssembler::with_max_size(3 * size).assemble(&tx_pool);
+        let hashes: Vec<[u8; 32]> = template.txs.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(vec![parent.hash(), child.hash(), grandchild.hash()], hashes);
+
+        // package does not fit, unrelated transaction does
+        let template = BlockAssembler::with_max_size(size).assemble(&tx_pool);
+        let hashes: Vec<[u8; 32]> = template.txs.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(vec![other.hash()], hashes);
+    }
+
+    #[test]
+    fn sibling_test() {
+        let funds = Funds::new(2);
+        // parent with two children, its fee is paid by the first one
+        let parent = funds.spend(&[Funds::coin(0)], &[5.0, 4.9]);
+        let first = funds.spend(&[UTXO::new(parent.hash(), 0)], &[3.0]);
+        let second = funds.spend(&[UTXO::new(parent.hash(), 1)], &[4.2]);
+        let other = funds.spend(&[Funds::coin(1)], &[9.4]);
+        let mut tx_pool = TransactionPool::new();
+
+        for tx in [&parent, &first, &second, &other].iter() {
+            assert!(funds.add(&mut tx_pool, tx));
+        }
+        // second child alone pays more than unrelated transaction, with parent less
+        let package_size = (parent.size() + second.size()) as f64;
+        assert!(0.7 / second.size() as f64 > 0.6 / other.size() as f64);
+        assert!(0.8 / package_size < 0.6 / other.size() as f64);
+
+        // package of second child drops parent once parent enters template
+        let template = BlockAssembler::new().assemble(&tx_pool);
+        let hashes: Vec<[u8; 32]> = template.txs.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(vec![parent.hash(), first.hash(), second.hash(), other.hash()], hashes);
+        assert!((template.fees - 3.4).abs() < 1e-9);
+    }
+}
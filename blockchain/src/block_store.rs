@@ -0,0 +1,527 @@
+use block::Block;
+use block::BlockHeader;
+use codec::checksum;
+use codec::Decode;
+use codec::Encode;
+use codec::invalid_data;
+use codec::put_u32;
+use codec::put_u64;
+use codec::Reader;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use undo::BlockUndo;
+
+/// Marks beginning of every block record
+static BLOCK_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+/// Marks beginning of every block undo record
+static UNDO_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xda];
+/// Marks beginning of every active chain record
+static ACTIVE_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xdb];
+/// Record header: magic, payload length and payload checksum
+const HEADER_SIZE: usize = 12;
+/// Index record: block hash, block record offset and record checksum
+const INDEX_RECORD_SIZE: usize = 44;
+static BLOCKS_FILE: &str = "blocks.dat";
+static INDEX_FILE: &str = "index.dat";
+static UNDO_FILE: &str = "undo.dat";
+static ACTIVE_FILE: &str = "active.dat";
+
+/// Append-only on-disk block storage.
+/// Block is written to blocks file as record with checksum and synced,
+/// only then its offset is appended to index file. On open, torn or corrupted
+/// records at the end of files (left by crash during write) are truncated
+/// and blocks missing in index are indexed again.
+/// Undo data of connected blocks is kept next to blocks in undo file,
+/// its records are indexed in memory on open.
+/// Active chain is kept in log of header records, record at height replaces
+/// active block at that height and drops blocks above it. So log grows by
+/// one record per block and by length of every reorg, and it is read once on open.
+pub struct BlockStore {
+    blocks: File,
+    index: File,
+    undo: File,
+    active: File,
+    /// Size of valid data in blocks file
+    blocks_len: u64,
+    /// Offset of block record in blocks file
+    offsets: HashMap<[u8; 32], u64>,
+    /// Stored blocks hashes in order of writing, parents before children
+    hashes: Vec<[u8; 32]>,
+    /// Hashes of active chain blocks by height
+    active_chain: Vec<[u8; 32]>,
+    /// Size of valid data in undo file
+    undo_len: u64,
+    /// Offset of block undo record in undo file
+    undo_offsets: HashMap<[u8; 32], u64>,
+}
+
+impl BlockStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let mut store = BlockStore {
+            blocks: options.open(dir.join(BLOCKS_FILE))?,
+            index: options.open(dir.join(INDEX_FILE))?,
+            undo: options.open(dir.join(UNDO_FILE))?,
+            active: options.open(dir.join(ACTIVE_FILE))?,
+            blocks_len: 0,
+            offsets: HashMap::new(),
+            hashes: Vec::new(),
+            active_chain: Vec::new(),
+            undo_len: 0,
+            undo_offsets: HashMap::new(),
+        };
+        store.load_index()?;
+        store.recover_blocks()?;
+        store.load_undo()?;
+        store.load_active()?;
+        Ok(store)
+    }
+
+    /// Write block to the end of store, stored blocks are skipped
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        if self.offsets.contains_key(block.hash()) {
+            return Ok(());
+        }
+        let record = encode_record(&BLOCK_MAGIC, block.to_bytes());
+        let offset = self.blocks_len;
+        self.blocks.seek(SeekFrom::Start(offset))?;
+        self.blocks.write_all(&record)?;
+        self.blocks.sync_data()?;
+        self.blocks_len += record.len() as u64;
+        self.write_index(block.hash(), offset)
+    }
+
+    pub fn read_block(&self, hash: &[u8; 32]) -> io::Result<Option<Block>> {
+        let offset = match self.offsets.get(hash) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let payload = read_record(&self.blocks, offset, &BLOCK_MAGIC)?;
+
+        match Block::from_bytes(&payload) {
+            Some(ref block) if block.hash() != hash => {
+                Err(invalid_data("Block record hash mismatch"))
+            }
+            Some(block) => Ok(Some(block)),
+            None => Err(invalid_data("Invalid block record")),
+        }
+    }
+
+    /// Write undo data of connected block, stored undo data is skipped
+    pub fn append_undo(&mut self, hash: &[u8; 32], undo: &BlockUndo) -> io::Result<()> {
+        if self.undo_offsets.contains_key(hash) {
+            return Ok(());
+        }
+        let mut payload = hash.to_vec();
+        payload.extend(undo.encode());
+        let record = encode_record(&UNDO_MAGIC, payload);
+        let offset = self.undo_len;
+        self.undo.seek(SeekFrom::Start(offset))?;
+        self.undo.write_all(&record)?;
+        self.undo.sync_data()?;
+        self.undo_len += record.len() as u64;
+        self.undo_offsets.insert(hash.clone(), offset);
+        Ok(())
+    }
+
+    pub fn read_undo(&self, hash: &[u8; 32]) -> io::Result<Option<BlockUndo>> {
+        let offset = match self.undo_offsets.get(hash) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let payload = read_record(&self.undo, offset, &UNDO_MAGIC)?;
+
+        if payload.len() < 32 || payload[..32] != hash[..] {
+            return Err(invalid_data("Undo record hash mismatch"));
+        }
+        match BlockUndo::decode(&payload[32..]) {
+            Some(undo) => Ok(Some(undo)),
+            None => Err(invalid_data("Invalid undo record")),
+        }
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.offsets.contains_key(hash)
+    }
+
+    /// Stored blocks hashes in order of writing
+    pub fn hashes(&self) -> &Vec<[u8; 32]> {
+        &self.hashes
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Hash of active chain block at height
+    pub fn active_hash(&self, height: usize) -> Option<&[u8; 32]> {
+        self.active_chain.get(height)
+    }
+
+    pub fn active_tip(&self) -> Option<&[u8; 32]> {
+        self.active_chain.last()
+    }
+
+    /// Append block to active chain, it should extend active tip
+    pub fn push_active(&mut self, header: &BlockHeader) -> io::Result<()> {
+        let height = self.active_chain.len();
+        self.write_active(height, header)?;
+        self.active_chain.push(header.hash.clone());
+        Ok(())
+    }
+
+    /// Replace active chain after switching to other branch,
+    /// only blocks above the last common block are written
+    pub fn set_active_chain(&mut self, headers: &[BlockHeader]) -> io::Result<()> {
+        let common = self.active_chain
+            .iter()
+            .zip(headers.iter())
+            .take_while(|&(hash, header)| *hash == header.hash)
+            .count();
+
+        if common == headers.len() && common == self.active_chain.len() {
+            return Ok(());
+        }
+        // chain which is prefix of active chain still needs record of its tip
+        let start = cmp::min(common, headers.len().saturating_sub(1));
+        self.active_chain.truncate(start);
+
+        for (height, header) in headers.iter().enumerate().skip(start) {
+            self.write_active(height, header)?;
+            self.active_chain.push(header.hash.clone());
+        }
+        Ok(())
+    }
+
+    /// Headers of active chain blocks by height, read from disk
+    pub fn active_headers(&self) -> io::Result<Vec<BlockHeader>> {
+        Ok(self.read_active()?.0)
+    }
+
+    fn load_index(&mut self) -> io::Result<()> {
+        let mut data = Vec::new();
+        self.index.seek(SeekFrom::Start(0))?;
+        self.index.read_to_end(&mut data)?;
+        let mut valid_len = 0;
+
+        for record in data.chunks(INDEX_RECORD_SIZE) {
+            if record.len() != INDEX_RECORD_SIZE || checksum(&record[..40]) != record[40..] {
+                break;
+            }
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&record[..32]);
+            let offset = Reader::new(&record[32..40]).u64().unwrap();
+            self.offsets.insert(hash, offset);
+            self.hashes.push(hash);
+            valid_len += INDEX_RECORD_SIZE;
+        }
+        self.index.set_len(valid_len as u64)?;
+        Ok(())
+    }
+
+    /// Index complete block records written after last indexed one
+    /// and drop incomplete record at the end of blocks file
+    fn recover_blocks(&mut self) -> io::Result<()> {
+        let mut offset = 0;
+
+        if let Some(hash) = self.hashes.last() {
+            offset = self.offsets[hash];
+            let len = read_record(&self.blocks, offset, &BLOCK_MAGIC)?.len();
+            offset += (HEADER_SIZE + len) as u64;
+        }
+        let mut data = Vec::new();
+        self.blocks.seek(SeekFrom::Start(offset))?;
+        self.blocks.read_to_end(&mut data)?;
+        let mut pos = 0;
+
+        while let Some(payload) = parse_record(&data[pos..], &BLOCK_MAGIC) {
+            let block = match Block::from_bytes(payload) {
+                Some(block) => block,
+                None => break,
+            };
+            if !self.offsets.contains_key(block.hash()) {
+                self.write_index(block.hash(), offset + pos as u64)?;
+            }
+            pos += HEADER_SIZE + payload.len();
+        }
+        self.blocks_len = offset + pos as u64;
+        self.blocks.set_len(self.blocks_len)?;
+        self.blocks.sync_data()
+    }
+
+    /// Index undo records and drop incomplete record at the end of undo file
+    fn load_undo(&mut self) -> io::Result<()> {
+        let mut data = Vec::new();
+        self.undo.seek(SeekFrom::Start(0))?;
+        self.undo.read_to_end(&mut data)?;
+        let mut pos = 0;
+
+        while let Some(payload) = parse_record(&data[pos..], &UNDO_MAGIC) {
+            if payload.len() < 32 {
+                break;
+            }
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&payload[..32]);
+            self.undo_offsets.insert(hash, pos as u64);
+            pos += HEADER_SIZE + payload.len();
+        }
+        self.undo_len = pos as u64;
+        self.undo.set_len(self.undo_len)?;
+        self.undo.sync_data()
+    }
+
+    /// Load active chain and drop incomplete record at the end of its log
+    fn load_active(&mut self) -> io::Result<()> {
+        let (headers, valid_len) = self.read_active()?;
+        self.active.set_len(valid_len as u64)?;
+        self.active.sync_data()?;
+        self.active_chain = headers.into_iter().map(|header| header.hash).collect();
+        Ok(())
+    }
+
+    /// Replay active chain log, return active headers and size of valid log data
+    fn read_active(&self) -> io::Result<(Vec<BlockHeader>, usize)> {
+        let mut data = Vec::new();
+        let mut file = &self.active;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+        let mut headers = Vec::new();
+        let mut pos = 0;
+
+        while let Some(payload) = parse_record(&data[pos..], &ACTIVE_MAGIC) {
+            let mut reader = Reader::new(payload);
+            let height = match reader.u64() {
+                Some(height) if height as usize <= headers.len() => height as usize,
+                _ => break,
+            };
+            let header = match BlockHeader::decode(&mut reader) {
+                Some(_) if !reader.is_empty() => break,
+                Some(header) => header,
+                None => break,
+            };
+            headers.truncate(height);
+            headers.push(header);
+            pos += HEADER_SIZE + payload.len();
+        }
+        Ok((headers, pos))
+    }
+
+    fn write_active(&mut self, height: usize, header: &BlockHeader) -> io::Result<()> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, height as u64);
+        header.encode(&mut payload);
+        let record = encode_record(&ACTIVE_MAGIC, payload);
+        self.active.seek(SeekFrom::End(0))?;
+        self.active.write_all(&record)?;
+        self.active.sync_data()
+    }
+
+    fn write_index(&mut self, hash: &[u8; 32], offset: u64) -> io::Result<()> {
+        let mut record = Vec::with_capacity(INDEX_RECORD_SIZE);
+        record.extend(hash.iter());
+        put_u64(&mut record, offset);
+        let sum = checksum(&record);
+        record.extend(sum.iter());
+
+        self.index.seek(SeekFrom::End(0))?;
+        self.index.write_all(&record)?;
+        self.index.sync_data()?;
+        self.offsets.insert(hash.clone(), offset);
+        self.hashes.push(hash.clone());
+        Ok(())
+    }
+}
+
+fn encode_record(magic: &[u8; 4], payload: Vec<u8>) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+    record.extend(magic.iter());
+    put_u32(&mut record, payload.len() as u32);
+    record.extend(checksum(&payload).iter());
+    record.extend(payload);
+    record
+}
+
+/// Return payload if data starts with complete record with valid checksum
+fn parse_record<'a>(data: &'a [u8], magic: &[u8; 4]) -> Option<&'a [u8]> {
+    if data.len() < HEADER_SIZE || data[..4] != magic[..] {
+        return None;
+    }
+    let len = Reader::new(&data[4..8]).u32()? as usize;
+
+    if data.len() < HEADER_SIZE + len {
+        return None;
+    }
+    let payload = &data[HEADER_SIZE..HEADER_SIZE + len];
+
+    if checksum(payload) != data[8..HEADER_SIZE] {
+        return None;
+    }
+    Some(payload)
+}
+
+/// Read payload of record at offset in file
+fn read_record(mut file: &File, offset: u64, magic: &[u8; 4]) -> io::Result<Vec<u8>> {
+    let mut header = [0; HEADER_SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header)?;
+
+    if header[..4] != magic[..] {
+        return Err(invalid_data("Invalid record magic"));
+    }
+    let len = Reader::new(&header[4..8]).u32().unwrap() as usize;
+    let mut payload = vec![0; len];
+    file.read_exact(&mut payload)?;
+
+    if checksum(&payload) != header[8..] {
+        return Err(invalid_data("Record checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod block_store_tests {
+    use super::*;
+    use std::env;
+    use transaction::TransactionOutput;
+    use undo::SpentOutput;
+    use undo::TxUndo;
+    use utxo::UTXOEntry;
+    use utxo::UTXO;
+
+    fn test_dir(name: &str) -> ::std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("block_store_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn init_block(prev_hash: [u8; 32], value: f64, height: usize) -> Block {
+        let mut block = Block::new(prev_hash, b"address".to_vec(), value, height);
+        block.finalize();
+        block
+    }
+
+    #[test]
+    fn append_read_test() {
+        let dir = test_dir("append_read");
+        let block0 = init_block([1; 32], 25.0, 0);
+        let block1 = init_block(block0.hash().clone(), 12.5, 1);
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&block0).unwrap();
+            store.append(&block1).unwrap();
+            store.append(&block1).unwrap();
+        }
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(vec![block0.hash().clone(), block1.hash().clone()], *store.hashes());
+        let block = store.read_block(block1.hash()).unwrap().unwrap();
+        assert_eq!(block1.prev_hash(), block.prev_hash());
+        assert_eq!(block1.coinbase(), block.coinbase());
+        assert_eq!(None, store.read_block(&[0; 32]).unwrap().map(|_| ()));
+    }
+
+    #[test]
+    fn undo_test() {
+        let dir = test_dir("undo");
+        let block0 = init_block([1; 32], 25.0, 0);
+        let mut undo = BlockUndo::new();
+        undo.txs.push(TxUndo {
+            spent: vec![SpentOutput {
+                utxo: UTXO::new([2; 32], 1),
+                entry: UTXOEntry {
+                    tx_out: TransactionOutput::new(5.0, b"address".to_vec()),
+                    height: 3,
+                    coinbase: true,
+                },
+            }],
+        });
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&block0).unwrap();
+            store.append_undo(block0.hash(), &undo).unwrap();
+        }
+        // torn record at the end of undo file is dropped
+        let file = OpenOptions::new().write(true).open(dir.join(UNDO_FILE)).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(None, store.read_undo(block0.hash()).unwrap());
+        store.append_undo(block0.hash(), &undo).unwrap();
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(Some(undo), store.read_undo(block0.hash()).unwrap());
+    }
+
+    #[test]
+    fn recover_test() {
+        let dir = test_dir("recover");
+        let block0 = init_block([1; 32], 25.0, 0);
+        let block1 = init_block(block0.hash().clone(), 12.5, 1);
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&block0).unwrap();
+            store.append(&block1).unwrap();
+        }
+        // drop index of last block and tear its record
+        let index = OpenOptions::new().write(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.set_len(INDEX_RECORD_SIZE as u64 + 10).unwrap();
+        let blocks = OpenOptions::new().write(true).open(dir.join(BLOCKS_FILE)).unwrap();
+        let len = blocks.metadata().unwrap().len();
+        blocks.set_len(len - 1).unwrap();
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(vec![block0.hash().clone()], *store.hashes());
+        store.append(&block1).unwrap();
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(vec![block0.hash().clone(), block1.hash().clone()], *store.hashes());
+        assert!(store.read_block(block1.hash()).unwrap().is_some());
+    }
+
+    #[test]
+    fn active_chain_test() {
+        let dir = test_dir("active_chain");
+        let block0 = init_block([1; 32], 25.0, 0);
+        let block1 = init_block(block0.hash().clone(), 12.5, 1);
+        let fork1 = init_block(block0.hash().clone(), 6.25, 1);
+        let fork2 = init_block(fork1.hash().clone(), 6.25, 2);
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.push_active(&block0.header()).unwrap();
+            store.push_active(&block1.header()).unwrap();
+            // switch to longer fork rewrites only blocks above genesis
+            let headers = vec![block0.header(), fork1.header(), fork2.header()];
+            store.set_active_chain(&headers).unwrap();
+            assert_eq!(Some(fork1.hash()), store.active_hash(1));
+        }
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(Some(fork2.hash()), store.active_tip());
+        let headers = store.active_headers().unwrap();
+        assert_eq!(vec![block0.header(), fork1.header(), fork2.header()], headers);
+
+        // torn record at the end of active chain log is dropped
+        let file = OpenOptions::new().write(true).open(dir.join(ACTIVE_FILE)).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(Some(fork1.hash()), store.active_tip());
+        store.set_active_chain(&[block0.header(), block1.header()]).unwrap();
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(vec![block0.header(), block1.header()], store.active_headers().unwrap());
+    }
+}
@@ -1,44 +1,186 @@
 use block::Block;
+use block::BlockHeader;
+use block_store::BlockStore;
+use codec::invalid_data;
+use events::ChainEvent;
+use events::ChainListener;
+use params::ChainParams;
+use snapshot::UtxoSnapshot;
 use std::cmp;
 use std::cmp::Ordering;
 use std::collections::binary_heap::PeekMut;
 use std::collections::BinaryHeap;
 use std::collections::LinkedList;
+use std::io;
+use std::path::Path;
 use time;
 use time::Timespec;
+use transaction_pool::RemovalReason;
 use transaction_pool::TransactionPool;
 use transaction::Transaction;
+use txhandler::TxHandler;
+use undo::BlockUndo;
 use utxo::UTXOPool;
+use utxo::UtxoBatch;
+use utxo::UTXOEntry;
 use utxo::UTXO;
+use utxo_store::FileUtxoStore;
+use utxo_store::FlushPolicy;
+use utxo_store::UtxoStore;
 
+#[derive(Clone)]
 pub struct Branch {
+    /// Headers of all branch blocks, index is block height
+    _headers: Vec<BlockHeader>,
+    /// Bodies of last branch blocks, older bodies are dropped in pruning mode
     _blocks: LinkedList<Block>,
+    /// Undo data of every block in `_blocks`, empty for genesis block
+    _undo: LinkedList<BlockUndo>,
     /// Timestamp for last block in list
     _timestamp: Timespec,
-    /// unspended transactions pool for this branch
-    pub _utxo_pool: UTXOPool,
 }
 
 impl Branch {
     fn new(genesis_block: Block) -> Self {
         let mut branch = Branch {
+            _headers: Vec::new(),
             _blocks: LinkedList::new(),
+            _undo: LinkedList::new(),
             _timestamp: time::get_time(),
-            _utxo_pool: UTXOPool::new(),
         };
-        // Add coinbase tx to utxo pool
-        for (index, tx) in genesis_block.coinbase().get_outputs().iter().enumerate() {
-            let utxo = UTXO::new(genesis_block.coinbase().hash().clone(), index);
-            branch._utxo_pool.add_UTXO(utxo, tx.clone());
-        }
-        branch._blocks.push_back(genesis_block);
+        branch.push(genesis_block, BlockUndo::new());
         branch
     }
+
+    /// Load branch which ends with best block from store: headers of all
+    /// blocks, bodies and undo data of only last `depth` blocks.
+    /// Headers come from stored active chain, if it includes best block,
+    /// otherwise parents are followed from best block to genesis.
+    fn restore(
+        store: &BlockStore,
+        best_block: [u8; 32],
+        genesis_hash: &[u8; 32],
+        depth: usize,
+    ) -> io::Result<Self> {
+        let mut headers = store.active_headers()?;
+
+        match headers.iter().position(|header| header.hash == best_block) {
+            Some(height) => headers.truncate(height + 1),
+            None => {
+                headers.clear();
+                let mut hash = best_block;
+
+                loop {
+                    let block = store
+                        .read_block(&hash)?
+                        .ok_or_else(|| invalid_data("Best block ancestor is missing in store"))?;
+                    headers.push(block.header());
+
+                    if hash == *genesis_hash {
+                        break;
+                    }
+                    hash = block.prev_hash().clone();
+                }
+                headers.reverse();
+            }
+        }
+        if headers.first().map(|header| header.hash) != Some(genesis_hash.clone()) {
+            return Err(invalid_data("Best block does not descend from genesis block"));
+        }
+        let mut blocks = LinkedList::new();
+        let mut undo = LinkedList::new();
+
+        for header in headers.iter().skip(headers.len().saturating_sub(depth)) {
+            let block = store
+                .read_block(&header.hash)?
+                .ok_or_else(|| invalid_data("Best block ancestor is missing in store"))?;
+            let block_undo = if header.hash == *genesis_hash {
+                BlockUndo::new()
+            } else {
+                store
+                    .read_undo(&header.hash)?
+                    .ok_or_else(|| invalid_data("Block undo data is missing in store"))?
+            };
+            blocks.push_back(block);
+            undo.push_back(block_undo);
+        }
+        Ok(Branch {
+            _headers: headers,
+            _blocks: blocks,
+            _undo: undo,
+            _timestamp: time::get_time(),
+        })
+    }
+
+    /// Number of branch blocks, including pruned ones
+    fn len(&self) -> usize {
+        self._headers.len()
+    }
+
+    fn tip(&self) -> &Block {
+        self._blocks.back().unwrap()
+    }
+
+    /// Height of the last block shared with other branch
+    fn common_height(&self, other: &Branch) -> usize {
+        let mut height = cmp::min(self.len(), other.len()) - 1;
+
+        while self._headers[height] != other._headers[height] {
+            height -= 1;
+        }
+        height
+    }
+
+    /// Height of block with given hash
+    fn height_of(&self, hash: &[u8; 32]) -> Option<usize> {
+        self._headers.iter().rposition(|header| header.hash == *hash)
+    }
+
+    /// Body of block at height, None if block is pruned
+    fn block_at(&self, height: usize) -> Option<&Block> {
+        self.entry_at(height).map(|(block, _)| block)
+    }
+
+    /// Body and undo data of block at height, None if block is pruned
+    fn entry_at(&self, height: usize) -> Option<(&Block, &BlockUndo)> {
+        if height >= self.len() {
+            return None;
+        }
+        self._blocks
+            .iter()
+            .rev()
+            .zip(self._undo.iter().rev())
+            .nth(self.len() - 1 - height)
+    }
+
+    fn push(&mut self, block: Block, undo: BlockUndo) {
+        self._headers.push(block.header());
+        self._blocks.push_back(block);
+        self._undo.push_back(undo);
+    }
+
+    fn pop(&mut self) -> (Block, BlockUndo) {
+        self._headers.pop();
+        (self._blocks.pop_back().unwrap(), self._undo.pop_back().unwrap())
+    }
+
+    /// Drop bodies and undo data of all blocks except last `depth` ones
+    fn prune(&mut self, depth: usize) {
+        while self._blocks.len() > depth {
+            self._blocks.pop_front();
+            self._undo.pop_front();
+        }
+    }
+
+    /// Height of the oldest block which body is kept
+    fn first_kept_height(&self) -> usize {
+        self.len() - self._blocks.len()
+    }
 }
 
 impl PartialEq for Branch {
     fn eq(&self, other: &Branch) -> bool {
-        self._blocks.len() == other._blocks.len()
+        self.len() == other.len()
     }
 }
 
@@ -54,108 +196,1220 @@ impl Ord for Branch {
     fn cmp(&self, other: &Branch) -> Ordering {
         // check brahches height and return max height branch
         // in case height equal - return oldest branch
-        self._blocks
-            .len()
-            .cmp(&other._blocks.len())
+        self.len()
+            .cmp(&other.len())
             .then_with(|| other._timestamp.cmp(&self._timestamp))
     }
 }
 
-static CUT_OFF_AGE: usize = 10;
+/// Reason why block was not added to chain
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    /// Block without parent, only genesis block may have it
+    NoParent,
+    /// Parent is not the last block of any branch
+    UnknownParent,
+    /// Some of block transactions are invalid against branch state
+    InvalidTx,
+    /// Coinbase has inputs, negative or non-finite outputs, or height other than block height
+    InvalidCoinbase,
+    /// Coinbase claims more than block subsidy plus transaction fees
+    CoinbaseValueTooHigh,
+    /// Block is already in one of branches
+    Duplicate,
+    /// Block hash does not meet proof-of-work target
+    InsufficientWork,
+    /// Block could not be written to disk, chain is not changed
+    Io(io::ErrorKind),
+}
+
+/// Reason why block is not returned by chain query
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    /// Block is not in any branch
+    NotFound,
+    /// Block is in branch, but its body was dropped by pruning
+    Pruned,
+    /// Pruned block could not be read from disk
+    Io(io::ErrorKind),
+}
+
+/// Directory of UTXO set inside chain directory
+static UTXO_DIR: &str = "utxo";
+/// Default number of blocks after which UTXO set changes are written to disk
+static UTXO_FLUSH_BLOCKS: usize = 10;
 /// Block Chain should maintain only limited block nodes to satisfy the functions
 /// You should not have all the blocks added to the block chain in memory
 /// as it would cause a memory overflow.
 /// Draft implementation
 pub struct Blockchain {
+    _params: ChainParams,
     /// Global transaction pool
     _tx_pool: TransactionPool,
     /// BinaryHeap hold all branches with blocks and
     /// timespec of creation of last block
     _branches: BinaryHeap<Branch>,
+    /// Unspent outputs at highest branch tip. UTXO sets of other branches
+    /// are not kept, they are derived from it with undo data when needed.
+    _utxo_pool: UTXOPool,
+    /// On-disk storage of accepted blocks and UTXO set, if chain is persistent
+    _store: Option<ChainStore>,
+    /// Number of last blocks whose bodies are kept in memory, if pruning is enabled
+    _prune_depth: Option<usize>,
+    /// Subscribers of active chain changes, in order of subscription
+    _listeners: Vec<Box<dyn ChainListener>>,
+}
+
+/// Blocks and UTXO set of persistent chain
+struct ChainStore {
+    blocks: BlockStore,
+    utxo: Box<dyn UtxoStore>,
+}
+
+impl ChainStore {
+    /// Write block connected to branch and its undo data. If block makes
+    /// branch highest, stored active chain and UTXO set follow it:
+    /// `batch` is committed if block extends best block, otherwise
+    /// whole UTXO set of branch with block, `utxo_pool`, is written.
+    fn write_block(
+        &mut self,
+        branch: &Branch,
+        utxo_pool: &UTXOPool,
+        block: &Block,
+        undo: &BlockUndo,
+        highest: bool,
+        batch: UtxoBatch,
+    ) -> io::Result<()> {
+        self.blocks.append(block)?;
+        self.blocks.append_undo(block.hash(), undo)?;
+
+        if !highest {
+            return Ok(());
+        }
+        if self.blocks.active_tip() == Some(block.prev_hash()) {
+            self.blocks.push_active(&block.header())?;
+        } else {
+            let mut headers = branch._headers.clone();
+            headers.push(block.header());
+            self.blocks.set_active_chain(&headers)?;
+        }
+        if self.utxo.best_block() == Some(block.prev_hash()) {
+            self.utxo.commit(batch, block.hash().clone())
+        } else {
+            self.utxo.reset(utxo_pool, block.hash().clone())
+        }
+    }
 }
 
 impl Blockchain {
     pub fn new(genesis_block: Block) -> Self {
+        Blockchain::with_params(genesis_block, ChainParams::new())
+    }
+
+    pub fn with_params(genesis_block: Block, params: ChainParams) -> Self {
         let mut blockchain = Blockchain {
+            _params: params,
             _tx_pool: TransactionPool::new(),
             _branches: BinaryHeap::new(),
+            _utxo_pool: UTXOPool::new(),
+            _store: None,
+            _prune_depth: None,
+            _listeners: Vec::new(),
         };
+        // Add coinbase tx to utxo pool
+        let coinbase = genesis_block.coinbase().clone();
+
+        for (index, tx) in coinbase.get_outputs().iter().enumerate() {
+            let utxo = UTXO::new(coinbase.hash(), index);
+            blockchain._utxo_pool.add_UTXO(utxo, tx.clone(), 0, true);
+        }
         blockchain._branches.push(Branch::new(genesis_block));
         blockchain
     }
 
+    /// Open chain stored in directory or initialise it with genesis block.
+    /// Tip and UTXO state are restored from UTXO store, blocks written
+    /// after its best block are added again in order. Only bodies of last
+    /// cut-off age blocks of every branch are kept in memory, older ones
+    /// are read from block store when needed.
+    pub fn open(dir: &Path, genesis_block: Block, params: ChainParams) -> io::Result<Self> {
+        let policy = FlushPolicy::EveryBlocks(UTXO_FLUSH_BLOCKS);
+        let utxo_store = FileUtxoStore::open(&dir.join(UTXO_DIR), policy)?;
+        Blockchain::open_with_utxo_store(dir, genesis_block, params, Box::new(utxo_store))
+    }
+
+    /// Open chain stored in directory with UTXO set kept in given store
+    pub fn open_with_utxo_store(
+        dir: &Path,
+        genesis_block: Block,
+        params: ChainParams,
+        mut utxo_store: Box<dyn UtxoStore>,
+    ) -> io::Result<Self> {
+        let mut store = BlockStore::open(dir)?;
+
+        if store.is_empty() {
+            store.append(&genesis_block)?;
+        } else if store.hashes()[0] != *genesis_block.hash() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Stored chain has other genesis block",
+            ));
+        }
+        let genesis_hash = genesis_block.hash().clone();
+        let cut_off_age = params.cut_off_age;
+        let mut blockchain = Blockchain::with_params(genesis_block, params);
+        let best_position = utxo_store
+            .best_block()
+            .and_then(|best_block| store.hashes().iter().position(|hash| hash == best_block));
+        let replay_from = match best_position {
+            Some(position) => {
+                let best_block = store.hashes()[position].clone();
+                let branch = Branch::restore(&store, best_block, &genesis_hash, cut_off_age)?;
+                blockchain._branches.clear();
+                blockchain._branches.push(branch);
+                blockchain._utxo_pool = utxo_store.to_pool()?;
+                position + 1
+            }
+            None => {
+                utxo_store.reset(blockchain.get_max_height_utxo_pool(), genesis_hash)?;
+                1
+            }
+        };
+        store.set_active_chain(&blockchain._branches.peek().unwrap()._headers)?;
+        blockchain.enable_pruning(cut_off_age);
+        let hashes = store.hashes()[replay_from..].to_vec();
+        blockchain._store = Some(ChainStore {
+            blocks: store,
+            utxo: utxo_store,
+        });
+
+        for hash in hashes.iter() {
+            let block = blockchain.store().unwrap().read_block(hash)?.unwrap();
+            // blocks of abandoned branches may be rejected, they stay only on disk
+            let _ = blockchain.try_add_block(block);
+        }
+        Ok(blockchain)
+    }
+
+    pub fn store(&self) -> Option<&BlockStore> {
+        self._store.as_ref().map(|store| &store.blocks)
+    }
+
+    /// Write pending UTXO set changes to disk
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self._store {
+            Some(ref mut store) => store.utxo.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Keep bodies of only last `depth` blocks of every branch, only headers
+    /// and UTXO set are kept for older blocks. Depth is at least cut-off age,
+    /// so forks can still start from any block they are allowed to.
+    /// Blocks written to block store are not removed from disk.
+    pub fn enable_pruning(&mut self, depth: usize) {
+        let depth = cmp::max(depth, self._params.cut_off_age);
+        self._prune_depth = Some(depth);
+        self._branches = self._branches
+            .drain()
+            .map(|mut branch| {
+                branch.prune(depth);
+                branch
+            })
+            .collect();
+        self.drop_pruned_branches();
+    }
+
+    /// Start chain from UTXO snapshot, snapshot block becomes chain tip
+    pub fn from_snapshot(snapshot: UtxoSnapshot, params: ChainParams) -> Self {
+        let mut blockchain = Blockchain::with_params(snapshot.block.clone(), params);
+        let mut branch = blockchain._branches.pop().unwrap();
+        branch._headers = snapshot.headers;
+        blockchain._branches.push(branch);
+        blockchain._utxo_pool = snapshot.utxo_pool;
+        blockchain
+    }
+
+    /// UTXO snapshot of branch at block with given hash
+    pub fn snapshot(&self, hash: &[u8; 32]) -> Result<UtxoSnapshot, QueryError> {
+        let block = self.block_by_hash(hash)?.clone();
+        // undo data of every kept block allows to cut branch back to it
+        let branch = self.branch_at(hash, usize::max_value()).ok_or(QueryError::Pruned)?;
+        let utxo_pool = self.branch_utxo_pool(&branch).ok_or(QueryError::Pruned)?;
+        Ok(UtxoSnapshot {
+            headers: branch._headers,
+            block,
+            utxo_pool,
+        })
+    }
+
+    pub fn prune_depth(&self) -> Option<usize> {
+        self._prune_depth
+    }
+
+    /// Subscribe listener to changes of active chain and transaction pool.
+    /// Current state is delivered to it first: all blocks of active chain
+    /// as connected ones, its tip and pooled transactions, highest fee rate first.
+    /// Pruned blocks are read from block store, listener is not subscribed
+    /// if chain has no store or they can not be read.
+    pub fn subscribe(&mut self, mut listener: Box<dyn ChainListener>) -> Result<(), QueryError> {
+        {
+            let branch = self._branches.peek().unwrap();
+            let first_height = branch.len() - branch._blocks.len();
+
+            for (height, header) in branch._headers[..first_height].iter().enumerate() {
+                let (block, undo) = self.read_pruned(&header.hash, height)?;
+                listener.on_event(&ChainEvent::BlockConnected {
+                    block: &block,
+                    height,
+                    undo: &undo,
+                });
+            }
+            for (height, (block, undo)) in
+                (first_height..).zip(branch._blocks.iter().zip(branch._undo.iter()))
+            {
+                listener.on_event(&ChainEvent::BlockConnected {
+                    block,
+                    height,
+                    undo,
+                });
+            }
+            listener.on_event(&ChainEvent::TipChanged {
+                block: branch.tip(),
+                height: branch.len() - 1,
+            });
+            for tx in self._tx_pool.iter() {
+                listener.on_event(&ChainEvent::TxAddedToPool { tx });
+            }
+        }
+        self._listeners.push(listener);
+        Ok(())
+    }
+
+    /// Body and undo data of pruned block from block store
+    fn read_pruned(
+        &self,
+        hash: &[u8; 32],
+        height: usize,
+    ) -> Result<(Block, BlockUndo), QueryError> {
+        let store = self.store().ok_or(QueryError::Pruned)?;
+        let io_error = |err: io::Error| QueryError::Io(err.kind());
+        let block = store.read_block(hash).map_err(io_error)?.ok_or(QueryError::Pruned)?;
+
+        // genesis block spends nothing, its undo data is not stored
+        if height == 0 {
+            return Ok((block, BlockUndo::new()));
+        }
+        let undo = store.read_undo(hash).map_err(io_error)?.ok_or(QueryError::Pruned)?;
+        Ok((block, undo))
+    }
+
+    /// Block with given hash from any branch
+    pub fn block_by_hash(&self, hash: &[u8; 32]) -> Result<&Block, QueryError> {
+        for branch in self._branches.iter() {
+            if let Some(block) = branch._blocks.iter().rev().find(|block| block.hash() == hash) {
+                return Ok(block);
+            }
+        }
+        if self._branches
+            .iter()
+            .any(|branch| branch._headers.iter().any(|header| header.hash == *hash))
+        {
+            return Err(QueryError::Pruned);
+        }
+        Err(QueryError::NotFound)
+    }
+
+    /// Block of active chain, the chain which ends with highest tip,
+    /// `depth` blocks below its tip. Tip is at depth 0.
+    pub fn block_at_height(&self, depth: usize) -> Result<&Block, QueryError> {
+        let branch = self._branches.peek().unwrap();
+
+        if depth >= branch.len() {
+            return Err(QueryError::NotFound);
+        }
+        branch.block_at(branch.len() - 1 - depth).ok_or(QueryError::Pruned)
+    }
+
+    /// Block of active chain which includes transaction with given hash and
+    /// transaction position in it, coinbase is at position 0. Only blocks
+    /// whose bodies are kept are searched, from tip down. Pruned is returned
+    /// for transaction which is not found, if some blocks are pruned.
+    pub fn tx_by_hash(&self, hash: &[u8; 32]) -> Result<(&Block, usize), QueryError> {
+        let branch = self._branches.peek().unwrap();
+
+        for block in branch._blocks.iter().rev() {
+            if block.coinbase().hash() == *hash {
+                return Ok((block, 0));
+            }
+            if let Some(index) = block.txs().iter().position(|tx| tx.hash() == *hash) {
+                return Ok((block, index + 1));
+            }
+        }
+        if branch._blocks.len() < branch.len() {
+            return Err(QueryError::Pruned);
+        }
+        Err(QueryError::NotFound)
+    }
+
+    /// Number of active chain blocks from block which includes transaction
+    /// to tip, 0 for transaction which is not in active chain
+    pub fn confirmations(&self, tx_hash: &[u8; 32]) -> usize {
+        let branch = self._branches.peek().unwrap();
+
+        match self.tx_by_hash(tx_hash) {
+            Ok((block, _)) => branch.len() - branch.height_of(block.hash()).unwrap(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Height of block in active chain
+    pub fn height_of(&self, block_hash: &[u8; 32]) -> Option<usize> {
+        self._branches.peek().unwrap().height_of(block_hash)
+    }
+
+    /// Block is part of chain which ends with highest tip
+    pub fn is_in_active_chain(&self, block_hash: &[u8; 32]) -> bool {
+        self._branches.peek().unwrap().height_of(block_hash).is_some()
+    }
+
+    /// Hashes of active chain blocks from tip down to genesis: last ten blocks
+    /// one by one, then with doubling step. Peer finds the latest common block in it.
+    pub fn block_locator(&self) -> Vec<[u8; 32]> {
+        let headers = &self._branches.peek().unwrap()._headers;
+        let mut locator = Vec::new();
+        let mut height = headers.len() - 1;
+        let mut step = 1;
+
+        while height > 0 {
+            locator.push(headers[height].hash);
+
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator.push(headers[0].hash);
+        locator
+    }
+
+    /// Headers of up to `max` active chain blocks after the first locator block
+    /// found in active chain, after genesis block if none is found
+    pub fn headers_after(&self, locator: &[[u8; 32]], max: usize) -> Vec<BlockHeader> {
+        let branch = self._branches.peek().unwrap();
+        let start = locator
+            .iter()
+            .filter_map(|hash| branch.height_of(hash))
+            .next()
+            .unwrap_or(0);
+        branch._headers[start + 1..].iter().take(max).cloned().collect()
+    }
+
     pub fn get_max_height_block(&self) -> &Block {
-        &self._branches.peek().unwrap()._blocks.back().unwrap()
+        &self._branches.peek().unwrap().tip()
+    }
+
+    /// Height of highest block, genesis block has height 0
+    pub fn get_max_height(&self) -> usize {
+        self._branches.peek().unwrap().len() - 1
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self._params
     }
 
     pub fn get_max_height_branch(&mut self) -> PeekMut<Branch> {
         self._branches.peek_mut().unwrap()
     }
 
+    /// Unspent transaction outputs of highest branch
+    pub fn get_max_height_utxo_pool(&self) -> &UTXOPool {
+        &self._utxo_pool
+    }
+
     pub fn get_max_height_tx_pool(&mut self) -> &mut TransactionPool {
         &mut self._tx_pool
     }
 
+    pub fn tx_pool(&self) -> &TransactionPool {
+        &self._tx_pool
+    }
+
     pub fn add_block(&mut self, block: Block) -> bool {
+        self.try_add_block(block).is_ok()
+    }
+
+    /// Add block to chain or return reason why block was rejected.
+    /// Chain state changes only if block is accepted.
+    pub fn try_add_block(&mut self, block: Block) -> Result<(), BlockError> {
+        let old_tip = self.get_max_height_block().hash().clone();
+        self.insert_block(block)?;
+        self.change_tip(&old_tip);
+        self.drop_pruned_branches();
+        Ok(())
+    }
+
+    /// Drop transactions confirmed by blocks which joined active chain since
+    /// its tip was `old_tip` from pool and notify listeners about changes.
+    /// On reorganization transactions of disconnected blocks return to pool
+    /// and pooled transactions spending outputs which left UTXO set are dropped.
+    /// Pruned blocks can not be delivered to listeners.
+    fn change_tip(&mut self, old_tip: &[u8; 32]) {
+        let mut disconnected = Vec::new();
+        let reorg;
+        {
+            let new_branch = self._branches.peek().unwrap();
+
+            if new_branch.tip().hash() == old_tip {
+                return;
+            }
+            let (old_branch, fork_height) = match new_branch.height_of(old_tip) {
+                Some(height) => (new_branch, height),
+                None => {
+                    let old_branch = self._branches
+                        .iter()
+                        .find(|branch| branch.tip().hash() == old_tip)
+                        .unwrap();
+                    (old_branch, old_branch.common_height(new_branch))
+                }
+            };
+            let old_height = old_branch.height_of(old_tip).unwrap();
+            reorg = old_height > fork_height;
+
+            for height in (fork_height + 1..old_height + 1).rev() {
+                if let Some((block, undo)) = old_branch.entry_at(height) {
+                    let event = ChainEvent::BlockDisconnected {
+                        block,
+                        height,
+                        undo,
+                    };
+                    Self::notify(&mut self._listeners, &event);
+                    disconnected.push(block.txs().clone());
+                }
+            }
+            for height in fork_height + 1..new_branch.len() {
+                if let Some((block, undo)) = new_branch.entry_at(height) {
+                    let event = ChainEvent::BlockConnected {
+                        block,
+                        height,
+                        undo,
+                    };
+                    Self::notify(&mut self._listeners, &event);
+
+                    for tx in block.txs() {
+                        self._tx_pool.remove_confirmed(tx);
+                    }
+                    Self::notify_removed(&mut self._listeners, self._tx_pool.take_removed());
+                }
+            }
+        }
+        if reorg {
+            // transactions confirmed or double spent by new chain are rejected by pool
+            for txs in disconnected.into_iter().rev() {
+                for tx in txs {
+                    self.add_tx(tx);
+                }
+            }
+            let height = self._branches.peek().unwrap().len();
+            let coinbase_maturity = self._params.coinbase_maturity;
+            self._tx_pool.remove_invalid(&self._utxo_pool, height, coinbase_maturity);
+            Self::notify_removed(&mut self._listeners, self._tx_pool.take_removed());
+        }
+        let new_branch = self._branches.peek().unwrap();
+        let event = ChainEvent::TipChanged {
+            block: new_branch.tip(),
+            height: new_branch.len() - 1,
+        };
+        Self::notify(&mut self._listeners, &event);
+    }
+
+    fn notify(listeners: &mut Vec<Box<dyn ChainListener>>, event: &ChainEvent) {
+        for listener in listeners.iter_mut() {
+            listener.on_event(event);
+        }
+    }
+
+    fn notify_removed(
+        listeners: &mut Vec<Box<dyn ChainListener>>,
+        removed: Vec<(Transaction, RemovalReason)>,
+    ) {
+        for (tx, reason) in removed {
+            let event = ChainEvent::TxRemovedFromPool { tx: &tx, reason };
+            Self::notify(listeners, &event);
+        }
+    }
+
+    fn insert_block(&mut self, block: Block) -> Result<(), BlockError> {
         if *block.prev_hash() == [0; 32] {
-            return false;
+            return Err(BlockError::NoParent);
+        }
+        if !self._params.check_pow(block.hash()) {
+            return Err(BlockError::InsufficientWork);
+        }
+        let cut_off_age = self._params.cut_off_age;
+
+        if self._branches.iter().any(|branch| {
+            branch._headers.iter().rev().take(cut_off_age).any(|h| h.hash == *block.hash())
+        }) {
+            return Err(BlockError::Duplicate);
         }
         // try add block to highest branch
         let highest_branch_len;
         {
             let mut branch = self._branches.peek_mut().unwrap();
-            highest_branch_len = branch._blocks.len();
+            highest_branch_len = branch.len();
 
-            if *branch._blocks.back().unwrap().hash() != *block.prev_hash() {
-                Self::add_block_to_branch(&mut branch, block);
-                return true;
+            if *branch.tip().hash() == *block.prev_hash() {
+                return Self::add_block_to_branch(
+                    &mut branch,
+                    &mut self._utxo_pool,
+                    block,
+                    true,
+                    &self._params,
+                    self._prune_depth,
+                    self._store.as_mut(),
+                );
             }
         }
-        // search branch
+        // search branch, parent inside of branch starts new branch
+        let mut fork = None;
+
         if self._branches
             .iter()
-            .find(|ref branch| *branch._blocks.back().unwrap().hash() == *block.prev_hash())
+            .find(|ref branch| *branch.tip().hash() == *block.prev_hash())
             == None
         {
-            return false;
+            let branch = self.branch_at(block.prev_hash(), cut_off_age);
+            fork = Some(branch.ok_or(BlockError::UnknownParent)?);
         }
+        let forked = fork.is_some();
+        let mut utxo_pool = {
+            let branch = match fork {
+                Some(ref fork) => fork,
+                None => self._branches
+                    .iter()
+                    .find(|branch| *branch.tip().hash() == *block.prev_hash())
+                    .unwrap(),
+            };
+            self.branch_utxo_pool(branch).ok_or(BlockError::UnknownParent)?
+        };
         // drain branches heap, add block to branch, recreate heap
         let mut branches = BinaryHeap::new();
         // Modified branch
-        let mut mod_branch: Option<Branch> = None;
+        let mut mod_branch: Option<Branch> = fork;
+        let mut res = Err(BlockError::UnknownParent);
 
-        for mut branch in self._branches.drain() {
-            if cmp::max(highest_branch_len, branch._blocks.len())
-                - cmp::min(highest_branch_len, branch._blocks.len()) >= CUT_OFF_AGE
+        for branch in self._branches.drain() {
+            if cmp::max(highest_branch_len, branch.len())
+                - cmp::min(highest_branch_len, branch.len()) >= cut_off_age
             {
                 continue;
             }
-            if *branch._blocks.back().unwrap().hash() == *block.prev_hash() {
+            if !forked && *branch.tip().hash() == *block.prev_hash() {
                 mod_branch = Some(branch);
             } else {
                 branches.push(branch);
             }
         }
         if let Some(mut branch) = mod_branch {
-            Self::add_block_to_branch(&mut branch, block);
-            branches.push(branch);
+            // new block of shorter branch does not make it highest, ties keep older branch
+            let highest = branch.len() + 1 > highest_branch_len;
+            res = Self::add_block_to_branch(
+                &mut branch,
+                &mut utxo_pool,
+                block,
+                highest,
+                &self._params,
+                self._prune_depth,
+                self._store.as_mut(),
+            );
+
+            if res.is_ok() && highest {
+                self._utxo_pool = utxo_pool;
+            }
+
+            // new branch is kept only with its first block
+            if res.is_ok() || !forked {
+                branches.push(branch);
+            }
         }
         self._branches = branches;
-        true
+        res
     }
 
-    pub fn add_tx(&mut self, tx: Transaction) {
-        self._tx_pool.add_tx(tx)
+    /// Drop branches which fork from highest branch below pruned blocks of
+    /// any of them, UTXO set of such branch can not be derived anymore
+    fn drop_pruned_branches(&mut self) {
+        let highest_branch = self._branches.pop().unwrap();
+        let branches: BinaryHeap<Branch> = self._branches
+            .drain()
+            .filter(|branch| {
+                let fork_height = highest_branch.common_height(branch) + 1;
+                fork_height >= highest_branch.first_kept_height()
+                    && fork_height >= branch.first_kept_height()
+            })
+            .collect();
+        self._branches = branches;
+        self._branches.push(highest_branch);
     }
 
-    fn add_block_to_branch(branch: &mut Branch, block: Block) {
-        // Add coinbase tx to utxo pool
-        for (index, tx) in block.coinbase().get_outputs().iter().enumerate() {
-            let utxo = UTXO::new(block.coinbase().hash().clone(), index);
-            branch._utxo_pool.add_UTXO(utxo, tx.clone());
+    /// Copy of branch cut back to block with given hash, if block is one of
+    /// last `max_depth` blocks of branch. Only blocks not deeper than
+    /// cut-off age may be parents of new branch.
+    fn branch_at(&self, hash: &[u8; 32], max_depth: usize) -> Option<Branch> {
+        let (branch, depth) = self._branches
+            .iter()
+            .filter_map(|branch| {
+                branch
+                    ._blocks
+                    .iter()
+                    .rev()
+                    .take(max_depth)
+                    .position(|block| block.hash() == hash)
+                    .map(|depth| (branch, depth))
+            })
+            .next()?;
+        let mut fork = branch.clone();
+
+        for _ in 0..depth {
+            fork.pop();
+        }
+        fork._timestamp = time::get_time();
+        Some(fork)
+    }
+
+    /// UTXO set at tip of branch, derived from highest branch state: blocks of
+    /// highest branch above the last common block are disconnected, then
+    /// blocks of branch are connected. None if some of these blocks are pruned.
+    fn branch_utxo_pool(&self, branch: &Branch) -> Option<UTXOPool> {
+        let highest_branch = self._branches.peek().unwrap();
+        let common_height = highest_branch.common_height(branch);
+        let mut utxo_pool = self._utxo_pool.clone();
+
+        for height in (common_height + 1..highest_branch.len()).rev() {
+            let (block, undo) = highest_branch.entry_at(height)?;
+            Self::disconnect_block(&mut utxo_pool, block, undo)?;
+        }
+        for height in common_height + 1..branch.len() {
+            let block = branch.block_at(height)?;
+            Self::connect_block(&mut utxo_pool, block, height, &self._params).ok()?;
+        }
+        Some(utxo_pool)
+    }
+
+    /// Add transaction to global pool if it is valid against highest branch UTXO set
+    pub fn add_tx(&mut self, mut tx: Transaction) -> bool {
+        tx.finalize();
+        let hash = tx.hash();
+        let added;
+        {
+            let branch = self._branches.peek().unwrap();
+            let height = branch.len();
+            let coinbase_maturity = self._params.coinbase_maturity;
+            added = self._tx_pool.add_tx(tx, &self._utxo_pool, height, coinbase_maturity);
+        }
+        // replaced transactions leave pool before new one enters it, evicted ones after
+        let (replaced, evicted): (Vec<_>, Vec<_>) = self._tx_pool
+            .take_removed()
+            .into_iter()
+            .filter(|&(ref tx, _)| tx.hash() != hash)
+            .partition(|&(_, reason)| reason == RemovalReason::Replaced);
+        Self::notify_removed(&mut self._listeners, replaced);
+
+        if added {
+            let event = ChainEvent::TxAddedToPool {
+                tx: self._tx_pool.get_tx(&hash).unwrap(),
+            };
+            Self::notify(&mut self._listeners, &event);
+        }
+        Self::notify_removed(&mut self._listeners, evicted);
+        added
+    }
+
+    /// Drop transactions which stay in pool longer than its max age
+    pub fn expire_txs(&mut self, now: Timespec) {
+        self._tx_pool.expire(now);
+        Self::notify_removed(&mut self._listeners, self._tx_pool.take_removed());
+    }
+
+    /// Validate block transactions and coinbase against branch state, write
+    /// block to store and add it to branch. Block which makes branch `highest`
+    /// moves stored active chain and UTXO set to it as well.
+    /// Branch and its UTXO set are not changed if block is invalid or can not be written.
+    fn add_block_to_branch(
+        branch: &mut Branch,
+        utxo_pool: &mut UTXOPool,
+        block: Block,
+        highest: bool,
+        params: &ChainParams,
+        prune_depth: Option<usize>,
+        store: Option<&mut ChainStore>,
+    ) -> Result<(), BlockError> {
+        let height = branch.len();
+        let (batch, undo) = Self::connect_block(utxo_pool, &block, height, params)?;
+
+        if let Some(store) = store {
+            let result = store.write_block(branch, utxo_pool, &block, &undo, highest, batch);
+
+            if let Err(err) = result {
+                Self::disconnect_block(utxo_pool, &block, &undo).unwrap();
+                return Err(BlockError::Io(err.kind()));
+            }
         }
         // add block
-        branch._blocks.push_back(block);
+        branch.push(block, undo);
         branch._timestamp = time::get_time();
+
+        if let Some(depth) = prune_depth {
+            branch.prune(depth);
+        }
+        Ok(())
+    }
+
+    /// Validate block at given height against UTXO set of its parent and apply
+    /// block changes to it. Return applied changes and outputs spent by block.
+    /// UTXO set is not changed if block is invalid.
+    pub fn connect_block(
+        utxo_pool: &mut UTXOPool,
+        block: &Block,
+        height: usize,
+        params: &ChainParams,
+    ) -> Result<(UtxoBatch, BlockUndo), BlockError> {
+        // apply transactions to copy of branch state, so invalid block changes nothing
+        let mut new_pool = utxo_pool.clone();
+        let fees;
+        let mut batch;
+        let undo;
+        {
+            let mut tx_handler = TxHandler::new(&mut new_pool, height, params.coinbase_maturity);
+
+            if tx_handler.handle_txs(block.txs().clone()).len() != block.txs().len() {
+                return Err(BlockError::InvalidTx);
+            }
+            fees = tx_handler.fees();
+            let changes = tx_handler.into_changes();
+            batch = changes.0;
+            undo = changes.1;
+        }
+        let coinbase = block.coinbase();
+
+        if coinbase.is_coinbase() == 0 || coinbase.inputs_len() != 0 || coinbase.height() != height
+            || coinbase
+                .get_outputs()
+                .iter()
+                .any(|tx_out| !tx_out.value.is_finite() || tx_out.value < 0.0)
+        {
+            return Err(BlockError::InvalidCoinbase);
+        }
+        // coinbase may claim block subsidy and transaction fees, but no more
+        // written so that NaN value is rejected as well
+        if !(block.coinbase_value() <= params.subsidy(height) + fees) {
+            return Err(BlockError::CoinbaseValueTooHigh);
+        }
+        // Add coinbase tx to utxo pool
+        for (index, tx) in coinbase.get_outputs().iter().enumerate() {
+            let utxo = UTXO::new(coinbase.hash().clone(), index);
+            let entry = UTXOEntry {
+                tx_out: tx.clone(),
+                height,
+                coinbase: true,
+            };
+            new_pool.add_UTXO(utxo.clone(), tx.clone(), height, true);
+            batch.add(utxo, entry);
+        }
+        *utxo_pool = new_pool;
+        Ok((batch, undo))
+    }
+
+    /// Revert changes of block which is the last block connected to UTXO set:
+    /// drop outputs created by block and restore outputs it spent.
+    /// Return reverted changes or None, if undo data does not match block.
+    pub fn disconnect_block(
+        utxo_pool: &mut UTXOPool,
+        block: &Block,
+        undo: &BlockUndo,
+    ) -> Option<UtxoBatch> {
+        if undo.txs.len() != block.txs().len() {
+            return None;
+        }
+        let mut new_pool = utxo_pool.clone();
+        let mut batch = UtxoBatch::new();
+        let coinbase = block.coinbase();
+
+        for index in 0..coinbase.outputs_len() {
+            let utxo = UTXO::new(coinbase.hash().clone(), index);
+
+            if !new_pool.contains(&utxo) {
+                return None;
+            }
+            new_pool.remove_UTXO(utxo.clone());
+            batch.remove(utxo);
+        }
+        // later transactions may spend outputs of earlier ones, so revert them first
+        for (tx, tx_undo) in block.txs().iter().zip(undo.txs.iter()).rev() {
+            if tx_undo.spent.len() != tx.inputs_len() {
+                return None;
+            }
+            let mut tx = tx.clone();
+            tx.finalize();
+
+            for index in 0..tx.outputs_len() {
+                let utxo = UTXO::new(tx.hash(), index);
+
+                if !new_pool.contains(&utxo) {
+                    return None;
+                }
+                new_pool.remove_UTXO(utxo.clone());
+                batch.remove(utxo);
+            }
+            for (tx_in, spent) in tx.get_inputs().iter().zip(tx_undo.spent.iter()) {
+                if spent.utxo != UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index) {
+                    return None;
+                }
+                let entry = spent.entry.clone();
+                new_pool.add_UTXO(
+                    spent.utxo.clone(),
+                    entry.tx_out.clone(),
+                    entry.height,
+                    entry.coinbase,
+                );
+                batch.add(spent.utxo.clone(), entry);
+            }
+        }
+        *utxo_pool = new_pool;
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod blockchain_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+    use testutil::TestKey;
+
+    fn init_block(prev_hash: &[u8; 32], address: &[u8], value: f64, height: usize) -> Block {
+        let mut block = Block::new(prev_hash.clone(), address.to_vec(), value, height);
+        block.finalize();
+        block
+    }
+
+    #[test]
+    fn connect_disconnect_test() {
+        let params = ChainParams::new();
+        let genesis = init_block(&[1; 32], b"genesis", 25.0, 0);
+        let mut blocks = vec![genesis];
+        let mut pools = vec![Blockchain::new(blocks[0].clone()).get_max_height_utxo_pool().clone()];
+        let mut undos = Vec::new();
+
+        for height in 1..5 {
+            let block = init_block(blocks[height - 1].hash(), &[height as u8], 25.0, height);
+            let mut pool = pools[height - 1].clone();
+            let (_, undo) = Blockchain::connect_block(&mut pool, &block, height, &params).unwrap();
+            blocks.push(block);
+            pools.push(pool);
+            undos.push(undo);
+        }
+        for height in (1..5).rev() {
+            let mut pool = pools[height].clone();
+            let undo = &undos[height - 1];
+            Blockchain::disconnect_block(&mut pool, &blocks[height], undo).unwrap();
+            assert_eq!(pools[height - 1], pool);
+
+            let (_, reconnected) =
+                Blockchain::connect_block(&mut pool, &blocks[height], height, &params).unwrap();
+            assert_eq!(pools[height], pool);
+            assert_eq!(*undo, reconnected);
+            assert_eq!(Some(undo.clone()), BlockUndo::decode(&undo.encode()));
+        }
+        // block may be disconnected only from UTXO set it was connected to
+        let mut pool = pools[1].clone();
+        assert!(Blockchain::disconnect_block(&mut pool, &blocks[2], &undos[1]).is_none());
+        assert_eq!(pools[1], pool);
+    }
+
+    #[test]
+    fn fork_test() {
+        let genesis = init_block(&[1; 32], b"genesis", 25.0, 0);
+        let mut blockchain = Blockchain::new(genesis.clone());
+        let block1 = init_block(genesis.hash(), b"miner1", 25.0, 1);
+        let block2 = init_block(block1.hash(), b"miner2", 25.0, 2);
+        assert!(blockchain.add_block(block1.clone()));
+        assert!(blockchain.add_block(block2.clone()));
+        assert_eq!(Err(BlockError::Duplicate), blockchain.try_add_block(block2.clone()));
+
+        // fork from block 1 overtakes branch with block 2
+        let fork2 = init_block(block1.hash(), b"other2", 25.0, 2);
+        let fork3 = init_block(fork2.hash(), b"other3", 25.0, 3);
+        assert!(blockchain.add_block(fork2.clone()));
+        assert_eq!(block2.hash(), blockchain.get_max_height_block().hash());
+        assert!(blockchain.add_block(fork3.clone()));
+        assert_eq!(fork3.hash(), blockchain.get_max_height_block().hash());
+
+        let mut expected = Blockchain::new(genesis);
+        assert!(expected.add_block(block1));
+        assert!(expected.add_block(fork2));
+        assert!(expected.add_block(fork3));
+        assert_eq!(expected.get_max_height_utxo_pool(), blockchain.get_max_height_utxo_pool());
+    }
+
+    #[test]
+    fn pruning_test() {
+        let genesis = init_block(&[1; 32], b"genesis", 25.0, 0);
+        let mut params = ChainParams::new();
+        params.cut_off_age = 6;
+        let cut_off_age = params.cut_off_age;
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params);
+        blockchain.enable_pruning(1);
+        assert_eq!(Some(cut_off_age), blockchain.prune_depth());
+        let mut blocks = vec![genesis];
+
+        for height in 1..cut_off_age + 5 {
+            let block = init_block(blocks[height - 1].hash(), &[height as u8], 25.0, height);
+            assert!(blockchain.add_block(block.clone()));
+            blocks.push(block);
+        }
+        assert_eq!(cut_off_age + 4, blockchain.get_max_height());
+        assert_eq!(Err(QueryError::Pruned), blockchain.block_by_hash(blocks[0].hash()).map(|_| ()));
+        assert_eq!(Err(QueryError::Pruned), blockchain.block_by_hash(blocks[4].hash()).map(|_| ()));
+        assert!(blockchain.block_by_hash(blocks[5].hash()).is_ok());
+        assert_eq!(Err(QueryError::NotFound), blockchain.block_by_hash(&[7; 32]).map(|_| ()));
+        assert_eq!(Err(QueryError::Pruned), blockchain.block_at_height(cut_off_age).map(|_| ()));
+        let coinbase = blocks[cut_off_age + 4].coinbase().hash();
+        assert_eq!(Ok(0), blockchain.tx_by_hash(&coinbase).map(|(_, position)| position));
+        let coinbase = blocks[1].coinbase().hash();
+        assert_eq!(Err(QueryError::Pruned), blockchain.tx_by_hash(&coinbase).map(|_| ()));
+
+        // fork inside of kept window still replaces highest branch
+        let parent = &blocks[cut_off_age + 2];
+        let fork1 = init_block(parent.hash(), b"fork1", 25.0, cut_off_age + 3);
+        let fork2 = init_block(fork1.hash(), b"fork2", 25.0, cut_off_age + 4);
+        let fork3 = init_block(fork2.hash(), b"fork3", 25.0, cut_off_age + 5);
+        assert!(blockchain.add_block(fork1));
+        assert!(blockchain.add_block(fork2));
+        assert!(blockchain.add_block(fork3.clone()));
+        assert_eq!(fork3.hash(), blockchain.get_max_height_block().hash());
+        assert_eq!(cut_off_age + 5, blockchain.get_max_height());
+
+        // pruned blocks can not be replayed without block store
+        let recorder = Rc::new(RefCell::new(Recorder(Vec::new())));
+        assert_eq!(Err(QueryError::Pruned), blockchain.subscribe(Box::new(recorder.clone())));
+        assert!(recorder.borrow().0.is_empty());
+
+        // parent with pruned body can not start new branch
+        let late = init_block(blocks[1].hash(), b"late", 25.0, 2);
+        assert_eq!(Err(BlockError::UnknownParent), blockchain.try_add_block(late));
+    }
+
+    #[test]
+    fn prune_boundary_test() {
+        let genesis = init_block(&[1; 32], b"genesis", 25.0, 0);
+        let mut blockchain = Blockchain::new(genesis.clone());
+        let cut_off_age = blockchain.params().cut_off_age;
+        blockchain.enable_pruning(cut_off_age);
+        let block1 = init_block(genesis.hash(), b"miner1", 25.0, 1);
+        assert!(blockchain.add_block(block1.clone()));
+        let mut main = vec![block1.clone()];
+        let mut fork = vec![block1];
+
+        // both branches keep every block above fork point
+        for height in 2..cut_off_age + 2 {
+            let block = init_block(main[height - 2].hash(), &[height as u8], 25.0, height);
+            assert!(blockchain.add_block(block.clone()));
+            main.push(block);
+            let address = format!("fork{}", height).into_bytes();
+            let block = init_block(fork[height - 2].hash(), &address, 25.0, height);
+            assert!(blockchain.add_block(block.clone()));
+            fork.push(block);
+        }
+        assert_eq!(2, blockchain._branches.len());
+        assert_eq!(main[cut_off_age].hash(), blockchain.get_max_height_block().hash());
+
+        // fork overtakes, replaced branch needs pruned block of fork and is dropped
+        let height = cut_off_age + 2;
+        let tip = init_block(fork[cut_off_age].hash(), b"fork tip", 25.0, height);
+        assert!(blockchain.add_block(tip.clone()));
+        assert_eq!(tip.hash(), blockchain.get_max_height_block().hash());
+        assert_eq!(1, blockchain._branches.len());
+        let late = init_block(main[cut_off_age].hash(), b"late", 25.0, height);
+        assert_eq!(Err(BlockError::UnknownParent), blockchain.try_add_block(late));
+
+        let mut expected = Blockchain::new(genesis);
+
+        for block in fork.into_iter().chain(Some(tip)) {
+            assert!(expected.add_block(block));
+        }
+        assert_eq!(expected.get_max_height_utxo_pool(), blockchain.get_max_height_utxo_pool());
+    }
+
+    #[test]
+    fn reopen_test() {
+        let dir = env::temp_dir().join("blockchain_reopen");
+        let _ = fs::remove_dir_all(&dir);
+        let genesis = init_block(&[1; 32], b"genesis", 25.0, 0);
+        let mut blockchain = Blockchain::open(&dir, genesis.clone(), ChainParams::new()).unwrap();
+        let cut_off_age = blockchain.params().cut_off_age;
+        let mut blocks = vec![genesis.clone()];
+
+        for height in 1..cut_off_age + 5 {
+            let block = init_block(blocks[height - 1].hash(), &[height as u8], 25.0, height);
+            assert!(blockchain.add_block(block.clone()));
+            blocks.push(block);
+        }
+        let utxo_pool = blockchain.get_max_height_utxo_pool().clone();
+        drop(blockchain);
+
+        // only recent bodies are in memory, older ones are read from store
+        let mut blockchain = Blockchain::open(&dir, genesis, ChainParams::new()).unwrap();
+        assert_eq!(cut_off_age + 4, blockchain.get_max_height());
+        assert_eq!(&utxo_pool, blockchain.get_max_height_utxo_pool());
+        assert_eq!(Err(QueryError::Pruned), blockchain.block_by_hash(blocks[1].hash()).map(|_| ()));
+        let stored = blockchain.store().unwrap().read_block(blocks[1].hash()).unwrap();
+        assert_eq!(Some(blocks[1].hash()), stored.as_ref().map(|block| block.hash()));
+
+        // restored undo data lets fork inside of kept window replace highest branch
+        let parent = &blocks[cut_off_age + 1];
+        let fork1 = init_block(parent.hash(), b"fork1", 25.0, cut_off_age + 2);
+        let fork2 = init_block(fork1.hash(), b"fork2", 25.0, cut_off_age + 3);
+        let fork3 = init_block(fork2.hash(), b"fork3", 25.0, cut_off_age + 4);
+        let fork4 = init_block(fork3.hash(), b"fork4", 25.0, cut_off_age + 5);
+        assert!(blockchain.add_block(fork1.clone()));
+        assert!(blockchain.add_block(fork2.clone()));
+        assert!(blockchain.add_block(fork3.clone()));
+        assert!(blockchain.add_block(fork4.clone()));
+        assert_eq!(fork4.hash(), blockchain.get_max_height_block().hash());
+
+        let mut expected = Blockchain::new(blocks[0].clone());
+
+        for block in blocks[1..cut_off_age + 2].iter().chain(&[fork1, fork2, fork3, fork4]) {
+            assert!(expected.add_block(block.clone()));
+        }
+        assert_eq!(expected.get_max_height_utxo_pool(), blockchain.get_max_height_utxo_pool());
+    }
+
+    #[test]
+    fn query_test() {
+        let genesis = init_block(&[1; 32], b"genesis", 25.0, 0);
+        let mut blockchain = Blockchain::new(genesis.clone());
+        let block1 = init_block(genesis.hash(), b"miner1", 25.0, 1);
+        let block2 = init_block(block1.hash(), b"miner2", 25.0, 2);
+        let fork2 = init_block(block1.hash(), b"other2", 25.0, 2);
+        assert!(blockchain.add_block(block1.clone()));
+        assert!(blockchain.add_block(block2.clone()));
+        assert!(blockchain.add_block(fork2.clone()));
+
+        assert_eq!(block2.hash(), blockchain.block_by_hash(block2.hash()).unwrap().hash());
+        assert_eq!(fork2.hash(), blockchain.block_by_hash(fork2.hash()).unwrap().hash());
+        assert_eq!(block2.hash(), blockchain.block_at_height(0).unwrap().hash());
+        assert_eq!(block1.hash(), blockchain.block_at_height(1).unwrap().hash());
+        assert_eq!(genesis.hash(), blockchain.block_at_height(2).unwrap().hash());
+        assert_eq!(Err(QueryError::NotFound), blockchain.block_at_height(3).map(|_| ()));
+        assert!(blockchain.is_in_active_chain(block2.hash()));
+        assert!(!blockchain.is_in_active_chain(fork2.hash()));
+
+        let (block, position) = blockchain.tx_by_hash(&block1.coinbase().hash()).unwrap();
+        assert_eq!((block1.hash(), 0), (block.hash(), position));
+        assert_eq!(Err(QueryError::NotFound), blockchain.tx_by_hash(&[7; 32]).map(|_| ()));
+        assert_eq!(2, blockchain.confirmations(&block1.coinbase().hash()));
+        assert_eq!(0, blockchain.confirmations(&fork2.coinbase().hash()));
+
+        // fork becomes active chain
+        let fork3 = init_block(fork2.hash(), b"other3", 25.0, 3);
+        assert!(blockchain.add_block(fork3.clone()));
+        assert!(blockchain.is_in_active_chain(fork2.hash()));
+        assert!(!blockchain.is_in_active_chain(block2.hash()));
+        assert_eq!(fork2.hash(), blockchain.block_at_height(1).unwrap().hash());
+        assert_eq!(2, blockchain.confirmations(&fork2.coinbase().hash()));
+        assert_eq!(0, blockchain.confirmations(&block2.coinbase().hash()));
+        assert_eq!(3, blockchain.confirmations(&block1.coinbase().hash()));
+    }
+
+    /// Listener which records event names with block or transaction hash
+    struct Recorder(Vec<(String, [u8; 32])>);
+
+    impl ChainListener for Recorder {
+        fn on_event(&mut self, event: &ChainEvent) {
+            let record = match *event {
+                ChainEvent::BlockConnected { block, height, .. } => {
+                    (format!("connected {}", height), block.hash().clone())
+                }
+                ChainEvent::BlockDisconnected { block, height, .. } => {
+                    (format!("disconnected {}", height), block.hash().clone())
+                }
+                ChainEvent::TipChanged { block, height } => {
+                    (format!("tip {}", height), block.hash().clone())
+                }
+                ChainEvent::TxAddedToPool { tx } => ("added".to_string(), tx.hash()),
+                ChainEvent::TxRemovedFromPool { tx, reason } => {
+                    (format!("removed {:?}", reason), tx.hash())
+                }
+            };
+            self.0.push(record);
+        }
+    }
+
+    #[test]
+    fn events_test() {
+        let key = TestKey::new(0);
+        let genesis = init_block(&[1; 32], &key.address, 25.0, 0);
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 1;
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params);
+        let recorder = Rc::new(RefCell::new(Recorder(Vec::new())));
+        blockchain.subscribe(Box::new(recorder.clone())).unwrap();
+
+        let address = key.address.clone();
+        let spend = |utxo: &UTXO, value: f64| key.spend(&[utxo.clone()], &[(value, &address[..])]);
+        // second spend pays higher fee and replaces first one
+        let genesis_coin = UTXO::new(genesis.coinbase().hash(), 0);
+        let tx1 = spend(&genesis_coin, 24.0);
+        let tx2 = spend(&genesis_coin, 20.0);
+        assert!(blockchain.add_tx(tx1.clone()));
+        assert!(blockchain.add_tx(tx2.clone()));
+
+        let mut block1 = Block::new(genesis.hash().clone(), address.clone(), 25.0, 1);
+        block1.add_tx(tx2.clone());
+        block1.finalize();
+        assert!(blockchain.add_block(block1.clone()));
+        let child = spend(&UTXO::new(tx2.hash(), 0), 19.0);
+        let reward = spend(&UTXO::new(block1.coinbase().hash(), 0), 24.0);
+        assert!(blockchain.add_tx(child.clone()));
+        assert!(blockchain.add_tx(reward.clone()));
+
+        // longer fork replaces block 1, its transaction returns to pool
+        // and spend of its coinbase is dropped
+        let fork1 = init_block(genesis.hash(), b"other1", 25.0, 1);
+        let fork2 = init_block(fork1.hash(), b"other2", 25.0, 2);
+        assert!(blockchain.add_block(fork1.clone()));
+        assert!(blockchain.add_block(fork2.clone()));
+        assert!(blockchain.tx_pool().contains(&tx2.hash()));
+        assert!(blockchain.tx_pool().contains(&child.hash()));
+        assert_eq!(2, blockchain.tx_pool().len());
+
+        let expected = vec![
+            ("connected 0", genesis.hash().clone()),
+            ("tip 0", genesis.hash().clone()),
+            ("added", tx1.hash()),
+            ("removed Replaced", tx1.hash()),
+            ("added", tx2.hash()),
+            ("connected 1", block1.hash().clone()),
+            ("removed Confirmed", tx2.hash()),
+            ("tip 1", block1.hash().clone()),
+            ("added", child.hash()),
+            ("added", reward.hash()),
+            ("disconnected 1", block1.hash().clone()),
+            ("connected 1", fork1.hash().clone()),
+            ("connected 2", fork2.hash().clone()),
+            ("added", tx2.hash()),
+            ("removed Invalid", reward.hash()),
+            ("tip 2", fork2.hash().clone()),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(name, hash)| (name.to_string(), hash))
+            .collect();
+        assert_eq!(expected, recorder.borrow().0);
     }
 }
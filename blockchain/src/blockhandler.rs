@@ -1,42 +1,189 @@
+use address_index::AddressIndex;
 use block::Block;
+use block_assembler::BlockAssembler;
+use blockchain::BlockError;
 use blockchain::Blockchain;
+use blockchain::QueryError;
+use std::cell::Ref;
+use std::cell::RefCell;
+use std::rc::Rc;
 use transaction::Transaction;
 use txhandler::TxHandler;
 
 pub struct BlockHandler {
     blockchain: Blockchain,
+    assembler: BlockAssembler,
+    /// Index of active chain transactions by address, if it is enabled
+    address_index: Option<Rc<RefCell<AddressIndex>>>,
 }
 
 impl BlockHandler {
     pub fn new(blockchain: Blockchain) -> Self {
-        BlockHandler { blockchain }
+        BlockHandler::with_assembler(blockchain, BlockAssembler::new())
+    }
+
+    pub fn with_assembler(blockchain: Blockchain, assembler: BlockAssembler) -> Self {
+        BlockHandler {
+            blockchain,
+            assembler,
+            address_index: None,
+        }
+    }
+
+    pub fn blockchain(&self) -> &Blockchain {
+        &self.blockchain
+    }
+
+    pub fn blockchain_mut(&mut self) -> &mut Blockchain {
+        &mut self.blockchain
+    }
+
+    /// Build index of active chain transactions by address and keep it up to date.
+    /// Index is not enabled if pruned blocks can not be read from block store.
+    pub fn enable_address_index(&mut self) -> Result<(), QueryError> {
+        let index = Rc::new(RefCell::new(AddressIndex::new()));
+        self.blockchain.subscribe(Box::new(index.clone()))?;
+        self.address_index = Some(index);
+        Ok(())
+    }
+
+    pub fn address_index(&self) -> Option<Ref<AddressIndex>> {
+        self.address_index.as_ref().map(|index| index.borrow())
     }
 
     pub fn process_block(&mut self, block: Block) -> bool {
         self.blockchain.add_block(block)
     }
 
-    pub fn precess_tx(&mut self, tx: Transaction) {
-        self.blockchain.add_tx(tx);
+    pub fn precess_tx(&mut self, tx: Transaction) -> bool {
+        self.blockchain.add_tx(tx)
     }
 
-    pub fn create_block(&mut self, my_address: Vec<u8>) -> Block {
+    /// Mine block on top of highest branch paying reward to address.
+    /// Return mined block to broadcast or reason why chain rejected it.
+    pub fn create_block(&mut self, my_address: Vec<u8>) -> Result<Block, BlockError> {
         let parent_hash = self.blockchain.get_max_height_block().hash().clone();
-        let mut current = Block::new(parent_hash, my_address);
-        let mut txs = self.blockchain.get_max_height_tx_pool().get_all_txs();
+        let height = self.blockchain.get_max_height() + 1;
+        let template = self.assembler.assemble(self.blockchain.get_max_height_tx_pool());
+        let txs;
+        let fees;
         {
-            //TODO: txpoll changes, but not involved in heap reorganization
-            let mut branch = self.blockchain.get_max_height_branch();
-            let mut tx_handler = TxHandler::new(&mut branch._utxo_pool);
-            txs = tx_handler.handle_txs(txs);
+            // check transactions against copy of tip state,
+            // branch state changes only when block is added
+            let mut utxo_pool = self.blockchain.get_max_height_utxo_pool().clone();
+            let coinbase_maturity = self.blockchain.params().coinbase_maturity;
+            let mut tx_handler = TxHandler::new(&mut utxo_pool, height, coinbase_maturity);
+            txs = tx_handler.handle_txs(template.txs);
+            fees = tx_handler.fees();
         }
+        let coinbase_value = self.blockchain.params().subsidy(height) + fees;
+        let mut current = Block::new(parent_hash, my_address, coinbase_value, height);
 
         for tx in txs {
             current.add_tx(tx);
         }
-        current.finalize();
-        self.blockchain.add_block(current);
-        //
-        Block::new([0; 32], Vec::new())
+        current.mine(self.blockchain.params());
+        self.blockchain.try_add_block(current.clone())?;
+        Ok(current)
+    }
+}
+
+#[cfg(test)]
+mod blockhandler_tests {
+    use super::*;
+    use params::ChainParams;
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::rc::Rc;
+    use testutil::FailingStore;
+    use testutil::TestKey;
+    use utxo::UTXO;
+
+    #[test]
+    fn signed_spend_test() {
+        let alice = TestKey::new(0);
+        let bob = TestKey::new(1);
+        let genesis = alice.genesis();
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 1;
+        let blockchain = Blockchain::with_params(genesis.clone(), params);
+        let mut handler = BlockHandler::new(blockchain);
+        let coin = UTXO::new(genesis.coinbase().hash(), 0);
+
+        // only owner of output can spend it
+        let forged = bob.spend(&[coin.clone()], &[(24.0, &bob.address[..])]);
+        assert!(!handler.precess_tx(forged));
+        let tx = alice.spend(&[coin.clone()], &[(24.0, &bob.address[..])]);
+        assert_eq!(256, tx.get_input(0).signature.len());
+        assert!(handler.precess_tx(tx.clone()));
+
+        let block = handler.create_block(b"miner".to_vec()).unwrap();
+        assert_eq!(vec![tx.clone()], *block.txs());
+        assert_eq!(26.0, block.coinbase_value());
+        assert_eq!(block.hash(), handler.blockchain().get_max_height_block().hash());
+
+        let utxo_pool = handler.blockchain().get_max_height_utxo_pool();
+        assert!(!utxo_pool.contains(&coin));
+        let tx_out = utxo_pool.get_tx_out(&UTXO::new(tx.hash(), 0)).unwrap();
+        assert_eq!(bob.address, tx_out.address);
+    }
+
+    #[test]
+    fn same_address_test() {
+        let mut genesis = Block::new([1; 32], b"genesis".to_vec(), 25.0, 0);
+        genesis.finalize();
+        let mut handler = BlockHandler::new(Blockchain::new(genesis));
+
+        // coinbases differ by height, so second reward does not replace first one
+        let block1 = handler.create_block(b"miner".to_vec()).unwrap();
+        let block2 = handler.create_block(b"miner".to_vec()).unwrap();
+        assert!(block1.coinbase().hash() != block2.coinbase().hash());
+        let balance: f64 = handler
+            .blockchain()
+            .get_max_height_utxo_pool()
+            .iter()
+            .filter(|&(_, entry)| entry.tx_out.address == b"miner")
+            .map(|(_, entry)| entry.tx_out.value)
+            .sum();
+        assert_eq!(50.0, balance);
+
+        // coinbase must commit to height of its block and pay finite value
+        let params = handler.blockchain().params().clone();
+        let mut reused = Block::new(*block2.hash(), b"miner".to_vec(), 25.0, 2);
+        reused.mine(&params);
+        let result = handler.blockchain_mut().try_add_block(reused);
+        assert_eq!(Err(BlockError::InvalidCoinbase), result);
+        let mut nan = Block::new(*block2.hash(), b"miner".to_vec(), ::std::f64::NAN, 3);
+        nan.mine(&params);
+        let result = handler.blockchain_mut().try_add_block(nan);
+        assert_eq!(Err(BlockError::InvalidCoinbase), result);
+    }
+
+    #[test]
+    fn write_failure_test() {
+        let dir = env::temp_dir().join("blockhandler_write_failure");
+        let _ = fs::remove_dir_all(&dir);
+        let mut genesis = Block::new([1; 32], b"genesis".to_vec(), 25.0, 0);
+        genesis.finalize();
+        let fail = Rc::new(Cell::new(false));
+        let store = FailingStore::new(fail.clone());
+        let params = ChainParams::new();
+        let blockchain =
+            Blockchain::open_with_utxo_store(&dir, genesis, params, Box::new(store)).unwrap();
+        let mut handler = BlockHandler::new(blockchain);
+
+        // mined block is not accepted, if chain can not write it
+        fail.set(true);
+        let result = handler.create_block(b"miner".to_vec()).map(|_| ());
+        assert_eq!(Err(BlockError::Io(io::ErrorKind::Other)), result);
+        assert_eq!(0, handler.blockchain().get_max_height());
+        assert_eq!(1, handler.blockchain().get_max_height_utxo_pool().len());
+
+        fail.set(false);
+        let block = handler.create_block(b"miner".to_vec()).unwrap();
+        assert_eq!(block.hash(), handler.blockchain().get_max_height_block().hash());
+        assert_eq!(2, handler.blockchain().get_max_height_utxo_pool().len());
     }
 }
@@ -0,0 +1,275 @@
+use crypto;
+use std::io;
+use transaction::TransactionOutput;
+use utxo::UTXOEntry;
+use utxo::UTXO;
+
+/// Largest accepted output address, RSA public key in PEM format
+pub static MAX_ADDRESS_SIZE: usize = 4096;
+/// Largest accepted input signature, RSA signature of 8192-bit key
+pub static MAX_SIGNATURE_SIZE: usize = 1024;
+
+/// Type with canonical binary encoding
+pub trait Encode {
+    fn encode(&self, data: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode(&mut data);
+        data
+    }
+}
+
+/// Type restored from its canonical binary encoding. Decoding fails
+/// on truncated data, out of range values and exceeded limits.
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Option<Self>;
+
+    /// Decode value which takes whole data, trailing bytes are rejected
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(data);
+        let value = Self::decode(&mut reader)?;
+
+        if !reader.is_empty() {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// First bytes of data double SHA-256
+pub fn checksum(data: &[u8]) -> [u8; 4] {
+    let hash = crypto::double_sha256(&data.to_vec());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn put_u16(data: &mut Vec<u8>, value: u16) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+}
+
+pub fn put_u32(data: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        data.push((value >> (8 * i)) as u8);
+    }
+}
+
+pub fn put_u64(data: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        data.push((value >> (8 * i)) as u8);
+    }
+}
+
+pub fn put_utxo(data: &mut Vec<u8>, utxo: &UTXO) {
+    data.extend(utxo.hash.iter());
+    put_u64(data, utxo.index as u64);
+}
+
+pub fn put_utxo_entry(data: &mut Vec<u8>, entry: &UTXOEntry) {
+    put_u64(data, entry.height as u64);
+    data.push(entry.coinbase as u8);
+    entry.tx_out.encode(data);
+}
+
+/// Little-endian reader over serialized data
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (res, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(res)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.u64_le(2).map(|value| value as u16)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.u64_le(4).map(|value| value as u32)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.u64_le(8)
+    }
+
+    /// Read item count, count is rejected if remaining data
+    /// can not hold that many items of `min_size` bytes
+    pub fn count(&mut self, min_size: usize) -> Option<usize> {
+        let count = self.u32()? as usize;
+
+        if count > self.data.len() / min_size {
+            return None;
+        }
+        Some(count)
+    }
+
+    /// Read bool encoded as single 0 or 1 byte
+    pub fn flag(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn hash(&mut self) -> Option<[u8; 32]> {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(self.bytes(32)?);
+        Some(hash)
+    }
+
+    pub fn utxo(&mut self) -> Option<UTXO> {
+        Some(UTXO::new(self.hash()?, self.u64()? as usize))
+    }
+
+    pub fn utxo_entry(&mut self) -> Option<UTXOEntry> {
+        let height = self.u64()? as usize;
+        let coinbase = self.flag()?;
+        Some(UTXOEntry {
+            tx_out: TransactionOutput::decode(self)?,
+            height,
+            coinbase,
+        })
+    }
+
+    fn u64_le(&mut self, len: usize) -> Option<u64> {
+        let bytes = self.bytes(len)?;
+        Some(
+            bytes
+                .iter()
+                .enumerate()
+                .fold(0, |value, (i, byte)| value | (*byte as u64) << (8 * i)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+    use block::Block;
+    use transaction::Transaction;
+
+    fn init_tx() -> Transaction {
+        let mut tx = Transaction::new();
+        tx.add_input_tx([3; 32], 1);
+        tx.add_input_tx([4; 32], 0);
+        tx.add_signature(vec![5; 256], 0);
+        tx.add_output_tx(2.5, b"address".to_vec());
+        tx.add_output_tx(0.5, Vec::new());
+        tx.finalize();
+        tx
+    }
+
+    #[test]
+    fn transaction_round_trip_test() {
+        let tx = init_tx();
+        let data = tx.to_bytes();
+        let decoded = Transaction::from_bytes(&data).unwrap();
+        assert_eq!(tx, decoded);
+        assert_eq!(data, decoded.to_bytes());
+        assert_eq!(tx.hash(), decoded.hash());
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(Transaction::from_bytes(&trailing).is_none());
+        assert!(Transaction::from_bytes(&data[..data.len() - 1]).is_none());
+
+        // coinbase flag is 0 or 1
+        let mut flag = data.clone();
+        flag[0] = 2;
+        assert!(Transaction::from_bytes(&flag).is_none());
+
+        // input count larger than data can hold
+        let mut count = data.clone();
+        count[1..5].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(Transaction::from_bytes(&count).is_none());
+
+        let mut output = Vec::new();
+        put_u64(&mut output, 1.0f64.to_bits());
+        put_u32(&mut output, MAX_ADDRESS_SIZE as u32 + 1);
+        output.extend(vec![0; MAX_ADDRESS_SIZE + 1]);
+        assert!(TransactionOutput::from_bytes(&output).is_none());
+    }
+
+    #[test]
+    fn hash_test() {
+        let tx = init_tx();
+        assert_eq!(crypto::double_sha256(&tx.raw_data()), tx.hash());
+
+        // outputs split at other place hash differently
+        let mut address = b"a".to_vec();
+        put_u64(&mut address, 2.0f64.to_bits());
+        address.push(b'b');
+        let mut joined = Transaction::new();
+        joined.add_output_tx(1.0, address);
+        joined.finalize();
+        let mut split = Transaction::new();
+        split.add_output_tx(1.0, b"a".to_vec());
+        split.add_output_tx(2.0, b"b".to_vec());
+        split.finalize();
+        assert!(joined.hash() != split.hash());
+
+        let coinbase = Transaction::new_coinbase(25.0, b"miner".to_vec(), 1);
+        let block = Block::from_parts([1; 32], coinbase, vec![joined, split]);
+        let mut txs = block.coinbase().to_bytes();
+        put_u32(&mut txs, 2);
+        txs.extend(block.tx(0).to_bytes());
+        txs.extend(block.tx(1).to_bytes());
+        assert_eq!(crypto::double_sha256(&txs), block.header().tx_root);
+    }
+
+    #[test]
+    fn signature_test() {
+        let tx = init_tx();
+        let mut other = tx.clone();
+        other.add_signature(vec![6; 300], 0);
+        other.finalize();
+
+        // signatures do not change hash, but are encoded and counted in size
+        assert_eq!(tx.hash(), other.hash());
+        assert_eq!(tx.size() + 44, other.size());
+        assert_eq!(other, Transaction::from_bytes(&other.to_bytes()).unwrap());
+    }
+
+    #[test]
+    fn block_round_trip_test() {
+        let coinbase = Transaction::new_coinbase(25.0, b"miner".to_vec(), 1);
+        let block = Block::from_parts([1; 32], coinbase, vec![init_tx(), init_tx()]);
+        let data = block.to_bytes();
+        let decoded = Block::from_bytes(&data).unwrap();
+        assert_eq!(data, decoded.to_bytes());
+        assert_eq!(block.hash(), decoded.hash());
+        assert_eq!(block.txs(), decoded.txs());
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(Block::from_bytes(&trailing).is_none());
+
+        // hash must match block content
+        let mut hash = data.clone();
+        hash[0] ^= 1;
+        assert!(Block::from_bytes(&hash).is_none());
+    }
+}
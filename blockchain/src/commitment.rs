@@ -0,0 +1,127 @@
+use codec::put_utxo;
+use codec::put_utxo_entry;
+use openssl::bn::BigNum;
+use openssl::bn::BigNumContext;
+use openssl::sha::sha256;
+use utxo::UTXOEntry;
+use utxo::UTXO;
+
+/// Set elements are numbers below 2^3072
+const ELEMENT_BITS: i32 = 3072;
+const ELEMENT_BYTES: usize = 384;
+/// Modulus is the largest prime below 2^3072, 2^3072 - 1103717
+const MODULUS_OFFSET: u32 = 1103717;
+
+/// Order-independent hash of set of unspent outputs, built like MuHash:
+/// every `(UTXO, UTXOEntry)` entry is hashed to number modulo large
+/// prime and numbers are multiplied, so entries can be added in any order.
+pub struct UtxoCommitment {
+    modulus: BigNum,
+    product: BigNum,
+}
+
+impl UtxoCommitment {
+    /// Commitment to empty set
+    pub fn new() -> Self {
+        let mut modulus = BigNum::new().unwrap();
+        modulus.lshift(&BigNum::from_u32(1).unwrap(), ELEMENT_BITS).unwrap();
+        modulus.sub_word(MODULUS_OFFSET).unwrap();
+        UtxoCommitment {
+            modulus,
+            product: BigNum::from_u32(1).unwrap(),
+        }
+    }
+
+    pub fn insert(&mut self, utxo: &UTXO, entry: &UTXOEntry) {
+        let element = Self::element(utxo, entry);
+        Self::multiply(&mut self.product, &element, &self.modulus);
+    }
+
+    /// 32 byte hash of set at given block, equal for equal sets at equal blocks
+    pub fn finalize(&self, block_hash: &[u8; 32]) -> [u8; 32] {
+        let bytes = self.product.to_vec();
+        let mut data = block_hash.to_vec();
+        data.extend(vec![0; ELEMENT_BYTES - bytes.len()]);
+        data.extend(bytes);
+        sha256(&data)
+    }
+
+    /// Expand entry hash into set element, entry is hashed in its
+    /// canonical encoding: output, creation height and coinbase flag
+    fn element(utxo: &UTXO, entry: &UTXOEntry) -> BigNum {
+        let mut data = Vec::new();
+        put_utxo(&mut data, utxo);
+        put_utxo_entry(&mut data, entry);
+        let seed = sha256(&data);
+        let mut bytes = Vec::with_capacity(ELEMENT_BYTES);
+
+        for counter in 0..(ELEMENT_BYTES / 32) as u8 {
+            let mut block = seed.to_vec();
+            block.push(counter);
+            bytes.extend(sha256(&block).iter());
+        }
+        BigNum::from_slice(&bytes).unwrap()
+    }
+
+    fn multiply(target: &mut BigNum, factor: &BigNum, modulus: &BigNum) {
+        let mut ctx = BigNumContext::new().unwrap();
+        let mut product = BigNum::new().unwrap();
+        product.mod_mul(target, factor, modulus, &mut ctx).unwrap();
+        *target = product;
+    }
+}
+
+#[cfg(test)]
+mod commitment_tests {
+    use super::*;
+    use transaction::TransactionOutput;
+
+    fn entry(index: usize, value: f64) -> (UTXO, UTXOEntry) {
+        let utxo = UTXO::new([index as u8; 32], index);
+        let entry = UTXOEntry {
+            tx_out: TransactionOutput::new(value, b"address".to_vec()),
+            height: index,
+            coinbase: false,
+        };
+        (utxo, entry)
+    }
+
+    fn commit(entries: &[(UTXO, UTXOEntry)], block_hash: &[u8; 32]) -> [u8; 32] {
+        let mut commitment = UtxoCommitment::new();
+
+        for &(ref utxo, ref entry) in entries.iter() {
+            commitment.insert(utxo, entry);
+        }
+        commitment.finalize(block_hash)
+    }
+
+    #[test]
+    fn order_independence_test() {
+        let entries: Vec<_> = (0..4).map(|index| entry(index, index as f64)).collect();
+        let mut backward = entries.clone();
+        backward.reverse();
+        assert_eq!(commit(&entries, &[1; 32]), commit(&backward, &[1; 32]));
+        assert!(commit(&entries, &[1; 32]) != commit(&[], &[1; 32]));
+        assert!(commit(&entries, &[1; 32]) != commit(&entries[1..], &[1; 32]));
+
+        // block hash is committed as well
+        assert!(commit(&entries, &[1; 32]) != commit(&entries, &[2; 32]));
+    }
+
+    #[test]
+    fn entry_test() {
+        let (utxo, entry) = entry(1, 1.0);
+        let same = commit(&[(utxo.clone(), entry.clone())], &[1; 32]);
+
+        // value, creation height and coinbase flag are committed
+        let mut other = entry.clone();
+        other.tx_out.value = 7.0;
+        assert!(commit(&[(utxo.clone(), other)], &[1; 32]) != same);
+        let mut other = entry.clone();
+        other.height = 2;
+        assert!(commit(&[(utxo.clone(), other)], &[1; 32]) != same);
+        let mut other = entry.clone();
+        other.coinbase = true;
+        assert!(commit(&[(utxo, other)], &[1; 32]) != same);
+    }
+}
@@ -0,0 +1,191 @@
+use block::Block;
+use block::BlockHeader;
+use codec::put_u32;
+use codec::put_u64;
+use codec::Decode;
+use codec::Encode;
+use codec::Reader;
+use openssl::sha::sha256;
+use std::collections::HashMap;
+use transaction::Transaction;
+use transaction_pool::TransactionPool;
+
+/// Bytes of transaction short ID
+const SHORT_ID_SIZE: usize = 6;
+
+pub type ShortId = [u8; SHORT_ID_SIZE];
+
+/// Block announced by header, coinbase and short IDs of other transactions.
+/// Receiver takes transactions from its pool and requests only missing ones.
+/// Short IDs are salted with block hash and random nonce, so collisions
+/// can not be prepared for every block in advance.
+#[derive(Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub coinbase: Transaction,
+    pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let mut compact = CompactBlock {
+            header: block.header(),
+            nonce,
+            coinbase: block.coinbase().clone(),
+            short_ids: Vec::new(),
+        };
+        compact.short_ids = block.txs().iter().map(|tx| compact.short_id(&tx.hash())).collect();
+        compact
+    }
+
+    pub fn short_id(&self, tx_hash: &[u8; 32]) -> ShortId {
+        let mut data = self.header.hash.to_vec();
+        put_u64(&mut data, self.nonce);
+        data.extend(tx_hash.iter());
+        let hash = sha256(&data);
+        let mut short_id = [0; SHORT_ID_SIZE];
+        short_id.copy_from_slice(&hash[..SHORT_ID_SIZE]);
+        short_id
+    }
+
+    /// Start block reconstruction with transactions of pool
+    pub fn reconstruct(&self, tx_pool: &TransactionPool) -> PartialBlock {
+        let mut txs: Vec<Option<Transaction>> = vec![None; self.short_ids.len()];
+        let positions: HashMap<ShortId, usize> = self.short_ids
+            .iter()
+            .enumerate()
+            .map(|(index, short_id)| (*short_id, index))
+            .collect();
+
+        for tx in tx_pool.iter() {
+            if let Some(&index) = positions.get(&self.short_id(&tx.hash())) {
+                txs[index] = Some(tx.clone());
+            }
+        }
+        PartialBlock {
+            compact: self.clone(),
+            txs,
+        }
+    }
+}
+
+/// Block being reconstructed from compact block, transactions are None until found
+pub struct PartialBlock {
+    compact: CompactBlock,
+    txs: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.compact.header.hash
+    }
+
+    /// Positions of missing transactions, not counting coinbase
+    pub fn missing(&self) -> Vec<u32> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|&(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Put transactions at missing positions in order.
+    /// Return false if number of transactions does not match.
+    pub fn fill(&mut self, txs: Vec<Transaction>) -> bool {
+        if txs.len() != self.missing().len() {
+            return false;
+        }
+        let mut txs = txs.into_iter();
+
+        for slot in self.txs.iter_mut().filter(|slot| slot.is_none()) {
+            *slot = txs.next();
+        }
+        true
+    }
+
+    /// Complete block, None if transactions are missing or they do not
+    /// match header, after short ID collision. Full block is requested then.
+    pub fn finish(self) -> Option<Block> {
+        let mut txs = Vec::new();
+
+        for tx in self.txs {
+            txs.push(tx?);
+        }
+        let header = self.compact.header;
+        let mut block = Block::from_parts(header.prev_hash, self.compact.coinbase, txs);
+        block.set_nonce(header.nonce);
+
+        if *block.hash() != header.hash {
+            return None;
+        }
+        Some(block)
+    }
+}
+
+impl Encode for CompactBlock {
+    fn encode(&self, data: &mut Vec<u8>) {
+        self.header.encode(data);
+        put_u64(data, self.nonce);
+        self.coinbase.encode(data);
+        put_u32(data, self.short_ids.len() as u32);
+
+        for short_id in &self.short_ids {
+            data.extend(short_id.iter());
+        }
+    }
+}
+
+impl Decode for CompactBlock {
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let header = BlockHeader::decode(reader)?;
+        let nonce = reader.u64()?;
+        let coinbase = Transaction::decode(reader)?;
+        let mut short_ids = Vec::new();
+
+        for _ in 0..reader.count(SHORT_ID_SIZE)? {
+            let mut short_id = [0; SHORT_ID_SIZE];
+            short_id.copy_from_slice(reader.bytes(SHORT_ID_SIZE)?);
+            short_ids.push(short_id);
+        }
+        Some(CompactBlock {
+            header,
+            nonce,
+            coinbase,
+            short_ids,
+        })
+    }
+}
+
+#[cfg(test)]
+mod compact_tests {
+    use super::*;
+    use testutil::Funds;
+
+    #[test]
+    fn reconstruct_test() {
+        let funds = Funds::new(3);
+        let txs: Vec<Transaction> = (0..3)
+            .map(|index| funds.key.spend(&[Funds::coin(index)], &[(9.0, b"bob")]))
+            .collect();
+        let mut tx_pool = TransactionPool::new();
+        assert!(funds.add(&mut tx_pool, &txs[0]));
+        assert!(funds.add(&mut tx_pool, &txs[2]));
+        let coinbase = Transaction::new_coinbase(28.0, b"miner".to_vec(), 1);
+        let block = Block::from_parts([1; 32], coinbase, txs.clone());
+
+        let compact = CompactBlock::new(&block, 7);
+        let compact = CompactBlock::from_bytes(&compact.to_bytes()).unwrap();
+        let mut partial = compact.reconstruct(&tx_pool);
+        assert_eq!(vec![1], partial.missing());
+        assert!(!partial.fill(Vec::new()));
+        assert!(partial.fill(vec![txs[1].clone()]));
+        assert_eq!(block.hash(), partial.finish().unwrap().hash());
+
+        // wrong transaction, as after short ID collision, does not make block
+        let mut partial = compact.reconstruct(&tx_pool);
+        assert!(partial.fill(vec![txs[0].clone()]));
+        assert!(partial.finish().is_none());
+    }
+}
@@ -0,0 +1,137 @@
+use codec::invalid_data;
+use json_dump::Json;
+use params::ChainParams;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+
+static DEFAULT_DATA_DIR: &str = "blockchain-data";
+static DEFAULT_RPC_PORT: u16 = 8332;
+static DEFAULT_GENESIS_ADDRESS: &[u8] = b"genesis";
+/// Default pause in seconds between blocks mined by node
+static DEFAULT_MINE_INTERVAL: u64 = 10;
+
+/// Settings of blockchain node. Nodes of one network should use
+/// the same genesis address, it defines genesis block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeConfig {
+    /// Directory of blocks, UTXO set and saved mempool
+    pub data_dir: PathBuf,
+    /// Port of RPC server on loopback address, 0 for any free port
+    pub rpc_port: u16,
+    pub genesis_address: Vec<u8>,
+    /// Number of blocks on top of coinbase before its outputs can be spent
+    pub coinbase_maturity: usize,
+    /// Number of leading zero bits of block hash, proof-of-work target
+    pub pow_bits: u32,
+    /// Address of mined blocks reward, node does not mine without it
+    pub mine_address: Option<Vec<u8>>,
+    /// Pause in seconds between mined blocks
+    pub mine_interval: u64,
+    /// Number of last blocks whose bodies are kept in memory, if pruning is enabled
+    pub prune_depth: Option<usize>,
+    /// Address P2P layer listens on for peers, node is not networked without it
+    pub p2p_listen: Option<SocketAddr>,
+    /// Peers node connects to on start
+    pub peers: Vec<SocketAddr>,
+    /// Keep index of transactions by address for `getaddresshistory` requests
+    pub address_index: bool,
+}
+
+impl NodeConfig {
+    pub fn new() -> Self {
+        NodeConfig {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            rpc_port: DEFAULT_RPC_PORT,
+            genesis_address: DEFAULT_GENESIS_ADDRESS.to_vec(),
+            coinbase_maturity: ChainParams::new().coinbase_maturity,
+            pow_bits: ChainParams::new().pow_bits,
+            mine_address: None,
+            mine_interval: DEFAULT_MINE_INTERVAL,
+            prune_depth: None,
+            p2p_listen: None,
+            peers: Vec::new(),
+            address_index: false,
+        }
+    }
+
+    /// Load config from JSON file. Every field is optional, addresses are
+    /// hex strings of address bytes, for example:
+    /// `{"data_dir": "node1", "rpc_port": 18443, "mine_address": "6d696e6572",
+    /// "p2p_listen": "127.0.0.1:18444", "peers": ["127.0.0.1:18445"]}`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        NodeConfig::from_json(&Json::parse(&text)?).ok_or_else(|| invalid_data("Invalid config"))
+    }
+
+    pub fn from_json(json: &Json) -> Option<Self> {
+        let mut config = NodeConfig::new();
+
+        if let Json::Object(ref fields) = *json {
+            for &(ref name, ref value) in fields {
+                match name.as_str() {
+                    "data_dir" => config.data_dir = PathBuf::from(value.as_str()?),
+                    "rpc_port" => {
+                        let port = value.as_usize()?;
+
+                        if port > u16::max_value() as usize {
+                            return None;
+                        }
+                        config.rpc_port = port as u16;
+                    }
+                    "genesis_address" => config.genesis_address = value.as_hex()?,
+                    "coinbase_maturity" => config.coinbase_maturity = value.as_usize()?,
+                    "pow_bits" => {
+                        let bits = value.as_usize()?;
+
+                        if bits > 256 {
+                            return None;
+                        }
+                        config.pow_bits = bits as u32;
+                    }
+                    "mine_address" => config.mine_address = Some(value.as_hex()?),
+                    "mine_interval" => config.mine_interval = value.as_usize()? as u64,
+                    "prune_depth" => config.prune_depth = Some(value.as_usize()?),
+                    "p2p_listen" => config.p2p_listen = Some(value.as_str()?.parse().ok()?),
+                    "peers" => {
+                        for peer in value.as_array()? {
+                            config.peers.push(peer.as_str()?.parse().ok()?);
+                        }
+                    }
+                    "address_index" => config.address_index = value.as_bool()?,
+                    _ => return None,
+                }
+            }
+            return Some(config);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[test]
+    fn from_json_test() {
+        let text = r#"{"data_dir": "node1", "rpc_port": 0, "mine_address": "6d696e6572",
+            "peers": ["127.0.0.1:18444"], "address_index": true}"#;
+        let config = NodeConfig::from_json(&Json::parse(text).unwrap()).unwrap();
+        assert_eq!(PathBuf::from("node1"), config.data_dir);
+        assert_eq!(0, config.rpc_port);
+        assert_eq!(Some(b"miner".to_vec()), config.mine_address);
+        assert_eq!(NodeConfig::new().genesis_address, config.genesis_address);
+        assert_eq!(vec!["127.0.0.1:18444".parse::<SocketAddr>().unwrap()], config.peers);
+        assert_eq!(None, config.p2p_listen);
+        assert!(config.address_index);
+
+        // unknown field is a typo, it is not ignored
+        assert!(NodeConfig::from_json(&Json::parse(r#"{"rpc_prot": 1}"#).unwrap()).is_none());
+        assert!(NodeConfig::from_json(&Json::parse(r#"{"rpc_port": 70000}"#).unwrap()).is_none());
+        assert!(NodeConfig::from_json(&Json::parse(r#"{"peers": ["a:1"]}"#).unwrap()).is_none());
+    }
+}
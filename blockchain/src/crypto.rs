@@ -1,14 +1,27 @@
 use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
 use openssl::sha::sha256;
+use openssl::sign::Signer;
 use openssl::sign::Verifier;
 
 
-pub fn verify_signature(pub_key: &Vec<u8>, msg: &[u8; 32], sig: &[u8; 32]) -> bool {
-    let pub_key = PKey::public_key_from_pem(pub_key).unwrap();
+/// Check RSA signature of message, malformed key or signature never verifies
+pub fn verify_signature(pub_key: &Vec<u8>, msg: &[u8; 32], sig: &[u8]) -> bool {
+    let pub_key = match PKey::public_key_from_pem(pub_key) {
+        Ok(pub_key) => pub_key,
+        Err(_) => return false,
+    };
     let mut verifier = Verifier::new(MessageDigest::sha256(), &pub_key).unwrap();
     verifier.update(msg).unwrap();
-    verifier.finish(sig).unwrap()
+    verifier.verify(sig).unwrap_or(false)
+}
+
+/// Sign message with RSA private key in PEM format
+pub fn sign(private_key: &[u8], msg: &[u8; 32]) -> Option<Vec<u8>> {
+    let private_key = PKey::private_key_from_pem(private_key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &private_key).ok()?;
+    signer.update(msg).ok()?;
+    signer.sign_to_vec().ok()
 }
 
 pub fn double_sha256(data: &Vec<u8>) -> [u8; 32] {
@@ -0,0 +1,45 @@
+use block::Block;
+use std::cell::RefCell;
+use std::rc::Rc;
+use transaction::Transaction;
+use transaction_pool::RemovalReason;
+use undo::BlockUndo;
+
+/// Change of active chain, the chain which ends with highest tip, or of
+/// transaction pool. After accepted block listeners get blocks which left
+/// active chain from tip down, then blocks which joined it from bottom up,
+/// each followed by pool transactions it confirmed or conflicted with, and
+/// `TipChanged` last. Listeners get every event in order of subscription.
+pub enum ChainEvent<'a> {
+    /// Block became part of active chain, undo holds outputs it spent
+    BlockConnected {
+        block: &'a Block,
+        height: usize,
+        undo: &'a BlockUndo,
+    },
+    /// Block left active chain on reorg
+    BlockDisconnected {
+        block: &'a Block,
+        height: usize,
+        undo: &'a BlockUndo,
+    },
+    /// Active chain has new tip
+    TipChanged { block: &'a Block, height: usize },
+    TxAddedToPool { tx: &'a Transaction },
+    TxRemovedFromPool {
+        tx: &'a Transaction,
+        reason: RemovalReason,
+    },
+}
+
+/// Subscriber of chain events
+pub trait ChainListener {
+    fn on_event(&mut self, event: &ChainEvent);
+}
+
+/// Shared listener, so subscriber can be queried while chain owns it
+impl<T: ChainListener> ChainListener for Rc<RefCell<T>> {
+    fn on_event(&mut self, event: &ChainEvent) {
+        self.borrow_mut().on_event(event);
+    }
+}
@@ -1,12 +1,36 @@
 #![feature(const_size_of)]
+extern crate json_dump;
 extern crate openssl;
 extern crate time;
 
-mod block;
-mod blockchain;
+mod address_index;
+pub mod block;
+mod block_assembler;
+mod block_store;
+pub mod blockchain;
 mod blockhandler;
+mod codec;
+mod commitment;
+mod compact;
+pub mod config;
 mod crypto;
-mod transaction;
-mod transaction_pool;
+pub mod events;
+mod mempool;
+mod message;
+pub mod node;
+mod p2p;
+pub mod params;
+mod rpc;
+pub mod selfish;
+pub mod simulator;
+mod snapshot;
+mod sync;
+#[cfg(test)]
+mod testutil;
+pub mod transaction;
+pub mod transaction_pool;
 mod txhandler;
+mod undo;
 mod utxo;
+mod utxo_store;
+pub mod wallet;
@@ -0,0 +1,89 @@
+use codec::checksum;
+use codec::invalid_data;
+use codec::put_u32;
+use codec::Decode;
+use codec::Encode;
+use codec::Reader;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use transaction::Transaction;
+use transaction_pool::TransactionPool;
+
+/// Marks beginning of mempool file
+static MEMPOOL_MAGIC: [u8; 4] = [0x6d, 0x65, 0x6d, 0x70];
+/// Mempool file header: magic, payload length and payload checksum
+const HEADER_SIZE: usize = 12;
+
+/// Write pooled transactions to file, parents before transactions spending
+/// their outputs, so they can be added to pool again in file order.
+/// File is replaced atomically.
+pub fn save(tx_pool: &TransactionPool, path: &Path) -> io::Result<()> {
+    let mut txs: Vec<(usize, &Transaction)> = tx_pool
+        .iter()
+        .map(|tx| (tx_pool.ancestors(&tx.hash()).len(), tx))
+        .collect();
+    txs.sort_by_key(|&(ancestors, _)| ancestors);
+    let mut payload = Vec::new();
+    put_u32(&mut payload, txs.len() as u32);
+
+    for (_, tx) in txs {
+        let data = tx.to_bytes();
+        put_u32(&mut payload, data.len() as u32);
+        payload.extend(data);
+    }
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&MEMPOOL_MAGIC)?;
+        let mut header = Vec::new();
+        put_u32(&mut header, payload.len() as u32);
+        header.extend(checksum(&payload).iter());
+        file.write_all(&header)?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+/// Read transactions written by `save`, no transactions if file does not exist
+pub fn load(path: &Path) -> io::Result<Vec<Transaction>> {
+    let mut data = Vec::new();
+
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    if data.len() < HEADER_SIZE || data[..4] != MEMPOOL_MAGIC {
+        return Err(invalid_data("Invalid mempool magic"));
+    }
+    let len = Reader::new(&data[4..8]).u32().unwrap() as usize;
+
+    if data.len() != HEADER_SIZE + len {
+        return Err(invalid_data("Invalid mempool length"));
+    }
+    let payload = &data[HEADER_SIZE..];
+
+    if checksum(payload) != data[8..HEADER_SIZE] {
+        return Err(invalid_data("Mempool checksum mismatch"));
+    }
+    decode(payload).ok_or_else(|| invalid_data("Invalid mempool"))
+}
+
+fn decode(payload: &[u8]) -> Option<Vec<Transaction>> {
+    let mut reader = Reader::new(payload);
+    let mut txs = Vec::new();
+
+    for _ in 0..reader.u32()? {
+        let len = reader.u32()? as usize;
+        txs.push(Transaction::from_bytes(reader.bytes(len)?)?);
+    }
+    if !reader.is_empty() {
+        return None;
+    }
+    Some(txs)
+}
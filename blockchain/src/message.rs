@@ -0,0 +1,390 @@
+use block::Block;
+use block::BlockHeader;
+use codec::checksum;
+use codec::invalid_data;
+use codec::put_u16;
+use codec::put_u32;
+use codec::put_u64;
+use codec::Decode;
+use codec::Encode;
+use codec::Reader;
+use compact::CompactBlock;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use transaction::Transaction;
+use transaction::MIN_TX_SIZE;
+
+/// Marks beginning of every message of network
+static NETWORK_MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+/// Message header: magic, command, payload length and payload checksum
+const HEADER_SIZE: usize = 24;
+/// Command name is ASCII padded with zero bytes
+const COMMAND_SIZE: usize = 12;
+/// Largest accepted message payload
+static MAX_PAYLOAD_SIZE: usize = 32_000_000;
+pub static PROTOCOL_VERSION: u32 = 3;
+/// Oldest version of peer node talks to, older peers do not know `getheaders`
+pub static MIN_PROTOCOL_VERSION: u32 = 2;
+/// Oldest version of peer which gets new blocks as `cmpctblock`
+pub static COMPACT_BLOCKS_VERSION: u32 = 3;
+/// Most items in one `inv` or `getdata` message
+pub static MAX_INV_SIZE: usize = 50_000;
+/// Most addresses in one `addr` message
+pub static MAX_ADDR_SIZE: usize = 1000;
+/// Most hashes in block locator
+pub static MAX_LOCATOR_SIZE: usize = 101;
+/// Most headers in one `headers` message
+pub static MAX_HEADERS_SIZE: usize = 2000;
+/// Size of encoded block header
+const BLOCK_HEADER_SIZE: usize = 104;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum InvKind {
+    Tx,
+    Block,
+}
+
+/// Transaction or block announced or requested by hash
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Inventory {
+    pub kind: InvKind,
+    pub hash: [u8; 32],
+}
+
+/// First message of connection, each side sends its own
+#[derive(Clone, PartialEq, Debug)]
+pub struct Version {
+    pub version: u32,
+    /// Height of sender active chain
+    pub height: u32,
+    /// Random number of sender, equal nonces mean connection to itself
+    pub nonce: u64,
+    /// Port sender accepts connections on
+    pub listen_port: u16,
+}
+
+/// Message of P2P protocol
+#[derive(Clone)]
+pub enum Message {
+    Version(Version),
+    /// Acknowledges received version
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    /// Announces transactions and blocks sender has
+    Inv(Vec<Inventory>),
+    /// Requests announced transactions and blocks
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    /// Requests `headers` of active chain blocks following first known locator hash
+    GetHeaders(Vec<[u8; 32]>),
+    /// Headers of consecutive blocks, parents before children
+    Headers(Vec<BlockHeader>),
+    /// Requests known peer addresses
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    /// Announces new block without transactions receiver likely has
+    CmpctBlock(CompactBlock),
+    /// Requests transactions of compact block by positions, not counting coinbase
+    GetBlockTxn([u8; 32], Vec<u32>),
+    /// Transactions of block requested by `getblocktxn`, in requested order
+    BlockTxn([u8; 32], Vec<Transaction>),
+}
+
+impl Message {
+    pub fn command(&self) -> &'static str {
+        match *self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetAddr => "getaddr",
+            Message::Addr(_) => "addr",
+            Message::CmpctBlock(_) => "cmpctblock",
+            Message::GetBlockTxn(..) => "getblocktxn",
+            Message::BlockTxn(..) => "blocktxn",
+        }
+    }
+
+    /// Message with header, ready to be sent
+    pub fn frame(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload);
+        let mut data = NETWORK_MAGIC.to_vec();
+        let mut command = [0; COMMAND_SIZE];
+        command[..self.command().len()].copy_from_slice(self.command().as_bytes());
+        data.extend(command.iter());
+        put_u32(&mut data, payload.len() as u32);
+        data.extend(checksum(&payload).iter());
+        data.extend(payload);
+        data
+    }
+
+    /// Take first message from received data. Return None if message
+    /// is not complete yet and error if data breaks protocol.
+    pub fn read_frame(data: &mut Vec<u8>) -> io::Result<Option<Message>> {
+        if data.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        if data[..4] != NETWORK_MAGIC {
+            return Err(invalid_data("Invalid network magic"));
+        }
+        let len = Reader::new(&data[16..20]).u32().unwrap() as usize;
+
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(invalid_data("Message is too large"));
+        }
+        if data.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = data.drain(..HEADER_SIZE + len).collect();
+        let payload = &frame[HEADER_SIZE..];
+
+        if checksum(payload) != frame[20..HEADER_SIZE] {
+            return Err(invalid_data("Message checksum mismatch"));
+        }
+        let command = &frame[4..4 + COMMAND_SIZE];
+        let name_len = command.iter().position(|&byte| byte == 0).unwrap_or(COMMAND_SIZE);
+
+        if command[name_len..].iter().any(|&byte| byte != 0) {
+            return Err(invalid_data("Invalid message command"));
+        }
+        let name = String::from_utf8_lossy(&command[..name_len]).into_owned();
+        let mut reader = Reader::new(payload);
+        match Self::decode_payload(&name, &mut reader) {
+            Some(message) if reader.is_empty() => Ok(Some(message)),
+            _ => Err(invalid_data("Invalid message payload")),
+        }
+    }
+
+    fn encode_payload(&self, data: &mut Vec<u8>) {
+        match *self {
+            Message::Version(ref version) => {
+                put_u32(data, version.version);
+                put_u32(data, version.height);
+                put_u64(data, version.nonce);
+                put_u16(data, version.listen_port);
+            }
+            Message::Verack | Message::GetAddr => {}
+            Message::Ping(nonce) | Message::Pong(nonce) => put_u64(data, nonce),
+            Message::Inv(ref items) | Message::GetData(ref items) => {
+                put_u32(data, items.len() as u32);
+
+                for item in items {
+                    data.push(item.kind as u8);
+                    data.extend(item.hash.iter());
+                }
+            }
+            Message::Block(ref block) => block.encode(data),
+            Message::Tx(ref tx) => tx.encode(data),
+            Message::GetHeaders(ref locator) => {
+                put_u32(data, locator.len() as u32);
+
+                for hash in locator {
+                    data.extend(hash.iter());
+                }
+            }
+            Message::Headers(ref headers) => {
+                put_u32(data, headers.len() as u32);
+
+                for header in headers {
+                    header.encode(data);
+                }
+            }
+            Message::Addr(ref addrs) => {
+                put_u32(data, addrs.len() as u32);
+
+                for addr in addrs {
+                    let ip = match addr.ip() {
+                        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                        IpAddr::V6(ip) => ip,
+                    };
+                    data.extend(ip.octets().iter());
+                    put_u16(data, addr.port());
+                }
+            }
+            Message::CmpctBlock(ref compact) => compact.encode(data),
+            Message::GetBlockTxn(ref hash, ref indexes) => {
+                data.extend(hash.iter());
+                put_u32(data, indexes.len() as u32);
+
+                for &index in indexes {
+                    put_u32(data, index);
+                }
+            }
+            Message::BlockTxn(ref hash, ref txs) => {
+                data.extend(hash.iter());
+                put_u32(data, txs.len() as u32);
+
+                for tx in txs {
+                    tx.encode(data);
+                }
+            }
+        }
+    }
+
+    fn decode_payload(command: &str, reader: &mut Reader) -> Option<Message> {
+        let message = match command {
+            "version" => Message::Version(Version {
+                version: reader.u32()?,
+                height: reader.u32()?,
+                nonce: reader.u64()?,
+                listen_port: reader.u16()?,
+            }),
+            "verack" => Message::Verack,
+            "ping" => Message::Ping(reader.u64()?),
+            "pong" => Message::Pong(reader.u64()?),
+            "inv" => Message::Inv(Self::decode_inv(reader)?),
+            "getdata" => Message::GetData(Self::decode_inv(reader)?),
+            "block" => Message::Block(Block::decode(reader)?),
+            "tx" => Message::Tx(Transaction::decode(reader)?),
+            "getheaders" => {
+                let count = reader.count(32)?;
+
+                if count > MAX_LOCATOR_SIZE {
+                    return None;
+                }
+                let mut locator = Vec::new();
+
+                for _ in 0..count {
+                    locator.push(reader.hash()?);
+                }
+                Message::GetHeaders(locator)
+            }
+            "headers" => {
+                let count = reader.count(BLOCK_HEADER_SIZE)?;
+
+                if count > MAX_HEADERS_SIZE {
+                    return None;
+                }
+                let mut headers = Vec::new();
+
+                for _ in 0..count {
+                    headers.push(BlockHeader::decode(reader)?);
+                }
+                Message::Headers(headers)
+            }
+            "getaddr" => Message::GetAddr,
+            "addr" => {
+                let count = reader.count(18)?;
+
+                if count > MAX_ADDR_SIZE {
+                    return None;
+                }
+                let mut addrs = Vec::new();
+
+                for _ in 0..count {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(reader.bytes(16)?);
+                    let ip = Ipv6Addr::from(octets);
+                    let ip = match ip.to_ipv4() {
+                        Some(ipv4) if ipv4.to_ipv6_mapped() == ip => IpAddr::V4(ipv4),
+                        _ => IpAddr::V6(ip),
+                    };
+                    addrs.push(SocketAddr::new(ip, reader.u16()?));
+                }
+                Message::Addr(addrs)
+            }
+            "cmpctblock" => Message::CmpctBlock(CompactBlock::decode(reader)?),
+            "getblocktxn" => {
+                let hash = reader.hash()?;
+                let mut indexes = Vec::new();
+
+                for _ in 0..reader.count(4)? {
+                    indexes.push(reader.u32()?);
+                }
+                Message::GetBlockTxn(hash, indexes)
+            }
+            "blocktxn" => {
+                let hash = reader.hash()?;
+                let mut txs = Vec::new();
+
+                for _ in 0..reader.count(MIN_TX_SIZE)? {
+                    txs.push(Transaction::decode(reader)?);
+                }
+                Message::BlockTxn(hash, txs)
+            }
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    fn decode_inv(reader: &mut Reader) -> Option<Vec<Inventory>> {
+        let count = reader.count(33)?;
+
+        if count > MAX_INV_SIZE {
+            return None;
+        }
+        let mut items = Vec::new();
+
+        for _ in 0..count {
+            let kind = match reader.u8()? {
+                0 => InvKind::Tx,
+                1 => InvKind::Block,
+                _ => return None,
+            };
+            items.push(Inventory {
+                kind,
+                hash: reader.hash()?,
+            });
+        }
+        Some(items)
+    }
+}
+
+#[cfg(test)]
+mod message_tests {
+    use super::*;
+
+    #[test]
+    fn frame_test() {
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            height: 7,
+            nonce: 42,
+            listen_port: 8333,
+        };
+        let addrs = vec![
+            "127.0.0.1:8333".parse().unwrap(),
+            "[::1]:8334".parse().unwrap(),
+        ];
+        let mut data = Message::Version(version.clone()).frame();
+        data.extend(Message::Addr(addrs.clone()).frame());
+        let ping = Message::Ping(5).frame();
+        data.extend(ping[..HEADER_SIZE + 1].iter());
+
+        match Message::read_frame(&mut data).unwrap() {
+            Some(Message::Version(decoded)) => assert_eq!(version, decoded),
+            _ => panic!("Version expected"),
+        }
+        match Message::read_frame(&mut data).unwrap() {
+            Some(Message::Addr(decoded)) => assert_eq!(addrs, decoded),
+            _ => panic!("Addr expected"),
+        }
+        // incomplete message waits for the rest of data
+        assert!(Message::read_frame(&mut data).unwrap().is_none());
+        data.extend(ping[HEADER_SIZE + 1..].iter());
+        match Message::read_frame(&mut data).unwrap() {
+            Some(Message::Ping(5)) => {}
+            _ => panic!("Ping expected"),
+        }
+        assert!(data.is_empty());
+
+        let mut damaged = Message::Pong(5).frame();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        assert!(Message::read_frame(&mut damaged).is_err());
+        let mut unknown = Message::Verack.frame();
+        unknown[4] = b'x';
+        assert!(Message::read_frame(&mut unknown).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use block::Block;
+use blockchain::Blockchain;
+use blockhandler::BlockHandler;
+use config::NodeConfig;
+use mempool;
+use p2p::PeerManager;
+use params::ChainParams;
+use rpc::RpcServer;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration as StdDuration;
+use time;
+use time::Duration;
+use time::Timespec;
+
+/// Saved mempool inside data directory
+static MEMPOOL_FILE: &str = "mempool.dat";
+/// Pause between polls of idle node
+static POLL_INTERVAL_MS: u64 = 10;
+
+/// Blockchain node: persistent chain, RPC server on loopback address,
+/// optional P2P layer and optional miner. Node stops on RPC `stop` request or stop flag.
+pub struct Node {
+    config: NodeConfig,
+    handler: BlockHandler,
+    rpc: RpcServer,
+    /// Connections to peers, if node listens for them
+    peers: Option<PeerManager>,
+    /// Time of last mined block or node start
+    last_mined: Timespec,
+}
+
+impl Node {
+    /// Open chain in data directory or initialise it with genesis block,
+    /// add saved mempool transactions again and start RPC server
+    pub fn open(config: NodeConfig) -> io::Result<Self> {
+        let genesis = Node::genesis_block(&config.genesis_address);
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = config.coinbase_maturity;
+        params.pow_bits = config.pow_bits;
+        let mut blockchain = Blockchain::open(&config.data_dir, genesis, params)?;
+
+        if let Some(depth) = config.prune_depth {
+            blockchain.enable_pruning(depth);
+        }
+        let mut handler = BlockHandler::new(blockchain);
+
+        if config.address_index {
+            handler.enable_address_index().map_err(|err| {
+                io::Error::new(io::ErrorKind::Other, format!("Address index: {:?}", err))
+            })?;
+        }
+
+        // transactions which became invalid while node was stopped are dropped
+        for tx in mempool::load(&config.data_dir.join(MEMPOOL_FILE))? {
+            handler.precess_tx(tx);
+        }
+        let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let rpc = RpcServer::bind(SocketAddr::new(loopback, config.rpc_port))?;
+        let peers = match config.p2p_listen {
+            Some(addr) => {
+                let mut peers = PeerManager::bind(addr, &mut handler)?;
+
+                // unreachable peer stays in address book and is tried again later
+                for &peer in config.peers.iter() {
+                    let _ = peers.connect(peer, &handler);
+                }
+                Some(peers)
+            }
+            None => None,
+        };
+
+        Ok(Node {
+            config,
+            handler,
+            rpc,
+            peers,
+            last_mined: time::get_time(),
+        })
+    }
+
+    pub fn rpc_addr(&self) -> io::Result<SocketAddr> {
+        self.rpc.local_addr()
+    }
+
+    /// Address of P2P listener, if node listens for peers
+    pub fn p2p_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.peers.as_ref().map(|peers| peers.local_addr())
+    }
+
+    /// Flag which stops node, the same as RPC `stop` request
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.rpc.stop_flag()
+    }
+
+    /// Serve RPC requests, exchange messages with peers and mine blocks until node is stopped,
+    /// then save mempool and pending UTXO set changes
+    pub fn run(&mut self) -> io::Result<()> {
+        while !self.rpc.is_stopped() {
+            let served = self.rpc.poll(&mut self.handler)?;
+            let received = match self.peers {
+                Some(ref mut peers) => peers.poll(&mut self.handler)?,
+                None => false,
+            };
+
+            if !self.mine() && !served && !received {
+                self.handler.blockchain_mut().expire_txs(time::get_time());
+                thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        let path = self.config.data_dir.join(MEMPOOL_FILE);
+        mempool::save(self.handler.blockchain().tx_pool(), &path)?;
+        self.handler.blockchain_mut().flush()
+    }
+
+    /// Mine block if mining is enabled and mine interval passed.
+    /// Return true if block was mined.
+    fn mine(&mut self) -> bool {
+        let address = match self.config.mine_address {
+            Some(ref address) => address.clone(),
+            None => return false,
+        };
+        let now = time::get_time();
+
+        if now - self.last_mined < Duration::seconds(self.config.mine_interval as i64) {
+            return false;
+        }
+        self.last_mined = now;
+        self.handler.create_block(address).is_ok()
+    }
+
+    /// Genesis block of network, equal for equal genesis addresses
+    fn genesis_block(address: &[u8]) -> Block {
+        let subsidy = ChainParams::new().subsidy(0);
+        let mut genesis = Block::new([0; 32], address.to_vec(), subsidy, 0);
+        genesis.finalize();
+        genesis
+    }
+}
+
+#[cfg(test)]
+mod node_tests {
+    use super::*;
+    use codec::Encode;
+    use json_dump::to_hex;
+    use json_dump::Json;
+    use std::env;
+    use std::fs;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::thread::JoinHandle;
+    use testutil::TestKey;
+    use utxo::UTXO;
+
+    fn start(config: NodeConfig) -> (SocketAddr, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut node = Node::open(config).unwrap();
+            sender.send(node.rpc_addr().unwrap()).unwrap();
+            node.run().unwrap();
+        });
+        (receiver.recv().unwrap(), thread)
+    }
+
+    fn call(addr: SocketAddr, method: &str, params: Vec<Json>) -> Json {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = Json::object(vec![
+            ("jsonrpc", Json::String("2.0".to_string())),
+            ("method", Json::String(method.to_string())),
+            ("params", Json::Array(params)),
+            ("id", Json::Number(1.0)),
+        ]);
+        stream.write_all(request.compact().as_bytes()).unwrap();
+        stream.write_all(b"\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        Json::parse(&line).unwrap().get("result").unwrap().clone()
+    }
+
+    #[test]
+    fn restart_test() {
+        let key = TestKey::new(0);
+        let mut config = NodeConfig::new();
+        config.data_dir = env::temp_dir().join("node_restart");
+        let _ = fs::remove_dir_all(&config.data_dir);
+        config.rpc_port = 0;
+        config.genesis_address = key.address.clone();
+        config.coinbase_maturity = 1;
+
+        let (addr, node) = start(config.clone());
+        let miner = Json::String(to_hex(b"miner"));
+        call(addr, "generate", vec![Json::Number(1.0), miner]);
+        let genesis = Node::genesis_block(&config.genesis_address);
+        let tx = key.spend(&[UTXO::new(genesis.coinbase().hash(), 0)], &[(24.0, b"bob")]);
+        call(addr, "sendrawtransaction", vec![Json::hex(&tx.to_bytes())]);
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+
+        // chain and mempool are restored
+        let (addr, node) = start(config.clone());
+        assert_eq!(Some(1), call(addr, "getblockcount", Vec::new()).as_usize());
+        let info = call(addr, "getmempoolinfo", Vec::new());
+        assert_eq!(Some(1), info.get("size").unwrap().as_usize());
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+
+        // miner includes pooled transaction
+        config.mine_address = Some(b"node miner".to_vec());
+        config.mine_interval = 0;
+        let (addr, node) = start(config);
+
+        while call(addr, "getblockcount", Vec::new()).as_usize() < Some(3) {
+            thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+        }
+        let info = call(addr, "getmempoolinfo", Vec::new());
+        assert_eq!(Some(0), info.get("size").unwrap().as_usize());
+        let balance = call(addr, "getbalance", vec![Json::String(to_hex(b"bob"))]);
+        assert_eq!(Some(24.0), balance.as_f64());
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn mining_test() {
+        let mut config = NodeConfig::new();
+        config.data_dir = env::temp_dir().join("node_mining");
+        let _ = fs::remove_dir_all(&config.data_dir);
+        config.rpc_port = 0;
+        config.mine_address = Some(b"node miner".to_vec());
+        config.mine_interval = 0;
+
+        let (addr, node) = start(config.clone());
+
+        while call(addr, "getblockcount", Vec::new()).as_usize() < Some(3) {
+            thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+        }
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+
+        // every mined block pays subsidy to miner address
+        config.mine_address = None;
+        let (addr, node) = start(config);
+        let count = call(addr, "getblockcount", Vec::new()).as_usize().unwrap();
+        let params = ChainParams::new();
+        let reward: f64 = (1..count + 1).map(|height| params.subsidy(height)).sum();
+        let miner = Json::String(to_hex(b"node miner"));
+        assert_eq!(Some(reward), call(addr, "getbalance", vec![miner]).as_f64());
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn address_index_test() {
+        let mut config = NodeConfig::new();
+        config.data_dir = env::temp_dir().join("node_address_index");
+        let _ = fs::remove_dir_all(&config.data_dir);
+        config.rpc_port = 0;
+        config.address_index = true;
+
+        let (addr, node) = start(config.clone());
+        let miner = Json::String(to_hex(b"miner"));
+        let count = ChainParams::new().cut_off_age + 2;
+        let hashes = call(addr, "generate", vec![Json::Number(count as f64), miner.clone()]);
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+
+        // index is rebuilt on start, pruned blocks are read from disk
+        let (addr, node) = start(config);
+        let page = vec![miner, Json::Number(1.0), Json::Number(5.0)];
+        let history = call(addr, "getaddresshistory", page);
+        assert_eq!(Some(count), history.get("count").unwrap().as_usize());
+        let records = history.get("records").unwrap().as_array().unwrap();
+        assert_eq!(5, records.len());
+        assert_eq!(Some(count - 5), records[0].get("height").unwrap().as_usize());
+        assert_eq!(Some("received"), records[0].get("direction").unwrap().as_str());
+
+        let hash = hashes.as_array().unwrap()[0].clone();
+        let block = call(addr, "getblock", vec![hash.clone()]);
+        assert_eq!(Some(&hash), block.get("hash"));
+        assert_eq!(Some(1), block.get("height").unwrap().as_usize());
+        call(addr, "stop", Vec::new());
+        node.join().unwrap();
+    }
+}
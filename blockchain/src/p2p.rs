@@ -0,0 +1,978 @@
+use blockchain::Blockchain;
+use blockchain::QueryError;
+use block::Block;
+use blockhandler::BlockHandler;
+use compact::CompactBlock;
+use compact::PartialBlock;
+use events::ChainEvent;
+use events::ChainListener;
+use message::Inventory;
+use message::InvKind;
+use message::Message;
+use message::Version;
+use message::COMPACT_BLOCKS_VERSION;
+use message::MAX_ADDR_SIZE;
+use message::MAX_HEADERS_SIZE;
+use message::MIN_PROTOCOL_VERSION;
+use message::PROTOCOL_VERSION;
+use openssl::rand::rand_bytes;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::Duration as StdDuration;
+use sync::Misbehavior;
+use sync::SyncManager;
+use time;
+use time::Duration;
+use time::Timespec;
+
+/// Number of connections node opens to peers from address book
+static MAX_OUTBOUND: usize = 8;
+/// Most connections accepted from peers
+static MAX_INBOUND: usize = 32;
+/// Most addresses kept in address book
+static MAX_BOOK_SIZE: usize = 1000;
+/// Most remembered inventory items known to peer
+static MAX_KNOWN_INVENTORY: usize = 50_000;
+/// Most not yet sent bytes kept for peer, slower peer is disconnected
+static MAX_SEND_BUFFER: usize = 64_000_000;
+/// Seconds peer has to complete handshake
+static HANDSHAKE_TIMEOUT: i64 = 60;
+/// Seconds between pings of peer
+static PING_INTERVAL: i64 = 120;
+/// Seconds peer has to answer ping
+static PING_TIMEOUT: i64 = 1200;
+/// Seconds before failed address is tried again
+static RETRY_INTERVAL: i64 = 60;
+/// Milliseconds given to outbound connection to open
+static CONNECT_TIMEOUT_MS: u64 = 1000;
+/// Hours misbehaving peer is not connected to
+static BAN_HOURS: i64 = 24;
+
+/// Listen addresses of known peers
+pub struct AddressBook {
+    /// Address with time of last connection attempt, in order of learning
+    addresses: Vec<(SocketAddr, Option<Timespec>)>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook {
+            addresses: Vec::new(),
+        }
+    }
+
+    /// Remember address, return false if it is known or book is full
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if addr.port() == 0 || self.addresses.len() >= MAX_BOOK_SIZE || self.contains(&addr) {
+            return false;
+        }
+        self.addresses.push((addr, None));
+        true
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.addresses.retain(|&(ref known, _)| known != addr);
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.addresses.iter().any(|&(ref known, _)| known == addr)
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.addresses.iter().map(|&(addr, _)| addr).collect()
+    }
+
+    /// Address not tried recently and not in `exclude`, it is marked as tried
+    fn next_to_try(&mut self, now: Timespec, exclude: &[SocketAddr]) -> Option<SocketAddr> {
+        let retry_interval = Duration::seconds(RETRY_INTERVAL);
+        let entry = self.addresses.iter_mut().find(|&&mut (ref addr, last_try)| {
+            !exclude.contains(addr) && last_try.map_or(true, |last| now - last >= retry_interval)
+        })?;
+        entry.1 = Some(now);
+        Some(entry.0)
+    }
+}
+
+/// Inventory of chain and pool changes waiting to be announced to peers
+struct RelayQueue {
+    items: Vec<Inventory>,
+}
+
+impl ChainListener for RelayQueue {
+    fn on_event(&mut self, event: &ChainEvent) {
+        match *event {
+            // only new tip is announced, peers request missing parents
+            ChainEvent::TipChanged { block, .. } => self.items.push(Inventory {
+                kind: InvKind::Block,
+                hash: block.hash().clone(),
+            }),
+            ChainEvent::TxAddedToPool { tx } => self.items.push(Inventory {
+                kind: InvKind::Tx,
+                hash: tx.hash(),
+            }),
+            _ => {}
+        }
+    }
+}
+
+struct Peer {
+    /// Number of connection, unique for peer manager
+    id: u64,
+    stream: TcpStream,
+    addr: SocketAddr,
+    inbound: bool,
+    /// Received data with not yet complete message
+    read_buf: Vec<u8>,
+    /// Messages not yet taken by peer
+    write_buf: Vec<u8>,
+    /// Version received from peer
+    version: Option<Version>,
+    /// Our version is acknowledged
+    verack: bool,
+    connected_at: Timespec,
+    last_ping: Timespec,
+    /// Nonce of ping waiting for pong
+    ping_nonce: Option<u64>,
+    /// Inventory peer has or was told about
+    known: HashSet<Inventory>,
+    /// Compact block of peer waiting for missing transactions
+    partial: Option<PartialBlock>,
+    disconnect: bool,
+}
+
+impl Peer {
+    fn new(id: u64, stream: TcpStream, addr: SocketAddr, inbound: bool) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        let now = time::get_time();
+        Ok(Peer {
+            id,
+            stream,
+            addr,
+            inbound,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            version: None,
+            verack: false,
+            connected_at: now,
+            last_ping: now,
+            ping_nonce: None,
+            known: HashSet::new(),
+            partial: None,
+            disconnect: false,
+        })
+    }
+
+    fn send(&mut self, message: &Message) {
+        self.write_buf.extend(message.frame());
+
+        if self.write_buf.len() > MAX_SEND_BUFFER {
+            self.disconnect = true;
+        }
+    }
+
+    /// Handshake is complete in both directions
+    fn is_ready(&self) -> bool {
+        self.version.is_some() && self.verack
+    }
+
+    /// Peer gets new blocks as compact blocks
+    fn wants_compact(&self) -> bool {
+        self.version
+            .as_ref()
+            .map_or(false, |version| version.version >= COMPACT_BLOCKS_VERSION)
+    }
+
+    /// Address peer accepts connections on, if it is known
+    fn listen_addr(&self) -> Option<SocketAddr> {
+        if !self.inbound {
+            return Some(self.addr);
+        }
+        match self.version {
+            Some(ref version) if version.listen_port != 0 => {
+                Some(SocketAddr::new(self.addr.ip(), version.listen_port))
+            }
+            _ => None,
+        }
+    }
+
+    fn add_known(&mut self, item: Inventory) {
+        if self.known.len() >= MAX_KNOWN_INVENTORY {
+            self.known.clear();
+        }
+        self.known.insert(item);
+    }
+
+    /// Read available data and return complete messages
+    fn receive(&mut self) -> Vec<Message> {
+        let mut data = [0; 65536];
+
+        loop {
+            match self.stream.read(&mut data) {
+                Ok(0) => {
+                    self.disconnect = true;
+                    break;
+                }
+                Ok(len) => self.read_buf.extend(data[..len].iter()),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.disconnect = true;
+                    break;
+                }
+            }
+        }
+        let mut messages = Vec::new();
+
+        loop {
+            match Message::read_frame(&mut self.read_buf) {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(_) => {
+                    self.disconnect = true;
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    /// Write as much of pending data as peer takes
+    fn flush(&mut self) {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => {
+                    self.disconnect = true;
+                    break;
+                }
+                Ok(len) => {
+                    self.write_buf.drain(..len);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.disconnect = true;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// P2P layer of node: keeps connections to peers, relays blocks and
+/// transactions and feeds received ones into block handler.
+/// Chain changes are announced to peers through chain events.
+/// Missing blocks are downloaded headers-first by sync manager.
+pub struct PeerManager {
+    listener: TcpListener,
+    listen_port: u16,
+    /// Random number sent in version, detects connections to itself
+    nonce: u64,
+    peers: Vec<Peer>,
+    /// Id of next connection
+    next_id: u64,
+    book: AddressBook,
+    /// Listen addresses of misbehaving peers with end of their ban
+    banned: HashMap<SocketAddr, Timespec>,
+    sync: SyncManager,
+    relay: Rc<RefCell<RelayQueue>>,
+}
+
+impl PeerManager {
+    /// Listen for peers on address and subscribe to chain of handler
+    pub fn bind(addr: SocketAddr, handler: &mut BlockHandler) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let listen_port = listener.local_addr()?.port();
+        let nonce = random_u64()?;
+        let relay = Rc::new(RefCell::new(RelayQueue { items: Vec::new() }));
+        handler
+            .blockchain_mut()
+            .subscribe(Box::new(relay.clone()))
+            .map_err(|err| io::Error::new(ErrorKind::Other, format!("{:?}", err)))?;
+        // current state is not announced, peers learn it with `getheaders`
+        relay.borrow_mut().items.clear();
+
+        Ok(PeerManager {
+            listener,
+            listen_port,
+            nonce,
+            peers: Vec::new(),
+            next_id: 0,
+            book: AddressBook::new(),
+            banned: HashMap::new(),
+            sync: SyncManager::new(),
+            relay,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Peer with listen address misbehaved recently
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.banned.get(addr).map_or(false, |&until| time::get_time() < until)
+    }
+
+    /// Add address to address book and connect to it
+    pub fn connect(&mut self, addr: SocketAddr, handler: &BlockHandler) -> io::Result<()> {
+        if self.is_banned(&addr) {
+            return Err(io::Error::new(ErrorKind::Other, "Peer is banned"));
+        }
+        self.book.add(addr);
+        let timeout = StdDuration::from_millis(CONNECT_TIMEOUT_MS);
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        self.add_peer(stream, addr, false, handler)
+    }
+
+    /// Accept and open connections, handle received messages, announce chain
+    /// changes and send pings without waiting. Return true if any message
+    /// was received.
+    pub fn poll(&mut self, handler: &mut BlockHandler) -> io::Result<bool> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    // connection which can not be set up is dropped like outbound one
+                    if self.peers.iter().filter(|peer| peer.inbound).count() < MAX_INBOUND {
+                        let _ = self.add_peer(stream, addr, true, handler);
+                    }
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.connect_outbound(handler);
+        let mut received = false;
+
+        for index in 0..self.peers.len() {
+            for message in self.peers[index].receive() {
+                received = true;
+
+                if self.handle(index, message, handler).is_err() {
+                    let id = self.peers[index].id;
+                    self.ban(id);
+                }
+            }
+        }
+        for id in self.sync.connect_blocks(handler)? {
+            self.ban(id);
+        }
+        self.request_blocks();
+        self.relay(handler);
+        self.check_timeouts();
+
+        for peer in self.peers.iter_mut() {
+            peer.flush();
+        }
+        for peer in self.peers.iter().filter(|peer| peer.disconnect) {
+            self.sync.remove_peer(peer.id);
+        }
+        self.peers.retain(|peer| !peer.disconnect);
+        Ok(received)
+    }
+
+    /// Start handshake with connected peer, outbound side sends version first
+    fn add_peer(
+        &mut self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        inbound: bool,
+        handler: &BlockHandler,
+    ) -> io::Result<()> {
+        let mut peer = Peer::new(self.next_id, stream, addr, inbound)?;
+        self.next_id += 1;
+
+        if !inbound {
+            peer.send(&self.version(handler));
+        }
+        self.peers.push(peer);
+        Ok(())
+    }
+
+    /// Add block received from peer to chain, through sync manager if block
+    /// is part of header chain
+    fn receive_block(
+        sync: &mut SyncManager,
+        peer: &mut Peer,
+        block: Block,
+        handler: &mut BlockHandler,
+    ) {
+        let prev_hash = block.prev_hash().clone();
+
+        if let Some(block) = sync.on_block(peer.id, block) {
+            handler.process_block(block);
+
+            // parent is missing, peer is asked for headers after common block
+            if !knows_block(handler.blockchain(), &prev_hash) {
+                peer.send(&Message::GetHeaders(sync.locator(handler.blockchain())));
+            }
+        }
+    }
+
+    /// Add reconstructed compact block to chain, full block is requested
+    /// if transactions do not match block header
+    fn finish_compact(
+        sync: &mut SyncManager,
+        peer: &mut Peer,
+        partial: PartialBlock,
+        handler: &mut BlockHandler,
+    ) {
+        let hash = partial.hash().clone();
+
+        match partial.finish() {
+            Some(block) => Self::receive_block(sync, peer, block, handler),
+            None => Self::request_block(peer, hash),
+        }
+    }
+
+    fn request_block(peer: &mut Peer, hash: [u8; 32]) {
+        let item = Inventory {
+            kind: InvKind::Block,
+            hash,
+        };
+        peer.send(&Message::GetData(vec![item]));
+    }
+
+    /// Disconnect peer and refuse its listen address for a while
+    fn ban(&mut self, id: u64) {
+        let until = time::get_time() + Duration::hours(BAN_HOURS);
+
+        if let Some(peer) = self.peers.iter_mut().find(|peer| peer.id == id) {
+            peer.disconnect = true;
+
+            if let Some(addr) = peer.listen_addr() {
+                self.book.remove(&addr);
+                self.banned.insert(addr, until);
+            }
+        }
+    }
+
+    /// Ask ready peers for blocks of header chain
+    fn request_blocks(&mut self) {
+        let now = time::get_time();
+
+        for peer in self.peers.iter_mut().filter(|peer| peer.is_ready()) {
+            let hashes = self.sync.request_blocks(peer.id, now);
+
+            if !hashes.is_empty() {
+                let items = hashes
+                    .into_iter()
+                    .map(|hash| Inventory {
+                        kind: InvKind::Block,
+                        hash,
+                    })
+                    .collect();
+                peer.send(&Message::GetData(items));
+            }
+        }
+    }
+
+    fn version(&self, handler: &BlockHandler) -> Message {
+        Message::Version(Version {
+            version: PROTOCOL_VERSION,
+            height: handler.blockchain().get_max_height() as u32,
+            nonce: self.nonce,
+            listen_port: self.listen_port,
+        })
+    }
+
+    /// Open connection to address from book if there are too few outbound peers
+    fn connect_outbound(&mut self, handler: &BlockHandler) {
+        if self.peers.iter().filter(|peer| !peer.inbound).count() >= MAX_OUTBOUND {
+            return;
+        }
+        let now = time::get_time();
+        let exclude: Vec<SocketAddr> = self.peers
+            .iter()
+            .filter_map(|peer| peer.listen_addr())
+            .chain(self.banned.iter().filter(|&(_, &until)| now < until).map(|(&addr, _)| addr))
+            .collect();
+
+        if let Some(addr) = self.book.next_to_try(now, &exclude) {
+            let timeout = StdDuration::from_millis(CONNECT_TIMEOUT_MS);
+
+            if let Ok(stream) = TcpStream::connect_timeout(&addr, timeout) {
+                let _ = self.add_peer(stream, addr, false, handler);
+            }
+        }
+    }
+
+    /// Handle message of peer, return error if peer sent invalid data
+    fn handle(
+        &mut self,
+        index: usize,
+        message: Message,
+        handler: &mut BlockHandler,
+    ) -> Result<(), Misbehavior> {
+        if self.peers[index].disconnect {
+            return Ok(());
+        }
+        let version = self.version(handler);
+        let peer = &mut self.peers[index];
+
+        match message {
+            Message::Version(peer_version) => {
+                if peer.version.is_some() || peer_version.version < MIN_PROTOCOL_VERSION {
+                    peer.disconnect = true;
+                    return Ok(());
+                }
+                let listen_addr = SocketAddr::new(peer.addr.ip(), peer_version.listen_port);
+
+                if peer_version.nonce == self.nonce {
+                    // connected to itself
+                    self.book.remove(&listen_addr);
+                    peer.disconnect = true;
+                    return Ok(());
+                }
+                if self.banned.get(&listen_addr).map_or(false, |&until| time::get_time() < until) {
+                    peer.disconnect = true;
+                    return Ok(());
+                }
+                let height = peer_version.height as usize;
+                peer.version = Some(peer_version);
+
+                if peer.inbound {
+                    peer.send(&version);
+                    self.book.add(listen_addr);
+                } else {
+                    peer.send(&Message::GetAddr);
+                }
+                peer.send(&Message::Verack);
+                self.sync.add_peer(peer.id, height);
+
+                if height > self.sync.best_height(handler.blockchain()) {
+                    peer.send(&Message::GetHeaders(self.sync.locator(handler.blockchain())));
+                }
+            }
+            Message::Verack => peer.verack = true,
+            // nothing but handshake is accepted before handshake
+            _ if peer.version.is_none() => peer.disconnect = true,
+            Message::Ping(nonce) => peer.send(&Message::Pong(nonce)),
+            Message::Pong(nonce) => {
+                if peer.ping_nonce == Some(nonce) {
+                    peer.ping_nonce = None;
+                }
+            }
+            Message::Inv(items) => {
+                let blockchain = handler.blockchain();
+                let mut wanted = Vec::new();
+
+                for item in items {
+                    peer.add_known(item);
+                    let known = match item.kind {
+                        InvKind::Block => knows_block(blockchain, &item.hash),
+                        InvKind::Tx => {
+                            blockchain.tx_pool().contains(&item.hash)
+                                || blockchain.tx_by_hash(&item.hash).is_ok()
+                        }
+                    };
+                    if !known {
+                        wanted.push(item);
+                    }
+                }
+                if !wanted.is_empty() {
+                    peer.send(&Message::GetData(wanted));
+                }
+            }
+            Message::GetData(items) => {
+                let blockchain = handler.blockchain();
+
+                for item in items {
+                    let message = match item.kind {
+                        InvKind::Block => match blockchain.block_by_hash(&item.hash) {
+                            Ok(block) => Some(Message::Block(block.clone())),
+                            // pruned block is read from disk
+                            Err(QueryError::Pruned) => blockchain
+                                .store()
+                                .and_then(|store| store.read_block(&item.hash).ok())
+                                .and_then(|block| block)
+                                .map(Message::Block),
+                            Err(_) => None,
+                        },
+                        InvKind::Tx => {
+                            blockchain.tx_pool().get_tx(&item.hash).cloned().map(Message::Tx)
+                        }
+                    };
+                    if let Some(message) = message {
+                        peer.add_known(item);
+                        peer.send(&message);
+                    }
+                }
+            }
+            Message::Block(block) => {
+                peer.add_known(Inventory {
+                    kind: InvKind::Block,
+                    hash: block.hash().clone(),
+                });
+                Self::receive_block(&mut self.sync, peer, block, handler);
+            }
+            Message::CmpctBlock(compact) => {
+                let hash = compact.header.hash;
+                peer.add_known(Inventory {
+                    kind: InvKind::Block,
+                    hash,
+                });
+                let blockchain = handler.blockchain();
+
+                if knows_block(blockchain, &hash) {
+                    return Ok(());
+                }
+                if !blockchain.params().check_pow(&hash) {
+                    return Err(Misbehavior::InsufficientWork);
+                }
+                if !knows_block(blockchain, &compact.header.prev_hash) {
+                    peer.send(&Message::GetHeaders(self.sync.locator(blockchain)));
+                    return Ok(());
+                }
+                let partial = compact.reconstruct(blockchain.tx_pool());
+                let missing = partial.missing();
+
+                if missing.is_empty() {
+                    Self::finish_compact(&mut self.sync, peer, partial, handler);
+                } else {
+                    peer.send(&Message::GetBlockTxn(hash, missing));
+                    peer.partial = Some(partial);
+                }
+            }
+            Message::GetBlockTxn(hash, indexes) => {
+                if let Ok(block) = handler.blockchain().block_by_hash(&hash) {
+                    let mut txs = Vec::new();
+
+                    for index in indexes {
+                        match block.txs().get(index as usize) {
+                            Some(tx) => txs.push(tx.clone()),
+                            None => return Err(Misbehavior::InvalidTxIndex),
+                        }
+                    }
+                    peer.send(&Message::BlockTxn(hash, txs));
+                }
+            }
+            Message::BlockTxn(hash, txs) => {
+                if let Some(mut partial) = peer.partial.take() {
+                    if *partial.hash() != hash {
+                        peer.partial = Some(partial);
+                    } else if partial.fill(txs) {
+                        Self::finish_compact(&mut self.sync, peer, partial, handler);
+                    } else {
+                        Self::request_block(peer, hash);
+                    }
+                }
+            }
+            Message::Tx(tx) => {
+                peer.add_known(Inventory {
+                    kind: InvKind::Tx,
+                    hash: tx.hash(),
+                });
+                handler.precess_tx(tx);
+            }
+            Message::GetHeaders(locator) => {
+                let headers = handler.blockchain().headers_after(&locator, MAX_HEADERS_SIZE);
+                peer.send(&Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                // full message means peer has more headers
+                let more = headers.len() == MAX_HEADERS_SIZE;
+                self.sync.on_headers(peer.id, headers, handler.blockchain())?;
+
+                if more {
+                    peer.send(&Message::GetHeaders(self.sync.locator(handler.blockchain())));
+                }
+            }
+            Message::GetAddr => {
+                let mut addrs = self.book.addresses();
+                addrs.truncate(MAX_ADDR_SIZE);
+                peer.send(&Message::Addr(addrs));
+            }
+            Message::Addr(addrs) => for addr in addrs {
+                self.book.add(addr);
+            },
+        }
+        Ok(())
+    }
+
+    /// Announce queued chain changes to ready peers which do not know them.
+    /// New blocks go to peers which support it as compact blocks.
+    fn relay(&mut self, handler: &BlockHandler) {
+        let items: Vec<Inventory> = self.relay.borrow_mut().items.drain(..).collect();
+
+        if items.is_empty() {
+            return;
+        }
+        for peer in self.peers.iter_mut().filter(|peer| peer.is_ready()) {
+            let mut new_items: Vec<Inventory> = items
+                .iter()
+                .filter(|item| !peer.known.contains(item))
+                .cloned()
+                .collect();
+
+            for item in new_items.iter() {
+                peer.add_known(*item);
+            }
+            if peer.wants_compact() {
+                let blockchain = handler.blockchain();
+                let mut compact_blocks = Vec::new();
+                new_items.retain(|item| match item.kind {
+                    InvKind::Block => match blockchain.block_by_hash(&item.hash) {
+                        Ok(block) => {
+                            compact_blocks.push(block);
+                            false
+                        }
+                        Err(_) => true,
+                    },
+                    InvKind::Tx => true,
+                });
+                // transactions are announced before blocks which may include them
+                if !new_items.is_empty() {
+                    peer.send(&Message::Inv(new_items));
+                }
+                for block in compact_blocks {
+                    // salt of short IDs is new for every message
+                    let nonce = random_u64().unwrap_or(self.nonce);
+                    peer.send(&Message::CmpctBlock(CompactBlock::new(block, nonce)));
+                }
+            } else if !new_items.is_empty() {
+                peer.send(&Message::Inv(new_items));
+            }
+        }
+    }
+
+    /// Disconnect peers which do not complete handshake, answer pings or deliver
+    /// requested blocks, ping others
+    fn check_timeouts(&mut self) {
+        let now = time::get_time();
+        let stalled = self.sync.stalled(now);
+
+        for peer in self.peers.iter_mut() {
+            if !peer.is_ready() {
+                if now - peer.connected_at > Duration::seconds(HANDSHAKE_TIMEOUT) {
+                    peer.disconnect = true;
+                }
+                continue;
+            }
+            if stalled.contains(&peer.id) {
+                peer.disconnect = true;
+            }
+            match peer.ping_nonce {
+                Some(_) if now - peer.last_ping > Duration::seconds(PING_TIMEOUT) => {
+                    peer.disconnect = true;
+                }
+                None if now - peer.last_ping > Duration::seconds(PING_INTERVAL) => {
+                    let nonce = now.sec as u64 ^ self.nonce;
+                    peer.ping_nonce = Some(nonce);
+                    peer.last_ping = now;
+                    peer.send(&Message::Ping(nonce));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn random_u64() -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    rand_bytes(&mut bytes).map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    Ok(bytes.iter().fold(0, |value, &byte| value << 8 | byte as u64))
+}
+
+/// Block is in one of branches, with body or pruned
+fn knows_block(blockchain: &Blockchain, hash: &[u8; 32]) -> bool {
+    match blockchain.block_by_hash(hash) {
+        Err(QueryError::NotFound) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod p2p_tests {
+    use super::*;
+    use block::Block;
+    use block::BlockHeader;
+    use params::ChainParams;
+    use std::thread;
+    use testutil::TestKey;
+    use utxo::UTXO;
+
+    struct TestNode {
+        handler: BlockHandler,
+        peers: PeerManager,
+    }
+
+    impl TestNode {
+        fn new(genesis: &Block) -> Self {
+            let mut params = ChainParams::new();
+            params.coinbase_maturity = 1;
+            TestNode::with_params(genesis, params)
+        }
+
+        fn with_params(genesis: &Block, params: ChainParams) -> Self {
+            let mut handler = BlockHandler::new(Blockchain::with_params(genesis.clone(), params));
+            let peers = PeerManager::bind("127.0.0.1:0".parse().unwrap(), &mut handler).unwrap();
+            TestNode { handler, peers }
+        }
+
+        fn connect(&mut self, addr: SocketAddr) {
+            self.peers.connect(addr, &self.handler).unwrap();
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.peers.local_addr().unwrap()
+        }
+
+        fn height(&self) -> usize {
+            self.handler.blockchain().get_max_height()
+        }
+
+        /// Number of peers with complete handshake
+        fn peer_count(&self) -> usize {
+            self.peers.peers.iter().filter(|peer| peer.is_ready()).count()
+        }
+    }
+
+    /// Poll all nodes until condition holds, panic after 10 seconds
+    fn poll_until<F: Fn(&[TestNode]) -> bool>(nodes: &mut [TestNode], condition: F) {
+        let deadline = time::get_time() + Duration::seconds(10);
+
+        while !condition(nodes) {
+            assert!(time::get_time() < deadline, "Nodes did not reach expected state");
+
+            for node in nodes.iter_mut() {
+                node.peers.poll(&mut node.handler).unwrap();
+            }
+            thread::sleep(StdDuration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn relay_test() {
+        let key = TestKey::new(0);
+        let genesis = key.genesis();
+        let mut nodes = vec![
+            TestNode::new(&genesis),
+            TestNode::new(&genesis),
+            TestNode::new(&genesis),
+        ];
+        let addrs: Vec<SocketAddr> = nodes.iter().map(|node| node.addr()).collect();
+
+        // late node downloads blocks mined before connection
+        nodes[0].handler.create_block(b"miner 1".to_vec()).unwrap();
+        nodes[0].handler.create_block(b"miner 2".to_vec()).unwrap();
+        nodes[1].connect(addrs[0]);
+        poll_until(&mut nodes, |nodes| nodes[1].height() == 2);
+        nodes[2].connect(addrs[1]);
+        poll_until(&mut nodes, |nodes| nodes[2].height() == 2);
+
+        // new block travels A -> B -> C
+        let block = nodes[0].handler.create_block(b"miner 3".to_vec()).unwrap();
+        poll_until(&mut nodes, |nodes| {
+            nodes.iter().all(|node| node.handler.blockchain().is_in_active_chain(block.hash()))
+        });
+
+        // transaction travels C -> B -> A and is mined by A
+        let tx = key.spend(&[UTXO::new(genesis.coinbase().hash(), 0)], &[(24.0, b"bob")]);
+        assert!(nodes[2].handler.precess_tx(tx.clone()));
+        poll_until(&mut nodes, |nodes| {
+            nodes[0].handler.blockchain().tx_pool().contains(&tx.hash())
+        });
+        nodes[0].handler.create_block(b"miner 4".to_vec()).unwrap();
+        poll_until(&mut nodes, |nodes| {
+            nodes.iter().all(|node| {
+                node.height() == 4 && node.handler.blockchain().tx_pool().is_empty()
+            })
+        });
+        assert_eq!(1, nodes[2].handler.blockchain().confirmations(&tx.hash()));
+
+        // C learns address of A from B and connects to it, but not to itself
+        assert!(nodes[2].peers.book.contains(&addrs[0]));
+        poll_until(&mut nodes, |nodes| nodes[2].peer_count() == 2);
+        assert!(!nodes[2].peers.book.contains(&addrs[2]));
+    }
+
+    #[test]
+    fn initial_download_test() {
+        let mut genesis = Block::new([0; 32], b"genesis".to_vec(), 25.0, 0);
+        genesis.finalize();
+        let mut params = ChainParams::new();
+        params.pow_bits = 8;
+        let mut nodes = vec![
+            TestNode::with_params(&genesis, params.clone()),
+            TestNode::with_params(&genesis, params.clone()),
+            TestNode::with_params(&genesis, params.clone()),
+        ];
+        let addrs: Vec<SocketAddr> = nodes.iter().map(|node| node.addr()).collect();
+
+        for index in 0..40 {
+            let block = nodes[0].handler.create_block(format!("miner {}", index).into_bytes());
+            assert!(nodes[1].handler.process_block(block.unwrap()));
+        }
+        // fresh node downloads blocks from both peers
+        nodes[2].connect(addrs[0]);
+        nodes[2].connect(addrs[1]);
+        poll_until(&mut nodes, |nodes| nodes[2].height() == 40);
+        let tip = nodes[0].handler.blockchain().get_max_height_block().hash();
+        assert!(nodes[2].handler.blockchain().is_in_active_chain(tip));
+
+        // peer sending header without proof-of-work is banned
+        let mut weak = BlockHeader {
+            hash: [0; 32],
+            prev_hash: genesis.hash().clone(),
+            tx_root: [0; 32],
+            nonce: 0,
+        };
+        weak.hash = weak.compute_hash();
+
+        while params.check_pow(&weak.hash) {
+            weak.nonce += 1;
+            weak.hash = weak.compute_hash();
+        }
+        let mut stream = TcpStream::connect(addrs[2]).unwrap();
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            height: 100,
+            nonce: 1,
+            listen_port: 1,
+        };
+        stream.write_all(&Message::Version(version).frame()).unwrap();
+        stream.write_all(&Message::Verack.frame()).unwrap();
+        stream.write_all(&Message::Headers(vec![weak]).frame()).unwrap();
+        let banned = "127.0.0.1:1".parse().unwrap();
+        poll_until(&mut nodes, |nodes| nodes[2].peers.is_banned(&banned));
+        poll_until(&mut nodes, |nodes| nodes[2].peer_count() == 2);
+    }
+
+    #[test]
+    fn compact_relay_test() {
+        let key = TestKey::new(0);
+        let genesis = key.genesis();
+        let mut nodes = vec![TestNode::new(&genesis), TestNode::new(&genesis)];
+        let addr = nodes[0].addr();
+        nodes[1].connect(addr);
+        poll_until(&mut nodes, |nodes| nodes[1].peer_count() == 1);
+
+        let coin = UTXO::new(genesis.coinbase().hash(), 0);
+        let parent = key.spend(&[coin], &[(24.0, &key.address[..])]);
+        let child = key.spend(&[UTXO::new(parent.hash(), 0)], &[(23.0, b"bob")]);
+        assert!(nodes[0].handler.precess_tx(parent.clone()));
+        poll_until(&mut nodes, |nodes| {
+            nodes[1].handler.blockchain().tx_pool().contains(&parent.hash())
+        });
+
+        // block is rebuilt from pooled parent and child fetched with `getblocktxn`
+        assert!(nodes[0].handler.precess_tx(child.clone()));
+        let block = nodes[0].handler.create_block(b"miner".to_vec()).unwrap();
+        assert_eq!(2, block.txs().len());
+        poll_until(&mut nodes, |nodes| nodes[1].height() == 1);
+        let tip = nodes[1].handler.blockchain().get_max_height_block();
+        assert_eq!(block.hash(), tip.hash());
+        assert!(nodes[1].handler.blockchain().tx_pool().is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+/// Block subsidy before first halving
+static INITIAL_SUBSIDY: f64 = 25.0;
+/// Default number of blocks after which block subsidy is halved
+static DEFAULT_HALVING_INTERVAL: usize = 210_000;
+/// Default number of blocks on top of coinbase before its outputs can be spent
+static DEFAULT_COINBASE_MATURITY: usize = 100;
+/// Default proof-of-work target, any block hash meets it
+static DEFAULT_POW_BITS: u32 = 0;
+/// Default number of last blocks of branch which may start new branch
+static DEFAULT_CUT_OFF_AGE: usize = 10;
+
+/// Consensus parameters of chain
+#[derive(Clone)]
+pub struct ChainParams {
+    /// Number of blocks after which block subsidy is halved, 0 means never
+    pub halving_interval: usize,
+    /// Number of blocks on top of coinbase before its outputs can be spent
+    pub coinbase_maturity: usize,
+    /// Number of leading zero bits every block hash must have, except genesis
+    pub pow_bits: u32,
+    /// Number of last blocks of branch which may start new branch, at least 1.
+    /// Branches falling this far behind highest branch are dropped.
+    pub cut_off_age: usize,
+}
+
+impl ChainParams {
+    pub fn new() -> Self {
+        ChainParams {
+            halving_interval: DEFAULT_HALVING_INTERVAL,
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY,
+            pow_bits: DEFAULT_POW_BITS,
+            cut_off_age: DEFAULT_CUT_OFF_AGE,
+        }
+    }
+
+    /// Coins created by block at height, without transaction fees
+    pub fn subsidy(&self, height: usize) -> f64 {
+        let halvings = height.checked_div(self.halving_interval).unwrap_or(0);
+
+        if halvings >= 64 {
+            return 0.0;
+        }
+        INITIAL_SUBSIDY / (1u64 << halvings) as f64
+    }
+
+    /// Block hash meets proof-of-work target
+    pub fn check_pow(&self, hash: &[u8; 32]) -> bool {
+        let mut zero_bits = 0;
+
+        for &byte in hash.iter() {
+            zero_bits += byte.leading_zeros();
+
+            if byte != 0 {
+                break;
+            }
+        }
+        zero_bits >= self.pow_bits
+    }
+}
+
+#[cfg(test)]
+mod params_tests {
+    use super::*;
+
+    #[test]
+    fn subsidy_test() {
+        let mut params = ChainParams::new();
+        params.halving_interval = 2;
+        assert_eq!(25.0, params.subsidy(1));
+        assert_eq!(12.5, params.subsidy(2));
+        assert_eq!(0.0, params.subsidy(128));
+
+        params.halving_interval = 0;
+        assert_eq!(25.0, params.subsidy(1_000_000));
+    }
+}
@@ -0,0 +1,589 @@
+use address_index::Direction;
+use blockchain::QueryError;
+use blockhandler::BlockHandler;
+use codec::Decode;
+use codec::Encode;
+use json_dump::from_hex;
+use json_dump::to_hex;
+use json_dump::Json;
+use json_dump::ToJson;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use transaction::Transaction;
+
+/// Longest accepted request line, longer requests close connection
+static MAX_REQUEST_SIZE: usize = 4_000_000;
+/// Most response data waiting for client, client taking responses slower is dropped
+static MAX_RESPONSE_BUFFER: usize = 16_000_000;
+/// Most blocks mined by one `generate` request, mining blocks the chain thread
+static MAX_GENERATE_BLOCKS: usize = 100;
+
+/// JSON-RPC 2.0 error codes
+static PARSE_ERROR: i32 = -32700;
+static INVALID_REQUEST: i32 = -32600;
+static METHOD_NOT_FOUND: i32 = -32601;
+static INVALID_PARAMS: i32 = -32602;
+/// Node error codes, the same as in Bitcoin Core
+static MISC_ERROR: i32 = -1;
+static NOT_FOUND: i32 = -5;
+static TX_REJECTED: i32 = -26;
+
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn invalid_params() -> Self {
+        RpcError::new(INVALID_PARAMS, "Invalid params")
+    }
+}
+
+/// Connection of RPC client with not yet complete request line
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    /// Responses not yet taken by client
+    write_buf: Vec<u8>,
+}
+
+/// JSON-RPC 2.0 server of blockchain node. Every request and response is JSON
+/// text in one line, client may send many requests over one connection.
+/// Server has no authentication, so it listens only on loopback addresses.
+pub struct RpcServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    /// Set by `stop` request or by owner of server
+    stop: Arc<AtomicBool>,
+}
+
+impl RpcServer {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        if !addr.ip().is_loopback() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "RPC server listens only on loopback address",
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(RpcServer {
+            listener,
+            clients: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Flag which stops server, it is set by `stop` request
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    /// Accept new connections and answer all received requests without
+    /// waiting for more. Return true if any request was served.
+    pub fn poll(&mut self, handler: &mut BlockHandler) -> io::Result<bool> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.clients.push(Client {
+                        stream,
+                        buffer: Vec::new(),
+                        write_buf: Vec::new(),
+                    });
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let mut served = false;
+        let mut clients = Vec::new();
+
+        for mut client in self.clients.drain(..) {
+            let (open, requests) = Self::read_requests(&mut client);
+
+            if !requests.is_empty() {
+                served = true;
+            }
+            Self::respond(handler, &self.stop, &mut client, requests);
+
+            // connection is dropped if client does not take responses
+            if Self::flush(&mut client) && open && client.write_buf.len() <= MAX_RESPONSE_BUFFER {
+                clients.push(client);
+            }
+        }
+        self.clients = clients;
+        Ok(served)
+    }
+
+    /// Complete request lines received from client and
+    /// false if client closed connection or broke protocol
+    fn read_requests(client: &mut Client) -> (bool, Vec<String>) {
+        let mut open = true;
+        let mut data = [0; 4096];
+
+        loop {
+            match client.stream.read(&mut data) {
+                Ok(0) => {
+                    open = false;
+                    break;
+                }
+                Ok(len) => client.buffer.extend(data[..len].iter()),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    open = false;
+                    break;
+                }
+            }
+        }
+        let mut requests = Vec::new();
+
+        while let Some(end) = client.buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = client.buffer.drain(..end + 1).collect();
+            requests.push(String::from_utf8_lossy(&line[..end]).into_owned());
+        }
+        if client.buffer.len() > MAX_REQUEST_SIZE {
+            open = false;
+        }
+        (open, requests)
+    }
+
+    /// Queue responses to requests, they are written by `flush`
+    fn respond(
+        handler: &mut BlockHandler,
+        stop: &AtomicBool,
+        client: &mut Client,
+        requests: Vec<String>,
+    ) {
+        for request in requests {
+            if request.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = handle_text(handler, stop, &request) {
+                client.write_buf.extend(response.compact().bytes());
+                client.write_buf.push(b'\n');
+            }
+        }
+    }
+
+    /// Write as much of queued responses as client takes,
+    /// false if connection is broken
+    fn flush(client: &mut Client) -> bool {
+        while !client.write_buf.is_empty() {
+            match client.stream.write(&client.write_buf) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    client.write_buf.drain(..len);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Response to request text, None if request is notification.
+/// Batch request gets array of responses. `stop` request sets stop flag.
+pub fn handle_text(handler: &mut BlockHandler, stop: &AtomicBool, text: &str) -> Option<Json> {
+    let request = match Json::parse(text) {
+        Ok(request) => request,
+        Err(_) => {
+            let error = RpcError::new(PARSE_ERROR, "Parse error");
+            return Some(response(Json::Null, Err(error)));
+        }
+    };
+    match request {
+        Json::Array(ref requests) if requests.is_empty() => {
+            let error = RpcError::new(INVALID_REQUEST, "Invalid Request");
+            Some(response(Json::Null, Err(error)))
+        }
+        Json::Array(requests) => {
+            let responses: Vec<Json> = requests
+                .iter()
+                .filter_map(|request| handle_request(handler, stop, request))
+                .collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Json::Array(responses))
+            }
+        }
+        request => handle_request(handler, stop, &request),
+    }
+}
+
+fn handle_request(handler: &mut BlockHandler, stop: &AtomicBool, request: &Json) -> Option<Json> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(|method| method.as_str());
+    let version = request.get("jsonrpc").and_then(|version| version.as_str());
+    let params = match request.get("params") {
+        Some(&Json::Array(ref params)) => Some(params.clone()),
+        None => Some(Vec::new()),
+        Some(_) => None,
+    };
+    let result = match (version, method, params) {
+        (Some("2.0"), Some("stop"), Some(_)) => {
+            stop.store(true, Ordering::SeqCst);
+            Ok(Json::String("Node is stopping".to_string()))
+        }
+        (Some("2.0"), Some(method), Some(params)) => call(handler, method, &params),
+        _ => Err(RpcError::new(INVALID_REQUEST, "Invalid Request")),
+    };
+    // notification, request without id, gets no response
+    id.map(|id| response(id, result))
+}
+
+fn response(id: Json, result: Result<Json, RpcError>) -> Json {
+    let outcome = match result {
+        Ok(result) => ("result", result),
+        Err(error) => (
+            "error",
+            Json::object(vec![
+                ("code", Json::Number(error.code as f64)),
+                ("message", Json::String(error.message)),
+            ]),
+        ),
+    };
+    Json::object(vec![
+        ("jsonrpc", Json::String("2.0".to_string())),
+        outcome,
+        ("id", id),
+    ])
+}
+
+fn call(handler: &mut BlockHandler, method: &str, params: &[Json]) -> Result<Json, RpcError> {
+    match method {
+        "getblockcount" => Ok(Json::Number(handler.blockchain().get_max_height() as f64)),
+        "getbestblockhash" => Ok(Json::hex(handler.blockchain().get_max_height_block().hash())),
+        "getblock" => get_block(handler, params),
+        "getrawtransaction" => get_raw_transaction(handler, params),
+        "sendrawtransaction" => send_raw_transaction(handler, params),
+        "getmempoolinfo" => {
+            let tx_pool = handler.blockchain().tx_pool();
+            Ok(Json::object(vec![
+                ("size", Json::Number(tx_pool.len() as f64)),
+                ("bytes", Json::Number(tx_pool.size() as f64)),
+                ("maxmempool", Json::Number(tx_pool.max_size() as f64)),
+            ]))
+        }
+        "getbalance" => get_balance(handler, params),
+        "getaddresshistory" => get_address_history(handler, params),
+        "generate" => generate(handler, params),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+fn hash_param(params: &[Json], index: usize) -> Result<[u8; 32], RpcError> {
+    params
+        .get(index)
+        .and_then(|param| param.as_hash())
+        .ok_or_else(RpcError::invalid_params)
+}
+
+/// Address is hex of its bytes
+fn address_param(params: &[Json], index: usize) -> Result<Vec<u8>, RpcError> {
+    params
+        .get(index)
+        .and_then(|param| param.as_str())
+        .and_then(from_hex)
+        .ok_or_else(RpcError::invalid_params)
+}
+
+/// Block with given hash from any branch, with height if it is in active chain.
+/// Pruned blocks of persistent chain are read from block store.
+fn get_block(handler: &mut BlockHandler, params: &[Json]) -> Result<Json, RpcError> {
+    let hash = hash_param(params, 0)?;
+    let blockchain = handler.blockchain();
+    let not_available = |err: QueryError| {
+        RpcError::new(NOT_FOUND, &format!("Block is not available: {:?}", err))
+    };
+    let mut json = match blockchain.block_by_hash(&hash) {
+        Ok(block) => block.document(),
+        Err(QueryError::Pruned) => match blockchain.store().map(|store| store.read_block(&hash)) {
+            Some(Ok(Some(block))) => block.document(),
+            Some(Err(err)) => return Err(not_available(QueryError::Io(err.kind()))),
+            _ => return Err(not_available(QueryError::Pruned)),
+        },
+        Err(err) => return Err(not_available(err)),
+    };
+
+    if let Json::Object(ref mut fields) = json {
+        let height = blockchain.height_of(&hash);
+        let height = height.map_or(Json::Null, |height| Json::Number(height as f64));
+        fields.push(("height".to_string(), height));
+    }
+    Ok(json)
+}
+
+/// Hex of transaction from active chain or transaction pool
+fn get_raw_transaction(handler: &mut BlockHandler, params: &[Json]) -> Result<Json, RpcError> {
+    let hash = hash_param(params, 0)?;
+    let blockchain = handler.blockchain();
+
+    if let Some(tx) = blockchain.tx_pool().get_tx(&hash) {
+        return Ok(Json::hex(&tx.to_bytes()));
+    }
+    match blockchain.tx_by_hash(&hash) {
+        Ok((block, 0)) => Ok(Json::hex(&block.coinbase().to_bytes())),
+        Ok((block, position)) => Ok(Json::hex(&block.tx(position - 1).to_bytes())),
+        Err(QueryError::Pruned) => {
+            Err(RpcError::new(NOT_FOUND, "Transaction is not found in kept blocks"))
+        }
+        Err(_) => Err(RpcError::new(NOT_FOUND, "Transaction is not found")),
+    }
+}
+
+/// Add transaction to pool and return its hash
+fn send_raw_transaction(handler: &mut BlockHandler, params: &[Json]) -> Result<Json, RpcError> {
+    let data = params
+        .get(0)
+        .and_then(|param| param.as_hex())
+        .ok_or_else(RpcError::invalid_params)?;
+    let mut tx = Transaction::from_bytes(&data)
+        .ok_or_else(|| RpcError::new(TX_REJECTED, "Transaction decode failed"))?;
+    tx.finalize();
+    let hash = tx.hash();
+
+    if !handler.precess_tx(tx) {
+        return Err(RpcError::new(TX_REJECTED, "Transaction is rejected by pool"));
+    }
+    Ok(Json::hex(&hash))
+}
+
+/// Sum of active chain unspent outputs paid to address
+fn get_balance(handler: &mut BlockHandler, params: &[Json]) -> Result<Json, RpcError> {
+    let address = address_param(params, 0)?;
+    let balance: f64 = handler
+        .blockchain()
+        .get_max_height_utxo_pool()
+        .iter()
+        .filter(|&(_, entry)| entry.tx_out.address == address)
+        .map(|(_, entry)| entry.tx_out.value)
+        .sum();
+    Ok(Json::Number(balance))
+}
+
+/// Page of address records from address index, newest first,
+/// and number of all records of address
+fn get_address_history(handler: &mut BlockHandler, params: &[Json]) -> Result<Json, RpcError> {
+    let address = address_param(params, 0)?;
+    let page = params
+        .get(1)
+        .and_then(|param| param.as_usize())
+        .ok_or_else(RpcError::invalid_params)?;
+    let page_size = params
+        .get(2)
+        .and_then(|param| param.as_usize())
+        .ok_or_else(RpcError::invalid_params)?;
+    let index = handler
+        .address_index()
+        .ok_or_else(|| RpcError::new(MISC_ERROR, "Address index is not enabled"))?;
+    let records = index
+        .history(&address, page, page_size)
+        .iter()
+        .map(|record| {
+            let direction = match record.direction {
+                Direction::Received => "received",
+                Direction::Spent => "spent",
+            };
+            Json::object(vec![
+                ("txid", Json::hex(&record.tx_hash)),
+                ("height", Json::Number(record.height as f64)),
+                ("direction", Json::String(direction.to_string())),
+                ("amount", Json::Number(record.amount)),
+            ])
+        })
+        .collect();
+    Ok(Json::object(vec![
+        ("count", Json::Number(index.history_len(&address) as f64)),
+        ("records", Json::Array(records)),
+    ]))
+}
+
+/// Mine blocks paying reward to address and return their hashes
+fn generate(handler: &mut BlockHandler, params: &[Json]) -> Result<Json, RpcError> {
+    let count = params
+        .get(0)
+        .and_then(|param| param.as_usize())
+        .filter(|&count| count <= MAX_GENERATE_BLOCKS)
+        .ok_or_else(RpcError::invalid_params)?;
+    let address = address_param(params, 1)?;
+    let mut hashes = Vec::new();
+
+    for _ in 0..count {
+        let block = handler.create_block(address.clone()).map_err(|err| {
+            RpcError::new(MISC_ERROR, &format!("Block is rejected: {:?}", err))
+        })?;
+        hashes.push(Json::String(to_hex(block.hash())));
+    }
+    Ok(Json::Array(hashes))
+}
+
+#[cfg(test)]
+mod rpc_tests {
+    use super::*;
+    use block::Block;
+    use blockchain::Blockchain;
+    use params::ChainParams;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::thread;
+    use std::time::Duration;
+    use testutil::TestKey;
+    use utxo::UTXO;
+
+    /// Client which sends request lines and reads response lines
+    struct TestClient {
+        reader: BufReader<TcpStream>,
+        stream: TcpStream,
+    }
+
+    impl TestClient {
+        fn connect(addr: SocketAddr) -> Self {
+            let stream = TcpStream::connect(addr).unwrap();
+            TestClient {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+            }
+        }
+
+        fn send(&mut self, text: &str) -> Json {
+            self.stream.write_all(text.as_bytes()).unwrap();
+            self.stream.write_all(b"\n").unwrap();
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            Json::parse(&line).unwrap()
+        }
+
+        fn call(&mut self, method: &str, params: Vec<Json>) -> Json {
+            let request = Json::object(vec![
+                ("jsonrpc", Json::String("2.0".to_string())),
+                ("method", Json::String(method.to_string())),
+                ("params", Json::Array(params)),
+                ("id", Json::Number(1.0)),
+            ]);
+            let response = self.send(&request.compact());
+            assert_eq!(None, response.get("error"));
+            response.get("result").unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn loopback_test() {
+        let key = TestKey::new(0);
+        let mut genesis = Block::new([1; 32], key.address.clone(), 25.0, 0);
+        genesis.finalize();
+        let coinbase_hash = genesis.coinbase().hash();
+
+        let mut server = RpcServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut params = ChainParams::new();
+            params.coinbase_maturity = 1;
+            let mut handler = BlockHandler::new(Blockchain::with_params(genesis, params));
+
+            while !server.is_stopped() {
+                if !server.poll(&mut handler).unwrap() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+        assert!(RpcServer::bind("0.0.0.0:0".parse().unwrap()).is_err());
+        let mut client = TestClient::connect(addr);
+
+        assert_eq!(Some(0), client.call("getblockcount", Vec::new()).as_usize());
+        let miner = Json::String(to_hex(b"miner"));
+        let hashes = client.call("generate", vec![Json::Number(2.0), miner.clone()]);
+        let hashes = hashes.as_array().unwrap();
+        assert_eq!(2, hashes.len());
+        assert_eq!(Some(50.0), client.call("getbalance", vec![miner]).as_f64());
+        assert_eq!(hashes[1], client.call("getbestblockhash", Vec::new()));
+
+        let block = client.call("getblock", vec![hashes[1].clone()]);
+        assert_eq!(Some(2), block.get("height").unwrap().as_usize());
+        assert_eq!(Some(&hashes[0]), block.get("prev_hash"));
+
+        // signed spend of genesis coinbase enters pool
+        let tx = key.spend(&[UTXO::new(coinbase_hash, 0)], &[(24.0, b"bob")]);
+        let raw = Json::hex(&tx.to_bytes());
+        let txid = client.call("sendrawtransaction", vec![raw.clone()]);
+        assert_eq!(Json::hex(&tx.hash()), txid);
+        let info = client.call("getmempoolinfo", Vec::new());
+        assert_eq!(Some(1), info.get("size").unwrap().as_usize());
+        assert_eq!(raw, client.call("getrawtransaction", vec![txid.clone()]));
+
+        // second client mines pooled transaction
+        let mut other = TestClient::connect(addr);
+        let other_miner = Json::String(to_hex(b"other miner"));
+        other.call("generate", vec![Json::Number(1.0), other_miner.clone()]);
+        assert_eq!(Some(26.0), other.call("getbalance", vec![other_miner]).as_f64());
+        assert_eq!(raw, client.call("getrawtransaction", vec![txid]));
+        let info = client.call("getmempoolinfo", Vec::new());
+        assert_eq!(Some(0), info.get("size").unwrap().as_usize());
+
+        // errors, notification gets no response
+        let response = client.send(concat!(
+            r#"{"jsonrpc":"2.0","method":"getblockcount"}"#,
+            "\n",
+            r#"{"jsonrpc":"2.0","method":"getblockcount","id":7}"#
+        ));
+        assert_eq!(Some(7), response.get("id").unwrap().as_usize());
+        let response = client.send("{");
+        assert_eq!(Some(-32700.0), response.get("error").unwrap().get("code").unwrap().as_f64());
+        let response = client.send(r#"{"jsonrpc":"2.0","method":"unknown","id":"a"}"#);
+        assert_eq!(Some(-32601.0), response.get("error").unwrap().get("code").unwrap().as_f64());
+        assert_eq!(Some("a"), response.get("id").unwrap().as_str());
+        let batch = client.send(
+            r#"[{"jsonrpc":"2.0","method":"getblockcount","id":1},
+            {"jsonrpc":"2.0","method":"getblock","params":["00"],"id":2}]"#
+                .replace('\n', "")
+                .as_str(),
+        );
+        let batch = batch.as_array().unwrap();
+        assert_eq!(Some(3), batch[0].get("result").unwrap().as_usize());
+        assert_eq!(Some(-32602.0), batch[1].get("error").unwrap().get("code").unwrap().as_f64());
+        let request = r#"{"jsonrpc":"2.0","method":"generate","params":[101,"00"],"id":3}"#;
+        let response = client.send(request);
+        assert_eq!(Some(-32602.0), response.get("error").unwrap().get("code").unwrap().as_f64());
+
+        // responses are queued for client which does not read them
+        let mut idle = TestClient::connect(addr);
+        let request = r#"{"jsonrpc":"2.0","method":"getblock","params":[HASH],"id":4}"#;
+        let request = request.replace("HASH", &hashes[1].compact());
+        let requests = format!("{}\n", request).repeat(10000);
+        idle.stream.write_all(requests.as_bytes()).unwrap();
+        assert_eq!(Some(3), client.call("getblockcount", Vec::new()).as_usize());
+        let response = idle.send(&request);
+        assert_eq!(Some(2), response.get("result").unwrap().get("height").unwrap().as_usize());
+
+        // server stops after answering stop request
+        assert!(client.call("stop", Vec::new()).as_str().is_some());
+        server_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use block::Block;
+use json_dump::Json;
+use simulator::SimConfig;
+use simulator::SimReport;
+use simulator::Simulator;
+use std::collections::VecDeque;
+
+/// When selfish miner publishes withheld blocks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReleasePolicy {
+    /// Strategy of Eyal and Sirer: match every honest block with withheld
+    /// block of same height, publish all when lead drops to one block or
+    /// when own block wins a tie
+    Classic,
+    /// Only match honest blocks, never publish more, even with lead of one
+    /// block or in a tie
+    LeadStubborn,
+}
+
+impl ReleasePolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "classic" => Some(ReleasePolicy::Classic),
+            "lead_stubborn" => Some(ReleasePolicy::LeadStubborn),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReleasePolicy::Classic => "classic",
+            ReleasePolicy::LeadStubborn => "lead_stubborn",
+        }
+    }
+}
+
+/// Node of simulated network mining selfishly
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfishConfig {
+    pub node: usize,
+    pub policy: ReleasePolicy,
+    /// Share of honest nodes which get released block before honest block
+    /// of same height
+    pub gamma: f64,
+}
+
+impl SelfishConfig {
+    /// Parse `{"node": 0, "policy": "classic", "gamma": 0.5}`
+    pub fn from_json(json: &Json) -> Option<Self> {
+        let gamma = json.get("gamma")?.as_f64()?;
+
+        if gamma < 0.0 || gamma > 1.0 {
+            return None;
+        }
+        Some(SelfishConfig {
+            node: json.get("node")?.as_usize()?,
+            policy: ReleasePolicy::from_name(json.get("policy")?.as_str()?)?,
+            gamma,
+        })
+    }
+}
+
+/// Withholding state of selfish miner. Miner mines on its own tip, which
+/// stays on its private branch in a tie, as blockchain keeps first seen
+/// branch. Blocks to publish are returned parents first.
+pub struct SelfishMiner {
+    policy: ReleasePolicy,
+    /// Mined blocks not published yet with their heights, parents first
+    withheld: VecDeque<(usize, Block)>,
+    /// Height of best block published by other nodes
+    public_height: usize,
+    public_tip: [u8; 32],
+    /// Height of last block shared by private and public branch
+    fork_height: usize,
+    /// Cut-off age of chain, private branch must stay shallower
+    cut_off_age: usize,
+}
+
+impl SelfishMiner {
+    pub fn new(policy: ReleasePolicy, genesis: &Block, cut_off_age: usize) -> Self {
+        SelfishMiner {
+            policy,
+            withheld: VecDeque::new(),
+            public_height: 0,
+            public_tip: genesis.hash().clone(),
+            fork_height: 0,
+            cut_off_age,
+        }
+    }
+
+    pub fn withheld(&self) -> usize {
+        self.withheld.len()
+    }
+
+    /// Miner found block at height on its tip
+    pub fn on_mined(&mut self, block: Block, height: usize) -> Vec<Block> {
+        if *block.prev_hash() == self.public_tip {
+            self.fork_height = height - 1;
+        }
+        let wins_tie = height == self.public_height + 1 && *block.prev_hash() != self.public_tip;
+        self.withheld.push_back((height, block));
+
+        // honest blocks must still be able to fork from private branch,
+        // blockchain accepts forks only cut-off age blocks deep
+        if (wins_tie && self.policy == ReleasePolicy::Classic)
+            || height - self.fork_height >= self.cut_off_age - 1
+        {
+            return self.release_all();
+        }
+        Vec::new()
+    }
+
+    /// Block published by other node was added at height,
+    /// private tip is at `private_height`
+    pub fn on_public_block(
+        &mut self,
+        hash: &[u8; 32],
+        height: usize,
+        private_height: usize,
+    ) -> Vec<Block> {
+        if height <= self.public_height {
+            return Vec::new();
+        }
+        self.public_height = height;
+        self.public_tip = *hash;
+
+        if private_height < height {
+            // public branch is longer, miner's chain has switched to it
+            self.withheld.clear();
+            return Vec::new();
+        }
+        let release_height = match self.policy {
+            ReleasePolicy::Classic if private_height == height + 1 => private_height,
+            _ => height,
+        };
+        self.release(release_height)
+    }
+
+    /// Publish all withheld blocks, when mining stops
+    pub fn release_all(&mut self) -> Vec<Block> {
+        self.withheld.drain(..).map(|(_, block)| block).collect()
+    }
+
+    fn release(&mut self, max_height: usize) -> Vec<Block> {
+        let mut blocks = Vec::new();
+
+        while self.withheld.front().map_or(false, |&(height, _)| height <= max_height) {
+            blocks.push(self.withheld.pop_front().unwrap().1);
+        }
+        blocks
+    }
+}
+
+/// Outcome of one selfish-mining simulation of sweep
+pub struct SweepPoint {
+    /// Hash power share of selfish miner
+    pub alpha: f64,
+    pub gamma: f64,
+    /// Hash power share of every node, selfish miner is node 0
+    pub hash_shares: Vec<f64>,
+    /// Share of canonical chain blocks of every node
+    pub block_shares: Vec<f64>,
+    pub report: SimReport,
+}
+
+impl SweepPoint {
+    pub fn to_json(&self) -> Json {
+        let shares = |shares: &[f64]| {
+            Json::Array(shares.iter().map(|&share| Json::Number(share)).collect())
+        };
+        Json::object(vec![
+            ("alpha", Json::Number(self.alpha)),
+            ("gamma", Json::Number(self.gamma)),
+            ("hash_shares", shares(&self.hash_shares)),
+            ("block_shares", shares(&self.block_shares)),
+            ("report", self.report.to_json()),
+        ])
+    }
+}
+
+/// Simulate selfish miner with every hash power share `alpha` and every
+/// `gamma`. Node 0 of base config is selfish, other nodes are honest and
+/// share the rest of hash power equally. Other settings come from base.
+pub fn sweep(
+    base: &SimConfig,
+    policy: ReleasePolicy,
+    alphas: &[f64],
+    gammas: &[f64],
+) -> Vec<SweepPoint> {
+    let honest = base.hash_power.len() - 1;
+    let mut points = Vec::new();
+
+    for &alpha in alphas {
+        for &gamma in gammas {
+            let mut config = base.clone();
+            config.hash_power = vec![(1.0 - alpha) / honest as f64; honest + 1];
+            config.hash_power[0] = alpha;
+            config.selfish = Some(SelfishConfig {
+                node: 0,
+                policy,
+                gamma,
+            });
+            let report = Simulator::new(config.clone()).run();
+            points.push(SweepPoint {
+                alpha,
+                gamma,
+                hash_shares: config.hash_shares(),
+                block_shares: report.block_shares(),
+                report,
+            });
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod selfish_tests {
+    use super::*;
+
+    #[test]
+    fn sweep_test() {
+        // with lead of two, honest block makes classic miner publish all
+        let mut genesis = Block::new([0; 32], b"genesis".to_vec(), 25.0, 0);
+        genesis.finalize();
+        let mut first = Block::new(*genesis.hash(), b"first".to_vec(), 25.0, 1);
+        first.finalize();
+        let mut second = Block::new(*first.hash(), b"second".to_vec(), 25.0, 2);
+        second.finalize();
+
+        for &(policy, released) in [(ReleasePolicy::Classic, 2), (ReleasePolicy::LeadStubborn, 1)]
+            .iter()
+        {
+            let mut miner = SelfishMiner::new(policy, &genesis, 10);
+            assert!(miner.on_mined(first.clone(), 1).is_empty());
+            assert!(miner.on_mined(second.clone(), 2).is_empty());
+            assert_eq!(released, miner.on_public_block(&[1; 32], 1, 2).len());
+            assert_eq!(2 - released, miner.withheld());
+        }
+
+        let mut base = SimConfig::new(10);
+        base.seed = 7;
+        base.jitter = 0;
+        base.blocks = 1000;
+
+        let points = sweep(&base, ReleasePolicy::Classic, &[0.1, 0.4], &[0.0, 0.5]);
+        assert_eq!(4, points.len());
+
+        for point in points.iter() {
+            let report = &point.report;
+            assert!((point.alpha - point.hash_shares[0]).abs() < 1e-9);
+            assert_eq!(report.chain_height, report.canonical_blocks.iter().sum());
+            assert!(report.convergence_time.is_some());
+            assert_eq!(0, report.rejected_blocks);
+        }
+        // small miner loses by withholding, large one gains more than fair share,
+        // more with help of honest nodes in ties
+        assert!(points[0].block_shares[0] < 0.1);
+        assert!(points[2].block_shares[0] > 0.4);
+        assert!(points[3].block_shares[0] > points[2].block_shares[0]);
+        assert!(points[3].report.orphan_rate > points[1].report.orphan_rate);
+    }
+}
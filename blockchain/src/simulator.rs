@@ -0,0 +1,698 @@
+use block::Block;
+use blockchain::Blockchain;
+use blockchain::QueryError;
+use blockhandler::BlockHandler;
+use codec::invalid_data;
+use events::ChainEvent;
+use events::ChainListener;
+use json_dump::Json;
+use params::ChainParams;
+use selfish::SelfishConfig;
+use selfish::SelfishMiner;
+use std::cell::RefCell;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+static DEFAULT_SEED: u64 = 1;
+/// Default mean time between blocks of whole network, in milliseconds
+static DEFAULT_BLOCK_INTERVAL: u64 = 600_000;
+/// Default smallest message delay, in milliseconds
+static DEFAULT_DELAY: u64 = 100;
+/// Default largest random addition to message delay, in milliseconds
+static DEFAULT_JITTER: u64 = 400;
+static DEFAULT_BLOCKS: usize = 100;
+
+/// Deterministic pseudo-random numbers (SplitMix64), equal seeds give equal runs
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = self.state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^ (value >> 31)
+    }
+
+    /// Uniform number in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Exponentially distributed number with given mean
+    pub fn exponential(&mut self, mean: f64) -> f64 {
+        -mean * (1.0 - self.next_f64()).ln()
+    }
+}
+
+/// Split of network for a period: nodes of different groups do not hear each other
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    /// Start of partition in milliseconds of simulated time
+    pub start: u64,
+    /// End of partition, exclusive
+    pub end: u64,
+    /// Group of every node
+    pub groups: Vec<usize>,
+}
+
+/// Settings of simulated network
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// Relative hash power of every node, its length is number of nodes
+    pub hash_power: Vec<f64>,
+    /// Mean time between blocks of whole network, in milliseconds
+    pub block_interval: u64,
+    /// Smallest message delay, in milliseconds
+    pub delay: u64,
+    /// Largest random addition to message delay, in milliseconds
+    pub jitter: u64,
+    /// Probability message is lost
+    pub loss: f64,
+    pub partitions: Vec<Partition>,
+    /// Number of blocks mined before mining stops
+    pub blocks: usize,
+    /// Node which withholds blocks, others are honest
+    pub selfish: Option<SelfishConfig>,
+    /// Number of last blocks of branch which may start new branch
+    pub cut_off_age: usize,
+}
+
+impl SimConfig {
+    /// Network of nodes with equal hash power and no losses or partitions
+    pub fn new(nodes: usize) -> Self {
+        SimConfig {
+            seed: DEFAULT_SEED,
+            hash_power: vec![1.0; nodes],
+            block_interval: DEFAULT_BLOCK_INTERVAL,
+            delay: DEFAULT_DELAY,
+            jitter: DEFAULT_JITTER,
+            loss: 0.0,
+            partitions: Vec::new(),
+            blocks: DEFAULT_BLOCKS,
+            selfish: None,
+            cut_off_age: ChainParams::new().cut_off_age,
+        }
+    }
+
+    /// Load config from JSON file. `hash_power` is required, other fields
+    /// are optional, times are milliseconds, for example:
+    /// `{"seed": 7, "hash_power": [3, 1, 1], "loss": 0.01,
+    /// "partitions": [{"start": 0, "end": 3600000, "groups": [0, 0, 1]}],
+    /// "selfish": {"node": 0, "policy": "classic", "gamma": 0.5}, "cut_off_age": 20}`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        SimConfig::from_json(&Json::parse(&text)?)
+            .ok_or_else(|| invalid_data("Invalid simulation config"))
+    }
+
+    pub fn from_json(json: &Json) -> Option<Self> {
+        let hash_power = json.get("hash_power")?
+            .as_array()?
+            .iter()
+            .map(|power| power.as_f64())
+            .collect::<Option<Vec<f64>>>()?;
+        let mut config = SimConfig::new(hash_power.len());
+        config.hash_power = hash_power;
+
+        if let Json::Object(ref fields) = *json {
+            for &(ref name, ref value) in fields {
+                match name.as_str() {
+                    "hash_power" => {}
+                    "seed" => config.seed = value.as_usize()? as u64,
+                    "block_interval" => config.block_interval = value.as_usize()? as u64,
+                    "delay" => config.delay = value.as_usize()? as u64,
+                    "jitter" => config.jitter = value.as_usize()? as u64,
+                    "loss" => config.loss = value.as_f64()?,
+                    "blocks" => config.blocks = value.as_usize()?,
+                    "cut_off_age" => config.cut_off_age = value.as_usize()?,
+                    "partitions" => for partition in value.as_array()? {
+                        config.partitions.push(Partition {
+                            start: partition.get("start")?.as_usize()? as u64,
+                            end: partition.get("end")?.as_usize()? as u64,
+                            groups: partition
+                                .get("groups")?
+                                .as_array()?
+                                .iter()
+                                .map(|group| group.as_usize())
+                                .collect::<Option<Vec<usize>>>()?,
+                        });
+                    },
+                    "selfish" => config.selfish = Some(SelfishConfig::from_json(value)?),
+                    _ => return None,
+                }
+            }
+        }
+        let nodes = config.hash_power.len();
+
+        if nodes == 0
+            || config.cut_off_age == 0
+            || config.hash_power.iter().any(|&power| power < 0.0)
+            || config.hash_power.iter().sum::<f64>() <= 0.0
+            || config.partitions.iter().any(|partition| partition.groups.len() != nodes)
+            || config.selfish.as_ref().map_or(false, |selfish| selfish.node >= nodes)
+        {
+            return None;
+        }
+        Some(config)
+    }
+
+    /// Share of total hash power of every node
+    pub fn hash_shares(&self) -> Vec<f64> {
+        let total: f64 = self.hash_power.iter().sum();
+        self.hash_power.iter().map(|&power| power / total).collect()
+    }
+}
+
+/// Outcome of simulation
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimReport {
+    pub blocks_mined: usize,
+    /// Height of canonical chain, the highest chain at the end of simulation
+    pub chain_height: usize,
+    /// Share of mined blocks which are not in canonical chain
+    pub orphan_rate: f64,
+    /// Most blocks any node disconnected in one tip change
+    pub deepest_reorg: usize,
+    /// Milliseconds from the last mined block until all nodes had the same
+    /// tip, None if nodes did not agree on tip
+    pub convergence_time: Option<u64>,
+    /// Blocks nodes could not add, their parents were too deep in fork
+    pub rejected_blocks: usize,
+    /// Number of canonical chain blocks mined by every node
+    pub canonical_blocks: Vec<usize>,
+}
+
+impl SimReport {
+    pub fn to_json(&self) -> Json {
+        let convergence_time = match self.convergence_time {
+            Some(time) => Json::Number(time as f64),
+            None => Json::Null,
+        };
+        let canonical_blocks = self.canonical_blocks
+            .iter()
+            .map(|&blocks| Json::Number(blocks as f64))
+            .collect();
+        Json::object(vec![
+            ("blocks_mined", Json::Number(self.blocks_mined as f64)),
+            ("chain_height", Json::Number(self.chain_height as f64)),
+            ("orphan_rate", Json::Number(self.orphan_rate)),
+            ("deepest_reorg", Json::Number(self.deepest_reorg as f64)),
+            ("convergence_time", convergence_time),
+            ("rejected_blocks", Json::Number(self.rejected_blocks as f64)),
+            ("canonical_blocks", Json::Array(canonical_blocks)),
+        ])
+    }
+
+    /// Share of canonical chain blocks mined by every node
+    pub fn block_shares(&self) -> Vec<f64> {
+        self.canonical_blocks
+            .iter()
+            .map(|&blocks| blocks as f64 / cmp::max(self.chain_height, 1) as f64)
+            .collect()
+    }
+}
+
+/// Deepest reorganization of node chain, from chain events
+struct ReorgTracker {
+    /// Blocks disconnected in current tip change
+    disconnected: usize,
+    deepest: usize,
+}
+
+impl ChainListener for ReorgTracker {
+    fn on_event(&mut self, event: &ChainEvent) {
+        match *event {
+            ChainEvent::BlockDisconnected { .. } => self.disconnected += 1,
+            ChainEvent::TipChanged { .. } => {
+                if self.disconnected > self.deepest {
+                    self.deepest = self.disconnected;
+                }
+                self.disconnected = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+enum EventKind {
+    /// Some node finds block
+    Mine,
+    /// Block sent by node reaches other node
+    Deliver { from: usize, to: usize, block: Block },
+}
+
+/// Simulation event, events are handled in order of time and then of scheduling
+struct Event {
+    time: u64,
+    seq: u64,
+    kind: EventKind,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Event) -> bool {
+        self.time == other.time && self.seq == other.seq
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Event) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Event {
+    fn cmp(&self, other: &Event) -> Ordering {
+        // earliest event is on top of heap
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+struct SimNode {
+    handler: BlockHandler,
+    reorgs: Rc<RefCell<ReorgTracker>>,
+    /// Simulated time of last tip change
+    last_tip_change: u64,
+}
+
+/// Deterministic network of block handlers in one process. Blocks are found
+/// at random times with exponential intervals, by node chosen by hash power.
+/// Every node relays newly added block to all other nodes. Node which gets
+/// block with unknown parent fetches missing ancestors from sender, fetches
+/// are not lost. Blockchain breaks ties by order blocks were added in, which
+/// follows simulated time, so equal configs give equal reports.
+///
+/// Selfish node, if any, hears blocks of others at once and does not relay
+/// them. Its withheld blocks reach honest nodes chosen with probability
+/// gamma at once, so before competing honest block, and other honest nodes
+/// after largest message delay. Blocks it holds back when mining stops are
+/// published then.
+pub struct Simulator {
+    config: SimConfig,
+    rng: SimRng,
+    /// Simulated time in milliseconds
+    now: u64,
+    /// Number of scheduled events
+    seq: u64,
+    events: BinaryHeap<Event>,
+    nodes: Vec<SimNode>,
+    /// Node which mined every block
+    miners: HashMap<[u8; 32], usize>,
+    /// Height of every mined block
+    heights: HashMap<[u8; 32], usize>,
+    selfish: Option<SelfishMiner>,
+    last_block_time: u64,
+    rejected_blocks: usize,
+    /// Blocks rejected by node, descendants of them are rejected without fetching
+    rejected: HashSet<(usize, [u8; 32])>,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let mut params = ChainParams::new();
+        params.cut_off_age = config.cut_off_age;
+        let mut genesis = Block::new([0; 32], b"genesis".to_vec(), params.subsidy(0), 0);
+        genesis.finalize();
+        let nodes = (0..config.hash_power.len())
+            .map(|_| {
+                let blockchain = Blockchain::with_params(genesis.clone(), params.clone());
+                let mut handler = BlockHandler::new(blockchain);
+                let reorgs = Rc::new(RefCell::new(ReorgTracker {
+                    disconnected: 0,
+                    deepest: 0,
+                }));
+                handler.blockchain_mut().subscribe(Box::new(reorgs.clone())).unwrap();
+                SimNode {
+                    handler,
+                    reorgs,
+                    last_tip_change: 0,
+                }
+            })
+            .collect();
+
+        let selfish = config
+            .selfish
+            .as_ref()
+            .map(|selfish| SelfishMiner::new(selfish.policy, &genesis, params.cut_off_age));
+
+        Simulator {
+            rng: SimRng::new(config.seed),
+            config,
+            now: 0,
+            seq: 0,
+            events: BinaryHeap::new(),
+            nodes,
+            miners: HashMap::new(),
+            heights: HashMap::new(),
+            selfish,
+            last_block_time: 0,
+            rejected_blocks: 0,
+            rejected: HashSet::new(),
+        }
+    }
+
+    /// Mine configured number of blocks, deliver all messages and report results
+    pub fn run(&mut self) -> SimReport {
+        let mut blocks_mined = 0;
+
+        if self.config.blocks > 0 {
+            self.schedule_mining();
+        }
+        while let Some(event) = self.events.pop() {
+            self.now = event.time;
+
+            match event.kind {
+                EventKind::Mine => {
+                    self.mine(blocks_mined);
+                    blocks_mined += 1;
+
+                    if blocks_mined < self.config.blocks {
+                        self.schedule_mining();
+                    } else if let Some(blocks) = self.selfish.as_mut().map(|s| s.release_all()) {
+                        self.release(blocks);
+                    }
+                }
+                EventKind::Deliver { from, to, block } => self.deliver(from, to, block),
+            }
+        }
+        self.report(blocks_mined)
+    }
+
+    pub fn blockchain(&self, node: usize) -> &Blockchain {
+        self.nodes[node].handler.blockchain()
+    }
+
+    /// Node which mined block, None for genesis block
+    pub fn miner(&self, hash: &[u8; 32]) -> Option<usize> {
+        self.miners.get(hash).cloned()
+    }
+
+    fn schedule(&mut self, delay: u64, kind: EventKind) {
+        let event = Event {
+            time: self.now + delay,
+            seq: self.seq,
+            kind,
+        };
+        self.seq += 1;
+        self.events.push(event);
+    }
+
+    fn schedule_mining(&mut self) {
+        let delay = self.rng.exponential(self.config.block_interval as f64);
+        self.schedule(delay.round() as u64, EventKind::Mine);
+    }
+
+    /// Let node chosen by hash power mine block on its tip and send it to others
+    fn mine(&mut self, index: usize) {
+        let total: f64 = self.config.hash_power.iter().sum();
+        let mut target = self.rng.next_f64() * total;
+        let mut miner = 0;
+
+        for (node, &power) in self.config.hash_power.iter().enumerate() {
+            miner = node;
+
+            if target < power {
+                break;
+            }
+            target -= power;
+        }
+        // address is unique, equal coinbases would collide in UTXO set
+        let address = format!("node {} block {}", miner, index).into_bytes();
+        let block = self.nodes[miner]
+            .handler
+            .create_block(address)
+            .expect("Block on own tip is valid");
+        let height = self.blockchain(miner).get_max_height();
+        self.miners.insert(block.hash().clone(), miner);
+        self.heights.insert(block.hash().clone(), height);
+        self.nodes[miner].last_tip_change = self.now;
+        self.last_block_time = self.now;
+
+        if self.is_selfish(miner) {
+            let blocks = self.selfish.as_mut().unwrap().on_mined(block, height);
+            self.release(blocks);
+        } else {
+            self.broadcast(miner, &block);
+        }
+    }
+
+    fn is_selfish(&self, node: usize) -> bool {
+        self.config.selfish.as_ref().map_or(false, |selfish| selfish.node == node)
+    }
+
+    /// Send blocks published by selfish node to honest nodes,
+    /// early to share gamma of them and late to others
+    fn release(&mut self, blocks: Vec<Block>) {
+        if blocks.is_empty() {
+            return;
+        }
+        let (from, gamma) = match self.config.selfish {
+            Some(ref selfish) => (selfish.node, selfish.gamma),
+            None => return,
+        };
+        for to in 0..self.nodes.len() {
+            if to == from {
+                continue;
+            }
+            let delay = if self.rng.next_f64() < gamma {
+                0
+            } else {
+                self.config.delay + self.config.jitter + 1
+            };
+            for block in blocks.iter() {
+                self.send_after(from, to, block.clone(), delay);
+            }
+        }
+    }
+
+    fn broadcast(&mut self, from: usize, block: &Block) {
+        for to in 0..self.nodes.len() {
+            if to != from {
+                self.send(from, to, block.clone());
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, block: Block) {
+        let delay = if self.is_selfish(to) {
+            0
+        } else {
+            self.config.delay + self.rng.next_u64() % (self.config.jitter + 1)
+        };
+        self.send_after(from, to, block, delay);
+    }
+
+    /// Schedule delivery of block unless it is lost or nodes are partitioned
+    fn send_after(&mut self, from: usize, to: usize, block: Block, delay: u64) {
+        let lost = self.rng.next_f64() < self.config.loss;
+        let now = self.now;
+        let partitioned = self.config.partitions.iter().any(|partition| {
+            partition.start <= now && now < partition.end
+                && partition.groups[from] != partition.groups[to]
+        });
+        if !lost && !partitioned {
+            self.schedule(delay, EventKind::Deliver { from, to, block });
+        }
+    }
+
+    fn deliver(&mut self, from: usize, to: usize, block: Block) {
+        if knows_block(self.blockchain(to), block.hash())
+            || self.rejected.contains(&(to, *block.hash()))
+        {
+            return;
+        }
+        if self.rejected.contains(&(to, *block.prev_hash())) {
+            self.reject(to, &block);
+            return;
+        }
+        if !knows_block(self.blockchain(to), block.prev_hash()) {
+            self.fetch_ancestors(from, to, block);
+            return;
+        }
+        let old_tip = self.blockchain(to).get_max_height_block().hash().clone();
+
+        if !self.nodes[to].handler.process_block(block.clone()) {
+            self.reject(to, &block);
+            return;
+        }
+        if *self.blockchain(to).get_max_height_block().hash() != old_tip {
+            self.nodes[to].last_tip_change = self.now;
+        }
+        if self.is_selfish(to) {
+            let height = self.heights[block.hash()];
+            let private_height = self.blockchain(to).get_max_height();
+            let blocks = self.selfish
+                .as_mut()
+                .unwrap()
+                .on_public_block(block.hash(), height, private_height);
+            self.release(blocks);
+        } else {
+            self.broadcast(to, &block);
+        }
+    }
+
+    fn reject(&mut self, node: usize, block: &Block) {
+        self.rejected_blocks += 1;
+        self.rejected.insert((node, *block.hash()));
+    }
+
+    /// Deliver ancestors of block which node misses, then block itself,
+    /// after request and response delay
+    fn fetch_ancestors(&mut self, from: usize, to: usize, block: Block) {
+        let mut blocks = vec![block];
+
+        loop {
+            let prev_hash = blocks.last().unwrap().prev_hash().clone();
+
+            if knows_block(self.blockchain(to), &prev_hash) {
+                break;
+            }
+            if self.rejected.contains(&(to, prev_hash)) {
+                self.reject(to, &blocks[0]);
+                return;
+            }
+            match self.blockchain(from).block_by_hash(&prev_hash) {
+                Ok(parent) => blocks.push(parent.clone()),
+                Err(_) => return,
+            }
+        }
+        let delay = 2 * self.config.delay;
+
+        for block in blocks.into_iter().rev() {
+            self.schedule(delay, EventKind::Deliver { from, to, block });
+        }
+    }
+
+    fn report(&self, blocks_mined: usize) -> SimReport {
+        // highest chain, the one most nodes are on for equal heights
+        let followers = |node: usize| {
+            let tip = self.blockchain(node).get_max_height_block().hash();
+            let count = self.nodes
+                .iter()
+                .filter(|other| other.handler.blockchain().get_max_height_block().hash() == tip)
+                .count();
+            (self.blockchain(node).get_max_height(), count)
+        };
+        let canonical = (1..self.nodes.len()).fold(0, |best, node| {
+            if followers(node) > followers(best) {
+                node
+            } else {
+                best
+            }
+        });
+        let blockchain = self.blockchain(canonical);
+        let chain_height = blockchain.get_max_height();
+        let mut canonical_blocks = vec![0; self.nodes.len()];
+
+        for height in 1..chain_height + 1 {
+            let block = blockchain.block_at_height(chain_height - height).unwrap();
+
+            if let Some(miner) = self.miner(block.hash()) {
+                canonical_blocks[miner] += 1;
+            }
+        }
+        let tip = blockchain.get_max_height_block().hash();
+        let converged = self.nodes
+            .iter()
+            .all(|node| node.handler.blockchain().get_max_height_block().hash() == tip);
+        let convergence_time = if converged {
+            let last_change = self.nodes.iter().map(|node| node.last_tip_change).max();
+            last_change.map(|time| time.saturating_sub(self.last_block_time))
+        } else {
+            None
+        };
+        let orphan_rate = if blocks_mined > 0 {
+            (blocks_mined - chain_height) as f64 / blocks_mined as f64
+        } else {
+            0.0
+        };
+        SimReport {
+            blocks_mined,
+            chain_height,
+            orphan_rate,
+            deepest_reorg: self.nodes
+                .iter()
+                .map(|node| node.reorgs.borrow().deepest)
+                .max()
+                .unwrap_or(0),
+            convergence_time,
+            rejected_blocks: self.rejected_blocks,
+            canonical_blocks,
+        }
+    }
+}
+
+/// Block is in one of branches, with body or pruned
+fn knows_block(blockchain: &Blockchain, hash: &[u8; 32]) -> bool {
+    match blockchain.block_by_hash(hash) {
+        Err(QueryError::NotFound) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod simulator_tests {
+    use super::*;
+
+    #[test]
+    fn partition_test() {
+        let mut config = SimConfig::new(4);
+        config.seed = 42;
+        config.block_interval = 10_000;
+        config.blocks = 40;
+
+        let report = Simulator::new(config.clone()).run();
+        assert_eq!(report, Simulator::new(config.clone()).run());
+        assert_eq!(40, report.blocks_mined);
+        assert!(report.convergence_time.is_some());
+        assert_eq!(report.chain_height, report.canonical_blocks.iter().sum());
+
+        // halves mine separate chains, the shorter one is reorganized away
+        config.partitions.push(Partition {
+            start: 0,
+            end: 60_000,
+            groups: vec![0, 0, 1, 1],
+        });
+        config.hash_power = vec![3.0, 3.0, 1.0, 1.0];
+        let split = Simulator::new(config.clone()).run();
+        assert!(split.deepest_reorg > 0);
+        assert!(split.orphan_rate > report.orphan_rate);
+        assert!(split.convergence_time.is_some());
+        assert_eq!(0, split.rejected_blocks);
+
+        // fork deeper than cut-off age is rejected by other half, halves stay apart
+        config.cut_off_age = 2;
+        let shallow = Simulator::new(config).run();
+        assert!(shallow.rejected_blocks > 0);
+        assert_eq!(0, shallow.deepest_reorg);
+        assert!(shallow.convergence_time.is_none());
+    }
+
+    #[test]
+    fn config_test() {
+        let json = Json::parse(r#"{"hash_power": [1, 1], "cut_off_age": 20}"#).unwrap();
+        let config = SimConfig::from_json(&json).unwrap();
+        assert_eq!(20, config.cut_off_age);
+        assert_eq!(10, SimConfig::new(2).cut_off_age);
+        let json = Json::parse(r#"{"hash_power": [1, 1], "cut_off_age": 0}"#).unwrap();
+        assert_eq!(None, SimConfig::from_json(&json));
+    }
+}
@@ -0,0 +1,211 @@
+use block::Block;
+use block::BlockHeader;
+use codec::checksum;
+use codec::Decode;
+use codec::Encode;
+use codec::invalid_data;
+use codec::put_u32;
+use codec::put_utxo;
+use codec::put_utxo_entry;
+use codec::Reader;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use utxo::UTXOPool;
+
+/// Marks beginning of snapshot file
+static SNAPSHOT_MAGIC: [u8; 4] = [0x75, 0x73, 0x6e, 0x70];
+/// Snapshot header: magic, payload length and payload checksum
+const HEADER_SIZE: usize = 12;
+
+/// UTXO set of branch at given block together with data needed to continue
+/// chain from that block: headers of all blocks below it and block itself.
+pub struct UtxoSnapshot {
+    /// Headers from genesis block to snapshot block, index is block height
+    pub headers: Vec<BlockHeader>,
+    /// Snapshot block, the tip of chain started from snapshot
+    pub block: Block,
+    pub utxo_pool: UTXOPool,
+}
+
+impl UtxoSnapshot {
+    pub fn height(&self) -> usize {
+        self.headers.len() - 1
+    }
+
+    /// Commitment hash of snapshot UTXO set and snapshot block
+    pub fn commitment(&self) -> [u8; 32] {
+        self.utxo_pool.commitment(self.block.hash())
+    }
+
+    /// Write snapshot to file and return its commitment hash.
+    /// File is replaced atomically.
+    pub fn export(&self, path: &Path) -> io::Result<[u8; 32]> {
+        let commitment = self.commitment();
+        let mut payload = commitment.to_vec();
+        put_u32(&mut payload, self.headers.len() as u32);
+
+        for header in &self.headers {
+            header.encode(&mut payload);
+        }
+        let block = self.block.to_bytes();
+        put_u32(&mut payload, block.len() as u32);
+        payload.extend(block);
+        put_u32(&mut payload, self.utxo_pool.len() as u32);
+
+        for (utxo, entry) in self.utxo_pool.iter() {
+            put_utxo(&mut payload, utxo);
+            put_utxo_entry(&mut payload, entry);
+        }
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&SNAPSHOT_MAGIC)?;
+            let mut header = Vec::new();
+            put_u32(&mut header, payload.len() as u32);
+            header.extend(checksum(&payload).iter());
+            file.write_all(&header)?;
+            file.write_all(&payload)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(commitment)
+    }
+
+    /// Read snapshot from file. Snapshot is rejected if its UTXO set does not
+    /// match commitment stored in file or trusted commitment, if it is given.
+    pub fn import(path: &Path, trusted_commitment: Option<&[u8; 32]>) -> io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        if data.len() < HEADER_SIZE || data[..4] != SNAPSHOT_MAGIC {
+            return Err(invalid_data("Invalid snapshot magic"));
+        }
+        let len = Reader::new(&data[4..8]).u32().unwrap() as usize;
+
+        if data.len() != HEADER_SIZE + len {
+            return Err(invalid_data("Invalid snapshot length"));
+        }
+        let payload = &data[HEADER_SIZE..];
+
+        if checksum(payload) != data[8..HEADER_SIZE] {
+            return Err(invalid_data("Snapshot checksum mismatch"));
+        }
+        let (commitment, snapshot) =
+            Self::decode(payload).ok_or_else(|| invalid_data("Invalid snapshot"))?;
+
+        if let Some(trusted_commitment) = trusted_commitment {
+            if commitment != *trusted_commitment {
+                return Err(invalid_data("Snapshot is not trusted"));
+            }
+        }
+        if snapshot.commitment() != commitment {
+            return Err(invalid_data("Snapshot commitment mismatch"));
+        }
+        Ok(snapshot)
+    }
+
+    /// Decode snapshot and its stored commitment, headers must link
+    /// to each other and end with snapshot block
+    fn decode(payload: &[u8]) -> Option<([u8; 32], Self)> {
+        let mut reader = Reader::new(payload);
+        let commitment = reader.hash()?;
+        let mut headers: Vec<BlockHeader> = Vec::new();
+
+        for _ in 0..reader.u32()? {
+            let header = BlockHeader::decode(&mut reader)?;
+            if let Some(parent) = headers.last() {
+                if header.prev_hash != parent.hash {
+                    return None;
+                }
+            }
+            headers.push(header);
+        }
+        let block_len = reader.u32()? as usize;
+        let block = Block::from_bytes(reader.bytes(block_len)?)?;
+
+        if headers.last() != Some(&block.header()) {
+            return None;
+        }
+        let mut utxo_pool = UTXOPool::new();
+
+        for _ in 0..reader.u32()? {
+            let utxo = reader.utxo()?;
+            let entry = reader.utxo_entry()?;
+
+            if utxo_pool.contains(&utxo) {
+                return None;
+            }
+            utxo_pool.add_UTXO(utxo, entry.tx_out, entry.height, entry.coinbase);
+        }
+        if !reader.is_empty() {
+            return None;
+        }
+        Some((
+            commitment,
+            UtxoSnapshot {
+                headers,
+                block,
+                utxo_pool,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use blockchain::Blockchain;
+    use params::ChainParams;
+    use std::env;
+
+    fn init_block(prev_hash: &[u8; 32], address: &[u8], height: usize) -> Block {
+        let mut block = Block::new(prev_hash.clone(), address.to_vec(), 25.0, height);
+        block.finalize();
+        block
+    }
+
+    #[test]
+    fn export_import_test() {
+        let path = env::temp_dir().join("snapshot_export_import.dat");
+        let genesis = init_block(&[1; 32], b"genesis", 0);
+        let mut blockchain = Blockchain::new(genesis.clone());
+        let mut blocks = vec![genesis];
+
+        for height in 1..5 {
+            let block = init_block(blocks[height - 1].hash(), &[height as u8], height);
+            assert!(blockchain.add_block(block.clone()));
+            blocks.push(block);
+        }
+        // snapshot below tip holds UTXO set of that block
+        let snapshot = blockchain.snapshot(blocks[2].hash()).unwrap();
+        assert_eq!(2, snapshot.height());
+        assert_eq!(3, snapshot.utxo_pool.len());
+        let commitment = snapshot.export(&path).unwrap();
+
+        assert!(UtxoSnapshot::import(&path, Some(&[0; 32])).is_err());
+        let snapshot = UtxoSnapshot::import(&path, Some(&commitment)).unwrap();
+        assert_eq!(commitment, snapshot.commitment());
+
+        // chain started from snapshot follows the same blocks
+        let mut restored = Blockchain::from_snapshot(snapshot, ChainParams::new());
+        assert_eq!(2, restored.get_max_height());
+        assert!(restored.add_block(blocks[3].clone()));
+        assert!(restored.add_block(blocks[4].clone()));
+        let tip = blocks[4].hash();
+        assert_eq!(
+            blockchain.get_max_height_utxo_pool().commitment(tip),
+            restored.get_max_height_utxo_pool().commitment(tip)
+        );
+        // damaged file is rejected
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(UtxoSnapshot::import(&path, None).is_err());
+    }
+}
@@ -0,0 +1,383 @@
+use block::Block;
+use block::BlockHeader;
+use blockchain::BlockError;
+use blockchain::Blockchain;
+use blockchain::QueryError;
+use blockhandler::BlockHandler;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use time::Duration;
+use time::Timespec;
+
+/// Most blocks requested from one peer at a time
+static MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// Blocks are requested only this far above active chain tip,
+/// so downloaded blocks waiting for parents stay few
+static DOWNLOAD_WINDOW: usize = 1024;
+/// Default seconds peer has to deliver requested block
+static DEFAULT_STALL_TIMEOUT: i64 = 10;
+
+/// Invalid data received from peer, peer is banned for it
+#[derive(Debug, PartialEq)]
+pub enum Misbehavior {
+    /// Header hash does not meet proof-of-work target
+    InsufficientWork,
+    /// Header does not follow previous header of message
+    UnlinkedHeader,
+    /// Requested transaction position is beyond block transactions
+    InvalidTxIndex,
+}
+
+/// Headers-first block download. Headers of best chain announced by peers
+/// are validated first: proof-of-work and linkage, without bodies. Bodies
+/// are then requested from several peers at once and connected to
+/// blockchain in header order. Peers not delivering requested block in
+/// time are reported as stalling.
+pub struct SyncManager {
+    /// Height of active chain block which is parent of first header
+    anchor_height: usize,
+    /// Validated headers of best known chain above active chain, parents first
+    headers: VecDeque<BlockHeader>,
+    /// Peer which sent headers of current header chain
+    headers_peer: Option<u64>,
+    /// Best chain height of every peer, as announced or proven by headers
+    peer_heights: HashMap<u64, usize>,
+    /// Requested blocks with peer asked for them and time of request
+    in_flight: HashMap<[u8; 32], (u64, Timespec)>,
+    /// Downloaded blocks waiting for parents, with peer which sent them
+    downloaded: HashMap<[u8; 32], (Block, u64)>,
+    stall_timeout: Duration,
+}
+
+impl SyncManager {
+    pub fn new() -> Self {
+        SyncManager {
+            anchor_height: 0,
+            headers: VecDeque::new(),
+            headers_peer: None,
+            peer_heights: HashMap::new(),
+            in_flight: HashMap::new(),
+            downloaded: HashMap::new(),
+            stall_timeout: Duration::seconds(DEFAULT_STALL_TIMEOUT),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: u64, height: usize) {
+        self.peer_heights.insert(peer, height);
+    }
+
+    /// Forget peer, blocks requested from it are requested from others
+    pub fn remove_peer(&mut self, peer: u64) {
+        self.peer_heights.remove(&peer);
+        self.in_flight.retain(|_, &mut (requested_from, _)| requested_from != peer);
+    }
+
+    /// Height of best known chain, by headers or active chain
+    pub fn best_height(&self, blockchain: &Blockchain) -> usize {
+        cmp::max(
+            self.anchor_height + self.headers.len(),
+            blockchain.get_max_height(),
+        )
+    }
+
+    /// Locator of best known chain, peer answers it with headers after it
+    pub fn locator(&self, blockchain: &Blockchain) -> Vec<[u8; 32]> {
+        let mut locator = Vec::new();
+
+        if let Some(header) = self.headers.back() {
+            locator.push(header.hash);
+        }
+        locator.extend(blockchain.block_locator());
+        locator
+    }
+
+    /// Validate headers received from peer and make them header chain if they
+    /// lead to the best known chain. Headers which do not connect to header
+    /// chain or active chain are ignored, they answer outdated locator.
+    pub fn on_headers(
+        &mut self,
+        peer: u64,
+        headers: Vec<BlockHeader>,
+        blockchain: &Blockchain,
+    ) -> Result<(), Misbehavior> {
+        // received headers are decoded, so their hashes match their content
+        for (index, header) in headers.iter().enumerate() {
+            if !blockchain.params().check_pow(&header.hash) {
+                return Err(Misbehavior::InsufficientWork);
+            }
+            if index > 0 && header.prev_hash != headers[index - 1].hash {
+                return Err(Misbehavior::UnlinkedHeader);
+            }
+        }
+        let prev_hash = match headers.first() {
+            Some(header) => header.prev_hash,
+            None => return Ok(()),
+        };
+        // height of first header parent and number of header chain headers below it
+        let (fork_height, keep) = match self.headers.iter().position(|h| h.hash == prev_hash) {
+            Some(index) => (self.anchor_height + index + 1, Some(index + 1)),
+            None => match blockchain.height_of(&prev_hash) {
+                Some(height) => (height, None),
+                None => return Ok(()),
+            },
+        };
+        let tip_height = fork_height + headers.len();
+        let peer_height = self.peer_heights.entry(peer).or_insert(0);
+        *peer_height = cmp::max(*peer_height, tip_height);
+
+        if tip_height <= self.best_height(blockchain) {
+            return Ok(());
+        }
+        match keep {
+            Some(len) => self.headers.truncate(len),
+            None => {
+                self.anchor_height = fork_height;
+                self.headers.clear();
+            }
+        }
+        self.headers.extend(headers);
+        self.headers_peer = Some(peer);
+        self.trim(blockchain);
+        Ok(())
+    }
+
+    /// Choose blocks of header chain to request from peer: not requested yet,
+    /// not higher than peer chain and inside download window
+    pub fn request_blocks(&mut self, peer: u64, now: Timespec) -> Vec<[u8; 32]> {
+        let peer_height = match self.peer_heights.get(&peer) {
+            Some(&height) => height,
+            None => return Vec::new(),
+        };
+        let requested = self.in_flight
+            .values()
+            .filter(|&&(requested_from, _)| requested_from == peer)
+            .count();
+        let mut hashes = Vec::new();
+
+        for (index, header) in self.headers.iter().enumerate().take(DOWNLOAD_WINDOW) {
+            if requested + hashes.len() >= MAX_BLOCKS_IN_FLIGHT
+                || self.anchor_height + index + 1 > peer_height
+            {
+                break;
+            }
+            if !self.in_flight.contains_key(&header.hash)
+                && !self.downloaded.contains_key(&header.hash)
+            {
+                hashes.push(header.hash);
+            }
+        }
+        for hash in hashes.iter() {
+            self.in_flight.insert(*hash, (peer, now));
+        }
+        hashes
+    }
+
+    /// Keep block of header chain until its parents are connected.
+    /// Return block back if it is not part of header chain.
+    pub fn on_block(&mut self, peer: u64, block: Block) -> Option<Block> {
+        if !self.headers.iter().any(|header| header.hash == *block.hash()) {
+            return Some(block);
+        }
+        self.in_flight.remove(block.hash());
+        self.downloaded.insert(block.hash().clone(), (block, peer));
+        None
+    }
+
+    /// Add downloaded blocks following active chain tip to blockchain.
+    /// Return peers which sent invalid block or headers of it, header
+    /// chain is dropped then. Block which could not be written to disk
+    /// stops connecting and is requested again.
+    pub fn connect_blocks(&mut self, handler: &mut BlockHandler) -> io::Result<Vec<u64>> {
+        self.trim(handler.blockchain());
+
+        loop {
+            let hash = match self.headers.front() {
+                Some(header) => header.hash,
+                None => break,
+            };
+            let (block, peer) = match self.downloaded.remove(&hash) {
+                Some(entry) => entry,
+                None => break,
+            };
+            let known = match handler.blockchain().block_by_hash(&hash) {
+                Err(QueryError::NotFound) => false,
+                _ => true,
+            };
+            let result = if known {
+                Ok(())
+            } else {
+                handler.blockchain_mut().try_add_block(block)
+            };
+            match result {
+                Ok(()) | Err(BlockError::Duplicate) => {}
+                Err(BlockError::Io(kind)) => {
+                    return Err(io::Error::new(kind, "Block could not be written to disk"));
+                }
+                Err(err) => {
+                    // blocks above rejected block are rejected too
+                    self.headers.clear();
+                    self.downloaded.clear();
+                    let source = self.headers_peer.take();
+
+                    match err {
+                        BlockError::InvalidTx
+                        | BlockError::InvalidCoinbase
+                        | BlockError::CoinbaseValueTooHigh => {
+                            let mut misbehaving = vec![peer];
+                            misbehaving.extend(source.filter(|&source| source != peer));
+                            return Ok(misbehaving);
+                        }
+                        // chain moved away from header chain, nobody is to blame
+                        _ => return Ok(Vec::new()),
+                    }
+                }
+            }
+            self.headers.pop_front();
+            self.anchor_height += 1;
+        }
+        Ok(Vec::new())
+    }
+
+    /// Peers which did not deliver requested block in time
+    pub fn stalled(&self, now: Timespec) -> Vec<u64> {
+        let mut peers: Vec<u64> = self.in_flight
+            .values()
+            .filter(|&&(_, requested_at)| now - requested_at > self.stall_timeout)
+            .map(|&(peer, _)| peer)
+            .collect();
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+
+    /// Drop headers of blocks which are in active chain already
+    fn trim(&mut self, blockchain: &Blockchain) {
+        while let Some(height) = self.headers
+            .front()
+            .and_then(|header| blockchain.height_of(&header.hash))
+        {
+            self.headers.pop_front();
+            self.anchor_height = height;
+        }
+    }
+}
+
+#[cfg(test)]
+mod sync_tests {
+    use super::*;
+    use params::ChainParams;
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+    use testutil::FailingStore;
+    use time;
+
+    fn init_handler(genesis: &Block) -> BlockHandler {
+        let mut params = ChainParams::new();
+        params.pow_bits = 4;
+        BlockHandler::new(Blockchain::with_params(genesis.clone(), params))
+    }
+
+    #[test]
+    fn download_test() {
+        let mut genesis = Block::new([0; 32], b"genesis".to_vec(), 25.0, 0);
+        genesis.finalize();
+        let mut source = init_handler(&genesis);
+        let mut blocks = Vec::new();
+
+        for index in 0..5 {
+            blocks.push(source.create_block(format!("miner {}", index).into_bytes()).unwrap());
+        }
+        let headers = source.blockchain().headers_after(&[*genesis.hash()], 2000);
+        let mut handler = init_handler(&genesis);
+        let mut sync = SyncManager::new();
+        sync.add_peer(1, 5);
+        sync.add_peer(2, 5);
+
+        // headers without work or linkage are rejected
+        let mut weak = headers[0].clone();
+
+        while handler.blockchain().params().check_pow(&weak.hash) {
+            weak.nonce += 1;
+            weak.hash = weak.compute_hash();
+        }
+        let result = sync.on_headers(2, vec![weak], handler.blockchain());
+        assert_eq!(Err(Misbehavior::InsufficientWork), result);
+        let unlinked = vec![headers[0].clone(), headers[2].clone()];
+        let result = sync.on_headers(2, unlinked, handler.blockchain());
+        assert_eq!(Err(Misbehavior::UnlinkedHeader), result);
+        assert_eq!(0, sync.best_height(handler.blockchain()));
+
+        assert_eq!(Ok(()), sync.on_headers(1, headers.clone(), handler.blockchain()));
+        assert_eq!(5, sync.best_height(handler.blockchain()));
+        assert_eq!(headers[4].hash, sync.locator(handler.blockchain())[0]);
+
+        // requested blocks are not requested again until peer stalls
+        let now = time::get_time();
+        assert_eq!(5, sync.request_blocks(1, now).len());
+        assert!(sync.request_blocks(2, now).is_empty());
+        assert!(sync.stalled(now).is_empty());
+        let later = now + Duration::seconds(DEFAULT_STALL_TIMEOUT + 1);
+        assert_eq!(vec![1], sync.stalled(later));
+        sync.remove_peer(1);
+        assert_eq!(5, sync.request_blocks(2, later).len());
+
+        // blocks are connected in order whatever order they arrive in
+        for block in blocks.into_iter().rev() {
+            assert!(sync.on_block(2, block).is_none());
+            assert!(sync.connect_blocks(&mut handler).unwrap().is_empty());
+        }
+        assert_eq!(5, handler.blockchain().get_max_height());
+        let tip = handler.blockchain().get_max_height_block().hash();
+        assert_eq!(source.blockchain().get_max_height_block().hash(), tip);
+
+        // block with valid header but invalid body bans peers
+        let mut invalid = Block::new(headers[4].hash, b"greedy".to_vec(), 1000.0, 6);
+        invalid.mine(handler.blockchain().params());
+        let result = sync.on_headers(3, vec![invalid.header()], handler.blockchain());
+        assert_eq!(Ok(()), result);
+        assert_eq!(6, sync.best_height(handler.blockchain()));
+        assert!(sync.on_block(4, invalid).is_none());
+        assert_eq!(vec![4, 3], sync.connect_blocks(&mut handler).unwrap());
+        assert_eq!(5, sync.best_height(handler.blockchain()));
+    }
+
+    #[test]
+    fn write_failure_test() {
+        let dir = env::temp_dir().join("sync_write_failure");
+        let _ = fs::remove_dir_all(&dir);
+        let mut genesis = Block::new([0; 32], b"genesis".to_vec(), 25.0, 0);
+        genesis.finalize();
+        let mut source = init_handler(&genesis);
+        let block = source.create_block(b"miner".to_vec()).unwrap();
+        let headers = source.blockchain().headers_after(&[*genesis.hash()], 2000);
+
+        let fail = Rc::new(Cell::new(false));
+        let store = Box::new(FailingStore::new(fail.clone()));
+        let params = source.blockchain().params().clone();
+        let blockchain = Blockchain::open_with_utxo_store(&dir, genesis, params, store).unwrap();
+        let mut handler = BlockHandler::new(blockchain);
+        let mut sync = SyncManager::new();
+        sync.add_peer(1, 1);
+        assert_eq!(Ok(()), sync.on_headers(1, headers, handler.blockchain()));
+        fail.set(true);
+
+        // disk error is returned, header chain is kept and block is requested again
+        let now = time::get_time();
+        assert_eq!(vec![*block.hash()], sync.request_blocks(1, now));
+        assert!(sync.on_block(1, block.clone()).is_none());
+        let err = sync.connect_blocks(&mut handler).unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+        assert_eq!(0, handler.blockchain().get_max_height());
+        assert_eq!(1, sync.best_height(handler.blockchain()));
+
+        fail.set(false);
+        assert_eq!(vec![*block.hash()], sync.request_blocks(1, now));
+        assert!(sync.on_block(1, block.clone()).is_none());
+        assert!(sync.connect_blocks(&mut handler).unwrap().is_empty());
+        assert_eq!(block.hash(), handler.blockchain().get_max_height_block().hash());
+    }
+}
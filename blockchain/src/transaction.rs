@@ -1,20 +1,33 @@
+use codec::put_u32;
+use codec::put_u64;
+use codec::Decode;
+use codec::Encode;
+use codec::Reader;
+use codec::MAX_ADDRESS_SIZE;
+use codec::MAX_SIGNATURE_SIZE;
 use crypto;
-use std::mem;
+use json_dump::AddressTable;
+use json_dump::FromJson;
+use json_dump::Json;
+use json_dump::ToJson;
 use utxo::UTXO;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct Transaction {
     hash: [u8; 32],
     input_txs: Vec<TransactionInput>,
     output_txs: Vec<TransactionOutput>,
     coinbase: u8, // bool is not supported in bytevec
+    /// Height of block of coinbase transaction, makes hashes of coinbases
+    /// paying same value to same address differ. Zero for other transactions.
+    height: usize,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct TransactionInput {
     pub prev_tx_hash: [u8; 32],
     pub output_index: usize,
-    pub signature: [u8; 32],
+    pub signature: Vec<u8>,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -30,25 +43,52 @@ impl Transaction {
             input_txs: Vec::new(),
             output_txs: Vec::new(),
             coinbase: 0,
+            height: 0,
         }
     }
 
-    pub fn new_coinbase(coin: f64, pub_key: Vec<u8>) -> Self {
+    /// Create coinbase of block at `height`
+    pub fn new_coinbase(coin: f64, pub_key: Vec<u8>, height: usize) -> Self {
         let mut tx = Transaction {
             hash: [0; 32],
             input_txs: Vec::new(),
             output_txs: Vec::new(),
             coinbase: 1,
+            height,
         };
         tx.add_output_tx(coin, pub_key);
         tx.finalize();
         tx
     }
 
+    /// Create finalized transaction from inputs and outputs,
+    /// height is ignored unless transaction is coinbase
+    pub fn from_parts(
+        input_txs: Vec<TransactionInput>,
+        output_txs: Vec<TransactionOutput>,
+        coinbase: u8,
+        height: usize,
+    ) -> Self {
+        let mut tx = Transaction {
+            hash: [0; 32],
+            input_txs,
+            output_txs,
+            coinbase,
+            height: if coinbase != 0 { height } else { 0 },
+        };
+        tx.finalize();
+        tx
+    }
+
     pub fn is_coinbase(&self) -> u8 {
         return self.coinbase;
     }
 
+    /// Height of block, which coinbase belongs to
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn add_input_tx(&mut self, prav_tx_hash: [u8; 32], output_index: usize) {
         self.input_txs.push(TransactionInput::new(
             prav_tx_hash,
@@ -79,26 +119,39 @@ impl Transaction {
         }
         let input_tx = self.input_txs.get(index).unwrap();
         data.append(&mut input_tx.raw_data_to_sign());
+        put_u32(&mut data, self.output_txs.len() as u32);
         for output_tx in &self.output_txs {
-            data.append(&mut output_tx.raw_data());
+            output_tx.encode(&mut data);
         }
         data
     }
 
+    /// Bytes hashed into transaction hash: canonical encoding without signatures,
+    /// so other valid signature of the same spend does not change the hash
     pub fn raw_data(&self) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
+        data.push(self.coinbase);
 
+        if self.coinbase != 0 {
+            put_u64(&mut data, self.height as u64);
+        }
+        put_u32(&mut data, self.input_txs.len() as u32);
         for input_tx in &self.input_txs {
             data.append(&mut input_tx.raw_data());
         }
-
+        put_u32(&mut data, self.output_txs.len() as u32);
         for output_tx in &self.output_txs {
             data.append(&mut output_tx.raw_data());
         }
         data
     }
 
-    pub fn add_signature(&mut self, signature: [u8; 32], index: usize) {
+    /// Size of canonical encoding, signatures included
+    pub fn size(&self) -> usize {
+        self.to_bytes().len()
+    }
+
+    pub fn add_signature(&mut self, signature: Vec<u8>, index: usize) {
         if let Some(ref mut intput_tx) = self.input_txs.get_mut(index) {
 
             intput_tx.add_signature(signature);
@@ -143,31 +196,24 @@ impl TransactionInput {
         TransactionInput {
             prev_tx_hash,
             output_index,
-            signature: [0; 32],
+            signature: Vec::new(),
         }
     }
 
-    pub fn add_signature(&mut self, signature: [u8; 32]) {
+    pub fn add_signature(&mut self, signature: Vec<u8>) {
         self.signature = signature;
     }
 
     pub fn raw_data_to_sign(&self) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
-        data.extend(self.prev_tx_hash.to_vec().iter().clone());
-        unsafe {
-            data.extend(
-                mem::transmute::<&usize, &[u8; mem::size_of::<usize>()]>(&self.output_index)
-                    .iter()
-                    .clone(),
-            );
-        }
+        data.extend(self.prev_tx_hash.iter());
+        put_u64(&mut data, self.output_index as u64);
         data
     }
 
+    /// Input without signature, as hashed into transaction hash
     pub fn raw_data(&self) -> Vec<u8> {
-        let mut data = self.raw_data_to_sign();
-        data.extend(self.signature.to_vec().iter().clone());
-        data
+        self.raw_data_to_sign()
     }
 }
 
@@ -177,15 +223,185 @@ impl TransactionOutput {
     }
 
     pub fn raw_data(&self) -> Vec<u8> {
-        let mut data: Vec<u8> = Vec::new();
-        unsafe {
-            data.extend(
-                mem::transmute::<&f64, &[u8; mem::size_of::<f64>()]>(&self.value)
-                    .iter()
-                    .clone(),
-            );
-        }
-        data.append(&mut self.address.clone());
-        data
+        self.to_bytes()
+    }
+}
+
+/// Smallest encoded sizes, used to limit decoded item counts
+const MIN_INPUT_SIZE: usize = 44;
+const MIN_OUTPUT_SIZE: usize = 12;
+pub const MIN_TX_SIZE: usize = 9;
+
+impl Encode for Transaction {
+    fn encode(&self, data: &mut Vec<u8>) {
+        data.push(self.coinbase);
+
+        if self.coinbase != 0 {
+            put_u64(data, self.height as u64);
+        }
+        put_u32(data, self.input_txs.len() as u32);
+
+        for tx_in in &self.input_txs {
+            tx_in.encode(data);
+        }
+        put_u32(data, self.output_txs.len() as u32);
+
+        for tx_out in &self.output_txs {
+            tx_out.encode(data);
+        }
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let coinbase = reader.flag()? as u8;
+        let height = if coinbase != 0 { reader.u64()? as usize } else { 0 };
+        let mut input_txs = Vec::new();
+
+        for _ in 0..reader.count(MIN_INPUT_SIZE)? {
+            input_txs.push(TransactionInput::decode(reader)?);
+        }
+        let mut output_txs = Vec::new();
+
+        for _ in 0..reader.count(MIN_OUTPUT_SIZE)? {
+            output_txs.push(TransactionOutput::decode(reader)?);
+        }
+        Some(Transaction::from_parts(input_txs, output_txs, coinbase, height))
+    }
+}
+
+impl Encode for TransactionInput {
+    fn encode(&self, data: &mut Vec<u8>) {
+        data.extend(self.prev_tx_hash.iter());
+        put_u64(data, self.output_index as u64);
+        put_u32(data, self.signature.len() as u32);
+        data.extend(self.signature.iter());
+    }
+}
+
+impl Decode for TransactionInput {
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let mut tx_in = TransactionInput::new(reader.hash()?, reader.u64()? as usize);
+        let signature_len = reader.u32()? as usize;
+
+        if signature_len > MAX_SIGNATURE_SIZE {
+            return None;
+        }
+        tx_in.signature = reader.bytes(signature_len)?.to_vec();
+        Some(tx_in)
+    }
+}
+
+impl Encode for TransactionOutput {
+    fn encode(&self, data: &mut Vec<u8>) {
+        put_u64(data, self.value.to_bits());
+        put_u32(data, self.address.len() as u32);
+        data.extend(self.address.iter());
+    }
+}
+
+impl Decode for TransactionOutput {
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let value = f64::from_bits(reader.u64()?);
+        let address_len = reader.u32()? as usize;
+
+        if address_len > MAX_ADDRESS_SIZE {
+            return None;
+        }
+        Some(TransactionOutput::new(value, reader.bytes(address_len)?.to_vec()))
+    }
+}
+
+impl ToJson for Transaction {
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        let inputs = self.input_txs
+            .iter()
+            .map(|tx_in| tx_in.to_json(addresses))
+            .collect();
+        let outputs = self.output_txs
+            .iter()
+            .map(|tx_out| tx_out.to_json(addresses))
+            .collect();
+        let mut fields = vec![
+            ("hash", Json::hex(&self.hash)),
+            ("coinbase", Json::Bool(self.coinbase != 0)),
+        ];
+
+        if self.coinbase != 0 {
+            fields.push(("height", Json::Number(self.height as f64)));
+        }
+        fields.push(("inputs", Json::Array(inputs)));
+        fields.push(("outputs", Json::Array(outputs)));
+        Json::object(fields)
+    }
+}
+
+impl FromJson for Transaction {
+    /// Hash may be left out, if it is given it must match transaction
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self> {
+        let coinbase = match json.get("coinbase") {
+            Some(coinbase) => coinbase.as_bool()? as u8,
+            None => 0,
+        };
+        let height = match json.get("height") {
+            Some(height) => height.as_usize()?,
+            None => 0,
+        };
+        let mut input_txs = Vec::new();
+
+        for tx_in in json.get("inputs")?.as_array()? {
+            input_txs.push(TransactionInput::from_json(tx_in, addresses)?);
+        }
+        let mut output_txs = Vec::new();
+
+        for tx_out in json.get("outputs")?.as_array()? {
+            output_txs.push(TransactionOutput::from_json(tx_out, addresses)?);
+        }
+        let tx = Transaction::from_parts(input_txs, output_txs, coinbase, height);
+
+        if let Some(hash) = json.get("hash") {
+            if hash.as_hash()? != tx.hash {
+                return None;
+            }
+        }
+        Some(tx)
+    }
+}
+
+impl ToJson for TransactionInput {
+    fn to_json(&self, _addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("prev_tx_hash", Json::hex(&self.prev_tx_hash)),
+            ("output_index", Json::Number(self.output_index as f64)),
+            ("signature", Json::hex(&self.signature)),
+        ])
+    }
+}
+
+impl FromJson for TransactionInput {
+    fn from_json(json: &Json, _addresses: &AddressTable) -> Option<Self> {
+        let prev_tx_hash = json.get("prev_tx_hash")?.as_hash()?;
+        let mut tx_in = TransactionInput::new(prev_tx_hash, json.get("output_index")?.as_usize()?);
+
+        if let Some(signature) = json.get("signature") {
+            tx_in.signature = signature.as_hex()?;
+        }
+        Some(tx_in)
+    }
+}
+
+impl ToJson for TransactionOutput {
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("value", Json::Number(self.value)),
+            ("address", Json::String(addresses.insert(&self.address))),
+        ])
+    }
+}
+
+impl FromJson for TransactionOutput {
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self> {
+        let value = json.get("value")?.as_f64()?;
+        Some(TransactionOutput::new(value, addresses.address(json.get("address")?)?))
     }
 }
@@ -1,36 +1,664 @@
+use crypto;
+use std::cmp::Ordering;
+use std::collections::btree_set;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::iter::Rev;
+use std::mem;
+use time;
+use time::Duration;
+use time::Timespec;
 use transaction::Transaction;
+use utxo::UTXOPool;
+use utxo::UTXO;
 
+/// Default memory limit for encoded size of pooled transactions in bytes
+static DEFAULT_MAX_SIZE: usize = 5_000_000;
+/// Default age in seconds after which pooled transaction expires
+static DEFAULT_MAX_AGE: i64 = 72 * 60 * 60;
+
+/// Transaction fee per byte of transaction encoding
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate(pub f64);
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &FeeRate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &FeeRate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &FeeRate) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Reason why transaction left pool
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RemovalReason {
+    /// Transaction is included in connected block
+    Confirmed,
+    /// Connected block spends the same outputs
+    Conflict,
+    /// Transaction paying higher fee spends the same outputs
+    Replaced,
+    /// Pool is full and transaction has the lowest fee rate
+    Evicted,
+    /// Transaction stayed in pool longer than max age
+    Expired,
+    /// Chain reorganization removed outputs spent by transaction
+    Invalid,
+}
+
+pub struct PoolEntry {
+    tx: Transaction,
+    fee: f64,
+    size: usize,
+    /// Time when transaction entered the pool
+    timestamp: Timespec,
+}
+
+impl PoolEntry {
+    pub fn tx(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn fee(&self) -> f64 {
+        self.fee
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate(self.fee / self.size as f64)
+    }
+
+    pub fn timestamp(&self) -> Timespec {
+        self.timestamp
+    }
+}
+
+/// Pool of unconfirmed transactions validated against tip UTXO set.
+/// Transactions may spend outputs of other pooled transactions.
+/// Each UTXO is spent by at most one pooled transaction, conflicting
+/// transaction replaces existing ones only if it pays more (replace-by-fee).
 pub struct TransactionPool {
-    pool: HashMap<[u8; 32], Transaction>,
+    pool: HashMap<[u8; 32], PoolEntry>,
+    /// Pooled transaction hash for every UTXO spent in pool
+    spent: HashMap<UTXO, [u8; 32]>,
+    /// Pooled transactions hashes ordered by fee rate, lowest first
+    by_fee_rate: BTreeSet<(FeeRate, [u8; 32])>,
+    /// Encoded size of all pooled transactions
+    size: usize,
+    max_size: usize,
+    max_age: Duration,
+    /// Transactions removed for a reason since last `take_removed`, in order of removal
+    removed: Vec<(Transaction, RemovalReason)>,
 }
 
 impl TransactionPool {
     pub fn new() -> Self {
+        TransactionPool::with_limits(DEFAULT_MAX_SIZE, Duration::seconds(DEFAULT_MAX_AGE))
+    }
+
+    pub fn with_limits(max_size: usize, max_age: Duration) -> Self {
         TransactionPool {
             pool: HashMap::new(),
+            spent: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            size: 0,
+            max_size,
+            max_age,
+            removed: Vec::new(),
+        }
+    }
+
+    /// Validate transaction against `utxo_pool` and pooled transactions
+    /// and add it to pool. Return false if transaction is invalid, conflicts
+    /// with pooled transactions it can not replace or was evicted right away
+    /// due to low fee rate. `height` is height of the next block.
+    pub fn add_tx(
+        &mut self,
+        mut tx: Transaction,
+        utxo_pool: &UTXOPool,
+        height: usize,
+        coinbase_maturity: usize,
+    ) -> bool {
+        tx.finalize();
+        let hash = tx.hash();
+
+        if self.pool.contains_key(&hash) {
+            return false;
+        }
+        let fee = match self.check_tx(&tx, utxo_pool, height, coinbase_maturity) {
+            Some(fee) => fee,
+            None => return false,
+        };
+        let size = tx.size();
+
+        if size > self.max_size {
+            return false;
+        }
+        let conflicts = self.conflicts(&tx);
+
+        if !conflicts.is_empty() {
+            if !self.can_replace(&tx, fee, size, &conflicts) {
+                return false;
+            }
+            for hash in Self::sorted(conflicts) {
+                self.remove_for(&hash, RemovalReason::Replaced);
+            }
         }
+        let entry = PoolEntry {
+            tx,
+            fee,
+            size,
+            timestamp: time::get_time(),
+        };
+        self.insert(hash, entry);
+        self.trim();
+        self.pool.contains_key(&hash)
     }
 
-    pub fn add_tx(&mut self, tx: Transaction) {
+    /// Remove transaction from pool. Transactions spending its outputs stay in pool,
+    /// as it is expected that transaction was confirmed.
+    pub fn remove_tx(&mut self, hash: &[u8; 32]) -> Option<Transaction> {
+        let entry = self.pool.remove(hash)?;
+        self.by_fee_rate.remove(&(entry.fee_rate(), hash.clone()));
+
+        for tx_in in entry.tx.get_inputs() {
+            let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
+
+            if self.spent.get(&utxo) == Some(hash) {
+                self.spent.remove(&utxo);
+            }
+        }
+        self.size -= entry.size;
+        Some(entry.tx)
+    }
+
+    /// Remove transaction together with all pooled transactions spending its outputs
+    pub fn remove_with_descendants(&mut self, hash: &[u8; 32]) -> Vec<Transaction> {
+        let mut res = Vec::new();
+
+        for hash in self.with_descendants(hash) {
+            if let Some(tx) = self.remove_tx(&hash) {
+                res.push(tx);
+            }
+        }
+        res
+    }
+
+    /// Remove confirmed transaction and all pooled transactions
+    /// (with descendants) which spend the same outputs
+    pub fn remove_confirmed(&mut self, tx: &Transaction) -> Vec<Transaction> {
         let hash = tx.hash();
-        self.pool.insert(hash, tx);
+
+        if let Some(tx) = self.remove_tx(&hash) {
+            self.removed.push((tx, RemovalReason::Confirmed));
+        }
+        let mut res = Vec::new();
+
+        for conflict in Self::sorted(self.conflicts(tx)) {
+            res.extend(self.remove_for(&conflict, RemovalReason::Conflict));
+        }
+        res
+    }
+
+    /// Drop transactions which stay in pool longer than max age
+    pub fn expire(&mut self, now: Timespec) -> Vec<Transaction> {
+        let expired: HashSet<[u8; 32]> = self.pool
+            .iter()
+            .filter(|&(_, entry)| now - entry.timestamp > self.max_age)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        let mut res = Vec::new();
+
+        for hash in Self::sorted(expired) {
+            res.extend(self.remove_for(&hash, RemovalReason::Expired));
+        }
+        res
+    }
+
+    /// Remove transactions (with descendants) spending outputs which are neither
+    /// in `utxo_pool` nor created by pooled transactions, or spending immature
+    /// coinbase outputs. `height` is height of the next block.
+    pub fn remove_invalid(
+        &mut self,
+        utxo_pool: &UTXOPool,
+        height: usize,
+        coinbase_maturity: usize,
+    ) -> Vec<Transaction> {
+        let invalid: HashSet<[u8; 32]> = self.pool
+            .iter()
+            .filter(|&(_, entry)| {
+                entry.tx.get_inputs().iter().any(|tx_in| {
+                    let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
+
+                    match utxo_pool.get_entry(&utxo) {
+                        Some(entry) => !entry.is_mature(height, coinbase_maturity),
+                        None => !self.pool.contains_key(&utxo.hash),
+                    }
+                })
+            })
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        let mut res = Vec::new();
+
+        for hash in Self::sorted(invalid) {
+            res.extend(self.remove_for(&hash, RemovalReason::Invalid));
+        }
+        res
     }
 
-    pub fn remove_tx(&mut self, hash: &[u8; 32]) {
-        self.pool.remove(hash);
+    /// Transactions removed since last call with reason of removal, in order
+    /// of removal. Transactions removed by `remove_tx` and
+    /// `remove_with_descendants` are not reported.
+    pub fn take_removed(&mut self) -> Vec<(Transaction, RemovalReason)> {
+        mem::replace(&mut self.removed, Vec::new())
     }
 
-    pub fn get_tx(&mut self, hash: &[u8; 32]) -> Transaction {
-        self.pool.remove(hash).unwrap()
+    pub fn get_tx(&self, hash: &[u8; 32]) -> Option<&Transaction> {
+        self.pool.get(hash).map(|entry| entry.tx())
     }
 
-    pub fn get_all_txs(&mut self) -> Vec<Transaction> {
-        let mut res: Vec<Transaction> = Vec::new();
+    pub fn get_entry(&self, hash: &[u8; 32]) -> Option<&PoolEntry> {
+        self.pool.get(hash)
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.pool.contains_key(hash)
+    }
 
-        for (_, val) in self.pool.drain() {
-            res.push(val);
+    /// Hashes of pooled transactions whose outputs transaction spends
+    /// directly or through other pooled transactions
+    pub fn ancestors(&self, hash: &[u8; 32]) -> HashSet<[u8; 32]> {
+        let mut res = HashSet::new();
+        let mut hashes = vec![hash.clone()];
+
+        while let Some(hash) = hashes.pop() {
+            let entry = match self.pool.get(&hash) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for tx_in in entry.tx.get_inputs() {
+                let parent = tx_in.prev_tx_hash.clone();
+
+                if self.pool.contains_key(&parent) && res.insert(parent.clone()) {
+                    hashes.push(parent);
+                }
+            }
         }
         res
     }
+
+    /// Iterate over pooled transactions, highest fee rate first
+    pub fn iter(&self) -> Iter {
+        Iter {
+            pool: &self.pool,
+            keys: self.by_fee_rate.iter().rev(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    /// Encoded size of all pooled transactions
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Return transaction fee if transaction is valid
+    fn check_tx(
+        &self,
+        tx: &Transaction,
+        utxo_pool: &UTXOPool,
+        height: usize,
+        coinbase_maturity: usize,
+    ) -> Option<f64> {
+        if tx.is_coinbase() == 1 {
+            return None;
+        }
+        let mut utxo_set = HashSet::new();
+        let mut txs_in_value = 0.0;
+        let mut txs_out_value = 0.0;
+
+        for (index, tx_in) in tx.get_inputs().iter().enumerate() {
+            let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
+            // output is confirmed on tip or created by pooled transaction
+            let tx_out = match utxo_pool.get_entry(&utxo) {
+                Some(entry) if entry.is_mature(height, coinbase_maturity) => &entry.tx_out,
+                Some(_) => return None,
+                None => self.pool
+                    .get(&utxo.hash)
+                    .and_then(|entry| entry.tx.get_outputs().get(utxo.index))?,
+            };
+            let sign_msg = crypto::double_sha256(&tx.raw_data_to_sign(index));
+
+            if crypto::verify_signature(&tx_out.address, &sign_msg, &tx_in.signature) == false {
+                return None;
+            }
+            if utxo_set.contains(&utxo) {
+                return None;
+            }
+            txs_in_value += tx_out.value;
+            utxo_set.insert(utxo);
+        }
+
+        for tx_out in tx.get_outputs() {
+            if !tx_out.value.is_finite() || tx_out.value < 0.0 {
+                return None;
+            }
+            txs_out_value += tx_out.value;
+        }
+
+        if txs_in_value < txs_out_value {
+            return None;
+        }
+        Some(txs_in_value - txs_out_value)
+    }
+
+    fn insert(&mut self, hash: [u8; 32], entry: PoolEntry) {
+        for tx_in in entry.tx.get_inputs() {
+            let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
+            self.spent.insert(utxo, hash.clone());
+        }
+        self.size += entry.size;
+        self.by_fee_rate.insert((entry.fee_rate(), hash.clone()));
+        self.pool.insert(hash, entry);
+    }
+
+    /// Evict lowest fee rate transactions until pool fits into memory limit
+    fn trim(&mut self) {
+        while self.size > self.max_size {
+            let hash = match self.by_fee_rate.iter().next() {
+                Some(&(_, hash)) => hash,
+                None => break,
+            };
+            self.remove_for(&hash, RemovalReason::Evicted);
+        }
+    }
+
+    /// Remove transaction with descendants and remember reason of removal
+    fn remove_for(&mut self, hash: &[u8; 32], reason: RemovalReason) -> Vec<Transaction> {
+        let res = self.remove_with_descendants(hash);
+        self.removed.extend(res.iter().map(|tx| (tx.clone(), reason)));
+        res
+    }
+
+    /// Hashes in ascending order, so removal order does not depend on hashing
+    fn sorted(hashes: HashSet<[u8; 32]>) -> Vec<[u8; 32]> {
+        let mut hashes: Vec<[u8; 32]> = hashes.into_iter().collect();
+        hashes.sort();
+        hashes
+    }
+
+    /// Hashes of pooled transactions spending the same outputs as transaction
+    fn conflicts(&self, tx: &Transaction) -> HashSet<[u8; 32]> {
+        let hash = tx.hash();
+        tx.get_inputs()
+            .iter()
+            .filter_map(|tx_in| {
+                let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
+                self.spent.get(&utxo)
+            })
+            .filter(|&conflict| *conflict != hash)
+            .cloned()
+            .collect()
+    }
+
+    /// Replacement should pay strictly more fee than all replaced transactions
+    /// (conflicts with descendants) together, have higher fee rate than any
+    /// conflict and should not spend outputs of transactions it replaces
+    fn can_replace(
+        &self,
+        tx: &Transaction,
+        fee: f64,
+        size: usize,
+        conflicts: &HashSet<[u8; 32]>,
+    ) -> bool {
+        let fee_rate = FeeRate(fee / size as f64);
+        let mut replaced = HashSet::new();
+
+        for hash in conflicts {
+            if self.pool[hash].fee_rate() >= fee_rate {
+                return false;
+            }
+            replaced.extend(self.with_descendants(hash));
+        }
+        if tx.get_inputs()
+            .iter()
+            .any(|tx_in| replaced.contains(&tx_in.prev_tx_hash))
+        {
+            return false;
+        }
+        let replaced_fee: f64 = replaced.iter().map(|hash| self.pool[hash].fee).sum();
+        fee > replaced_fee
+    }
+
+    /// Hashes of pooled transaction and all pooled transactions
+    /// spending its outputs directly or through other pooled transactions
+    fn with_descendants(&self, hash: &[u8; 32]) -> Vec<[u8; 32]> {
+        let mut res = Vec::new();
+        let mut visited = HashSet::new();
+        let mut hashes = vec![hash.clone()];
+
+        while let Some(hash) = hashes.pop() {
+            let entry = match self.pool.get(&hash) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if !visited.insert(hash.clone()) {
+                continue;
+            }
+            for index in 0..entry.tx.outputs_len() {
+                if let Some(child) = self.spent.get(&UTXO::new(hash.clone(), index)) {
+                    hashes.push(child.clone());
+                }
+            }
+            res.push(hash);
+        }
+        res
+    }
+}
+
+/// Iterator over pooled transactions ordered by fee rate, highest first
+pub struct Iter<'a> {
+    pool: &'a HashMap<[u8; 32], PoolEntry>,
+    keys: Rev<btree_set::Iter<'a, (FeeRate, [u8; 32])>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Transaction;
+
+    fn next(&mut self) -> Option<&'a Transaction> {
+        self.keys.next().map(|&(_, ref hash)| self.pool[hash].tx())
+    }
+}
+
+#[cfg(test)]
+mod transaction_pool_tests {
+    use super::*;
+    use testutil::Funds;
+
+    fn reasons(tx_pool: &mut TransactionPool) -> Vec<([u8; 32], RemovalReason)> {
+        tx_pool
+            .take_removed()
+            .into_iter()
+            .map(|(tx, reason)| (tx.hash(), reason))
+            .collect()
+    }
+
+    #[test]
+    fn eviction_test() {
+        let funds = Funds::new(4);
+        // transactions of equal size paying fees 1, 3, 2 and 0.5
+        let txs: Vec<Transaction> = [9.0, 7.0, 8.0, 9.5]
+            .iter()
+            .enumerate()
+            .map(|(index, &value)| funds.spend(&[Funds::coin(index)], &[value]))
+            .collect();
+        let size = txs[0].size();
+        let mut tx_pool = TransactionPool::with_limits(2 * size, Duration::hours(1));
+
+        assert!(funds.add(&mut tx_pool, &txs[0]));
+        assert!(funds.add(&mut tx_pool, &txs[1]));
+        assert_eq!(2 * size, tx_pool.size());
+
+        // full pool evicts lowest fee rate transaction
+        assert!(funds.add(&mut tx_pool, &txs[2]));
+        assert_eq!(vec![(txs[0].hash(), RemovalReason::Evicted)], reasons(&mut tx_pool));
+        let hashes: Vec<[u8; 32]> = tx_pool.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(vec![txs[1].hash(), txs[2].hash()], hashes);
+
+        // transaction paying less than every pooled one is evicted right away
+        assert!(!funds.add(&mut tx_pool, &txs[3]));
+        assert_eq!(vec![(txs[3].hash(), RemovalReason::Evicted)], reasons(&mut tx_pool));
+        assert_eq!(2, tx_pool.len());
+        assert_eq!(2 * size, tx_pool.size());
+    }
+
+    #[test]
+    fn expire_test() {
+        let funds = Funds::new(2);
+        let mut tx_pool = TransactionPool::with_limits(DEFAULT_MAX_SIZE, Duration::seconds(60));
+        let parent = funds.spend(&[Funds::coin(0)], &[9.0]);
+        let child = funds.spend(&[UTXO::new(parent.hash(), 0)], &[8.0]);
+        let other = funds.spend(&[Funds::coin(1)], &[9.0]);
+        let start = Timespec::new(1_000_000, 0);
+
+        for (tx, age) in vec![(&parent, 0), (&child, 40), (&other, 40)] {
+            assert!(funds.add(&mut tx_pool, tx));
+            // entries get fixed timestamps instead of time of adding
+            tx_pool.pool.get_mut(&tx.hash()).unwrap().timestamp = start + Duration::seconds(age);
+        }
+        assert!(tx_pool.expire(start + Duration::seconds(60)).is_empty());
+        assert_eq!(3, tx_pool.len());
+
+        // expired transaction takes its younger descendants with it
+        let expired = tx_pool.expire(start + Duration::seconds(61));
+        assert_eq!(2, expired.len());
+        assert_eq!(vec![other.hash()], tx_pool.iter().map(|tx| tx.hash()).collect::<Vec<_>>());
+        assert_eq!(other.size(), tx_pool.size());
+        let removed = reasons(&mut tx_pool);
+        assert!(removed.contains(&(child.hash(), RemovalReason::Expired)));
+        assert!(removed.iter().all(|&(_, reason)| reason == RemovalReason::Expired));
+
+        assert_eq!(1, tx_pool.expire(start + Duration::seconds(101)).len());
+        assert!(tx_pool.is_empty());
+        assert_eq!(0, tx_pool.size());
+    }
+
+    #[test]
+    fn replace_fee_rate_test() {
+        let funds = Funds::new(1);
+        let mut tx_pool = TransactionPool::new();
+        let original = funds.spend(&[Funds::coin(0)], &[9.0]);
+        assert!(funds.add(&mut tx_pool, &original));
+
+        // higher fee, but extra output makes fee rate lower
+        let larger = funds.spend(&[Funds::coin(0)], &[4.0, 4.9]);
+        let entry = tx_pool.get_entry(&original.hash()).unwrap();
+        let fee_rate = 1.1 / larger.size() as f64;
+        assert!(fee_rate < entry.fee_rate().0);
+        assert!(!funds.add(&mut tx_pool, &larger));
+        assert!(tx_pool.contains(&original.hash()));
+
+        let replacement = funds.spend(&[Funds::coin(0)], &[8.5]);
+        assert!(funds.add(&mut tx_pool, &replacement));
+        assert_eq!(vec![(original.hash(), RemovalReason::Replaced)], reasons(&mut tx_pool));
+        assert_eq!(1, tx_pool.len());
+    }
+
+    #[test]
+    fn replace_descendants_fee_test() {
+        let funds = Funds::new(1);
+        let mut tx_pool = TransactionPool::new();
+        let parent = funds.spend(&[Funds::coin(0)], &[9.0]);
+        let child = funds.spend(&[UTXO::new(parent.hash(), 0)], &[7.0]);
+        assert!(funds.add(&mut tx_pool, &parent));
+        assert!(funds.add(&mut tx_pool, &child));
+
+        // higher fee rate than parent, but less fee than parent and child together
+        let replacement = funds.spend(&[Funds::coin(0)], &[7.5]);
+        assert!(!funds.add(&mut tx_pool, &replacement));
+        assert_eq!(2, tx_pool.len());
+
+        let replacement = funds.spend(&[Funds::coin(0)], &[6.5]);
+        assert!(funds.add(&mut tx_pool, &replacement));
+        let removed = reasons(&mut tx_pool);
+        assert_eq!(2, removed.len());
+        assert!(removed.contains(&(parent.hash(), RemovalReason::Replaced)));
+        assert!(removed.contains(&(child.hash(), RemovalReason::Replaced)));
+        let hashes: Vec<[u8; 32]> = tx_pool.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(vec![replacement.hash()], hashes);
+    }
+
+    #[test]
+    fn replace_spending_replaced_test() {
+        let funds = Funds::new(2);
+        let mut tx_pool = TransactionPool::new();
+        let original = funds.spend(&[Funds::coin(0)], &[9.0]);
+        assert!(funds.add(&mut tx_pool, &original));
+
+        // replacement may not spend outputs of transaction it replaces
+        let inputs = [Funds::coin(0), UTXO::new(original.hash(), 0)];
+        let replacement = funds.spend(&inputs, &[1.0]);
+        assert!(!funds.add(&mut tx_pool, &replacement));
+        assert!(tx_pool.contains(&original.hash()));
+        assert!(reasons(&mut tx_pool).is_empty());
+
+        let replacement = funds.spend(&[Funds::coin(0), Funds::coin(1)], &[1.0]);
+        assert!(funds.add(&mut tx_pool, &replacement));
+        assert!(!tx_pool.contains(&original.hash()));
+    }
+
+    #[test]
+    fn remove_invalid_test() {
+        let funds = Funds::new(2);
+        let mut tx_pool = TransactionPool::new();
+        let parent = funds.spend(&[Funds::coin(0)], &[9.0]);
+        let child = funds.spend(&[UTXO::new(parent.hash(), 0)], &[8.0]);
+        let other = funds.spend(&[Funds::coin(1)], &[9.0]);
+        assert!(funds.add(&mut tx_pool, &parent));
+        assert!(funds.add(&mut tx_pool, &child));
+        assert!(funds.add(&mut tx_pool, &other));
+        assert!(tx_pool.remove_invalid(&funds.utxo_pool, 1, 1).is_empty());
+
+        // output spent by parent left UTXO set, child goes together with parent
+        let mut utxo_pool = funds.utxo_pool.clone();
+        utxo_pool.remove_UTXO(Funds::coin(0));
+        assert_eq!(2, tx_pool.remove_invalid(&utxo_pool, 1, 1).len());
+        let removed = reasons(&mut tx_pool);
+        assert!(removed.contains(&(parent.hash(), RemovalReason::Invalid)));
+        assert!(removed.contains(&(child.hash(), RemovalReason::Invalid)));
+        assert_eq!(vec![other.hash()], tx_pool.iter().map(|tx| tx.hash()).collect::<Vec<_>>());
+
+        // coinbase output is not mature yet
+        let mut utxo_pool = UTXOPool::new();
+        let tx_out = funds.utxo_pool.get_tx_out(&Funds::coin(1)).unwrap().clone();
+        utxo_pool.add_UTXO(Funds::coin(1), tx_out, 1, true);
+        assert_eq!(1, tx_pool.remove_invalid(&utxo_pool, 2, 2).len());
+        assert!(tx_pool.is_empty());
+    }
 }
@@ -1,16 +1,35 @@
 use crypto::*;
 use std::collections::HashSet;
 use transaction::Transaction;
+use undo::BlockUndo;
+use undo::SpentOutput;
+use undo::TxUndo;
 use utxo::*;
 
 pub struct TxHandler<'a> {
     utxo_pool: &'a mut UTXOPool,
+    /// Height of block which includes handled transactions
+    height: usize,
+    coinbase_maturity: usize,
+    /// Sum of fees of handled transactions
+    fees: f64,
+    /// UTXO changes made by handled transactions
+    batch: UtxoBatch,
+    /// Outputs spent by handled transactions
+    undo: BlockUndo,
 }
 
 impl<'a> TxHandler<'a> {
-    pub fn new(utxo_pool: &'a mut UTXOPool) -> Self {
+    pub fn new(utxo_pool: &'a mut UTXOPool, height: usize, coinbase_maturity: usize) -> Self {
         {
-            TxHandler { utxo_pool }
+            TxHandler {
+                utxo_pool,
+                height,
+                coinbase_maturity,
+                fees: 0.0,
+                batch: UtxoBatch::new(),
+                undo: BlockUndo::new(),
+            }
         }
     }
 
@@ -26,10 +45,16 @@ impl<'a> TxHandler<'a> {
             if self.utxo_pool.contains(&utxo) == false {
                 return false;
             }
+            //coinbase outputs are buried deep enough in the current branch
+            let entry = self.utxo_pool.get_entry(&utxo).unwrap();
+
+            if entry.is_mature(self.height, self.coinbase_maturity) == false {
+                return false;
+            }
             //the signatures on each input of tx are valid
             let sign_msg = tx.raw_data_to_sign(index);
             let sign_msg = double_sha256(&sign_msg);
-            let tx_out = self.utxo_pool.get_tx(&utxo);
+            let tx_out = self.utxo_pool.get_tx_out(&utxo).unwrap();
             txs_in_value += tx_out.value;
 
             if verify_signature(&tx_out.address, &sign_msg, &tx_in.signature) == false {
@@ -41,9 +66,9 @@ impl<'a> TxHandler<'a> {
             }
             utxo_set.insert(utxo);
         }
-        //all of tx’s output values are non-negative
+        //all of tx’s output values are finite and non-negative
         for tx_out in tx.get_outputs() {
-            if tx_out.value < 0.0 {
+            if !tx_out.value.is_finite() || tx_out.value < 0.0 {
                 return false;
             }
             txs_out_value += tx_out.value;
@@ -61,18 +86,92 @@ impl<'a> TxHandler<'a> {
             }
             tx.finalize();
             let tx_hash = tx.hash();
+            self.fees += self.fee(&tx);
+            let mut tx_undo = TxUndo::new();
 
             for tx_in in tx.get_inputs() {
                 let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
-                self.utxo_pool.remove_UTXO(utxo);
+                let entry = self.utxo_pool.get_entry(&utxo).unwrap().clone();
+                tx_undo.spent.push(SpentOutput {
+                    utxo: utxo.clone(),
+                    entry,
+                });
+                self.utxo_pool.remove_UTXO(utxo.clone());
+                self.batch.remove(utxo);
             }
+            self.undo.txs.push(tx_undo);
 
             for (index, tx_out) in tx.get_outputs().iter().enumerate() {
                 let utxo = UTXO::new(tx_hash.clone(), index);
-                self.utxo_pool.add_UTXO(utxo, (*tx_out).clone());
+                let entry = UTXOEntry {
+                    tx_out: (*tx_out).clone(),
+                    height: self.height,
+                    coinbase: false,
+                };
+                self.utxo_pool.add_UTXO(utxo.clone(), (*tx_out).clone(), self.height, false);
+                self.batch.add(utxo, entry);
             }
             res.push(tx);
         }
         res
     }
+
+    /// Sum of fees of transactions accepted by `handle_txs`
+    pub fn fees(&self) -> f64 {
+        self.fees
+    }
+
+    /// UTXO changes made by transactions accepted by `handle_txs`
+    /// and outputs they spent
+    pub fn into_changes(self) -> (UtxoBatch, BlockUndo) {
+        (self.batch, self.undo)
+    }
+
+    /// Difference between input and output values of valid transaction
+    fn fee(&self, tx: &Transaction) -> f64 {
+        let mut txs_in_value = 0.0;
+        let mut txs_out_value = 0.0;
+
+        for tx_in in tx.get_inputs() {
+            let utxo = UTXO::new(tx_in.prev_tx_hash.clone(), tx_in.output_index);
+            txs_in_value += self.utxo_pool.get_tx_out(&utxo).unwrap().value;
+        }
+
+        for tx_out in tx.get_outputs() {
+            txs_out_value += tx_out.value;
+        }
+        txs_in_value - txs_out_value
+    }
+}
+
+#[cfg(test)]
+mod txhandler_tests {
+    use super::*;
+    use testutil::TestKey;
+    use transaction::TransactionOutput;
+
+    #[test]
+    fn coinbase_maturity_test() {
+        let key = TestKey::new(0);
+        let mut utxo_pool = UTXOPool::new();
+        let tx_out = TransactionOutput::new(25.0, key.address.clone());
+        utxo_pool.add_UTXO(UTXO::new([1; 32], 0), tx_out.clone(), 5, true);
+        utxo_pool.add_UTXO(UTXO::new([2; 32], 0), tx_out, 5, false);
+
+        let spend = |prev_hash: [u8; 32]| {
+            key.spend(&[UTXO::new(prev_hash, 0)], &[(25.0, &key.address[..])])
+        };
+        let coinbase_spend = spend([1; 32]);
+        let regular_spend = spend([2; 32]);
+
+        // coinbase output created at height 5 matures at height 5 + 3
+        assert!(!TxHandler::new(&mut utxo_pool, 7, 3).is_valid(&coinbase_spend));
+        assert!(TxHandler::new(&mut utxo_pool, 8, 3).is_valid(&coinbase_spend));
+        // other outputs may be spent at once
+        assert!(TxHandler::new(&mut utxo_pool, 6, 3).is_valid(&regular_spend));
+
+        let mut tx_handler = TxHandler::new(&mut utxo_pool, 7, 3);
+        let accepted = tx_handler.handle_txs(vec![coinbase_spend.clone(), regular_spend.clone()]);
+        assert_eq!(vec![regular_spend], accepted);
+    }
 }
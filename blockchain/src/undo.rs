@@ -0,0 +1,74 @@
+use codec::put_u32;
+use codec::put_utxo;
+use codec::put_utxo_entry;
+use codec::Reader;
+use utxo::UTXOEntry;
+use utxo::UTXO;
+
+/// Output spent by transaction, kept to restore it on block disconnect
+#[derive(Clone, PartialEq, Debug)]
+pub struct SpentOutput {
+    pub utxo: UTXO,
+    pub entry: UTXOEntry,
+}
+
+/// Outputs spent by transaction in order of its inputs
+#[derive(Clone, PartialEq, Debug)]
+pub struct TxUndo {
+    pub spent: Vec<SpentOutput>,
+}
+
+/// Data needed to disconnect block: outputs spent by every block
+/// transaction, in order of transactions in block. Outputs created
+/// by block are taken from block itself.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BlockUndo {
+    pub txs: Vec<TxUndo>,
+}
+
+impl TxUndo {
+    pub fn new() -> Self {
+        TxUndo { spent: Vec::new() }
+    }
+}
+
+impl BlockUndo {
+    pub fn new() -> Self {
+        BlockUndo { txs: Vec::new() }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_u32(&mut data, self.txs.len() as u32);
+
+        for tx_undo in &self.txs {
+            put_u32(&mut data, tx_undo.spent.len() as u32);
+
+            for spent in &tx_undo.spent {
+                put_utxo(&mut data, &spent.utxo);
+                put_utxo_entry(&mut data, &spent.entry);
+            }
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(data);
+        let mut undo = BlockUndo::new();
+
+        for _ in 0..reader.u32()? {
+            let mut tx_undo = TxUndo::new();
+
+            for _ in 0..reader.u32()? {
+                let utxo = reader.utxo()?;
+                let entry = reader.utxo_entry()?;
+                tx_undo.spent.push(SpentOutput { utxo, entry });
+            }
+            undo.txs.push(tx_undo);
+        }
+        if !reader.is_empty() {
+            return None;
+        }
+        Some(undo)
+    }
+}
@@ -1,7 +1,14 @@
+use commitment::UtxoCommitment;
+use json_dump::AddressTable;
+use json_dump::FromJson;
+use json_dump::Json;
+use json_dump::ToJson;
+use std::collections::hash_map;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use transaction::TransactionOutput;
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub struct UTXO {
     pub hash: [u8; 32],
     pub index: usize,
@@ -13,16 +20,47 @@ impl UTXO {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct UTXOEntry {
+    pub tx_out: TransactionOutput,
+    /// Height of block which created output
+    pub height: usize,
+    /// Output created by coinbase transaction
+    pub coinbase: bool,
+}
+
+impl UTXOEntry {
+    /// Coinbase output can be spent only in block which is at least
+    /// `coinbase_maturity` blocks above block that created it
+    pub fn is_mature(&self, spend_height: usize, coinbase_maturity: usize) -> bool {
+        !self.coinbase || spend_height >= self.height + coinbase_maturity
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct UTXOPool {
-    pool: HashMap<UTXO, TransactionOutput>,
+    pool: HashMap<UTXO, UTXOEntry>,
 }
 
 impl UTXOPool {
     pub fn new() -> Self {
         UTXOPool { pool: HashMap::new() }
     }
-    pub fn add_UTXO(&mut self, utxo: UTXO, tx_out: TransactionOutput) {
-        self.pool.insert(utxo, tx_out);
+    pub fn add_UTXO(
+        &mut self,
+        utxo: UTXO,
+        tx_out: TransactionOutput,
+        height: usize,
+        coinbase: bool,
+    ) {
+        self.pool.insert(
+            utxo,
+            UTXOEntry {
+                tx_out,
+                height,
+                coinbase,
+            },
+        );
     }
 
     pub fn remove_UTXO(&mut self, utxo: UTXO) {
@@ -30,14 +68,22 @@ impl UTXOPool {
     }
 
     pub fn get_tx(&mut self, utxo: &UTXO) -> TransactionOutput {
-        self.pool.remove(utxo).unwrap()
+        self.pool.remove(utxo).unwrap().tx_out
+    }
+
+    pub fn get_tx_out(&self, utxo: &UTXO) -> Option<&TransactionOutput> {
+        self.pool.get(utxo).map(|entry| &entry.tx_out)
+    }
+
+    pub fn get_entry(&self, utxo: &UTXO) -> Option<&UTXOEntry> {
+        self.pool.get(utxo)
     }
 
     pub fn get_all_txs(self) -> Vec<TransactionOutput> {
         let mut res: Vec<TransactionOutput> = Vec::new();
 
         for (_, val) in self.pool.into_iter() {
-            res.push(val);
+            res.push(val.tx_out);
         }
         res
     }
@@ -45,4 +91,143 @@ impl UTXOPool {
     pub fn contains(&self, utxo: &UTXO) -> bool {
         self.pool.contains_key(utxo)
     }
+
+    pub fn iter(&self) -> hash_map::Iter<UTXO, UTXOEntry> {
+        self.pool.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    /// Order-independent commitment hash of all unspent outputs
+    /// together with hash of block whose state they are
+    pub fn commitment(&self, block_hash: &[u8; 32]) -> [u8; 32] {
+        let mut commitment = UtxoCommitment::new();
+
+        for (utxo, entry) in self.pool.iter() {
+            commitment.insert(utxo, entry);
+        }
+        commitment.finalize(block_hash)
+    }
+
+    pub fn apply(&mut self, batch: &UtxoBatch) {
+        for (utxo, entry) in &batch.added {
+            self.pool.insert(utxo.clone(), entry.clone());
+        }
+        for utxo in &batch.removed {
+            self.pool.remove(utxo);
+        }
+    }
+}
+
+/// Net UTXO set changes made by one or several blocks
+#[derive(Clone)]
+pub struct UtxoBatch {
+    added: HashMap<UTXO, UTXOEntry>,
+    removed: HashSet<UTXO>,
+}
+
+impl UtxoBatch {
+    pub fn new() -> Self {
+        UtxoBatch {
+            added: HashMap::new(),
+            removed: HashSet::new(),
+        }
+    }
+
+    pub fn add(&mut self, utxo: UTXO, entry: UTXOEntry) {
+        self.removed.remove(&utxo);
+        self.added.insert(utxo, entry);
+    }
+
+    /// Spend output, output created in the same batch just disappears
+    pub fn remove(&mut self, utxo: UTXO) {
+        if self.added.remove(&utxo).is_none() {
+            self.removed.insert(utxo);
+        }
+    }
+
+    /// Append changes made after this batch
+    pub fn merge(&mut self, other: UtxoBatch) {
+        for (utxo, entry) in other.added {
+            self.add(utxo, entry);
+        }
+        for utxo in other.removed {
+            self.remove(utxo);
+        }
+    }
+
+    pub fn added(&self) -> &HashMap<UTXO, UTXOEntry> {
+        &self.added
+    }
+
+    pub fn removed(&self) -> &HashSet<UTXO> {
+        &self.removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl ToJson for UTXO {
+    fn to_json(&self, _addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("hash", Json::hex(&self.hash)),
+            ("index", Json::Number(self.index as f64)),
+        ])
+    }
+}
+
+impl FromJson for UTXO {
+    fn from_json(json: &Json, _addresses: &AddressTable) -> Option<Self> {
+        Some(UTXO::new(json.get("hash")?.as_hash()?, json.get("index")?.as_usize()?))
+    }
+}
+
+impl ToJson for UTXOPool {
+    /// Outputs are sorted by transaction hash and index, so equal pools
+    /// give equal dumps
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        let mut utxos: Vec<_> = self.pool.iter().collect();
+        utxos.sort_by_key(|&(utxo, _)| (utxo.hash, utxo.index));
+        let utxos = utxos
+            .into_iter()
+            .map(|(utxo, entry)| {
+                Json::object(vec![
+                    ("hash", Json::hex(&utxo.hash)),
+                    ("index", Json::Number(utxo.index as f64)),
+                    ("value", Json::Number(entry.tx_out.value)),
+                    ("address", Json::String(addresses.insert(&entry.tx_out.address))),
+                    ("height", Json::Number(entry.height as f64)),
+                    ("coinbase", Json::Bool(entry.coinbase)),
+                ])
+            })
+            .collect();
+        Json::object(vec![("utxos", Json::Array(utxos))])
+    }
+}
+
+impl FromJson for UTXOPool {
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self> {
+        let mut pool = UTXOPool::new();
+
+        for utxo_json in json.get("utxos")?.as_array()? {
+            let utxo = UTXO::from_json(utxo_json, addresses)?;
+            let tx_out = TransactionOutput::from_json(utxo_json, addresses)?;
+            let height = utxo_json.get("height")?.as_usize()?;
+            let coinbase = utxo_json.get("coinbase")?.as_bool()?;
+
+            if pool.contains(&utxo) {
+                return None;
+            }
+            pool.add_UTXO(utxo, tx_out, height, coinbase);
+        }
+        Some(pool)
+    }
 }
@@ -0,0 +1,439 @@
+use codec::checksum;
+use codec::put_u32;
+use codec::put_utxo;
+use codec::put_utxo_entry;
+use codec::Reader;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use utxo::UTXOPool;
+use utxo::UtxoBatch;
+
+/// Marks beginning of every UTXO log record
+static RECORD_MAGIC: [u8; 4] = [0x75, 0x74, 0x78, 0x6f];
+/// Record header: magic, payload length and payload checksum
+const HEADER_SIZE: usize = 12;
+/// Record replaces whole UTXO set
+const SNAPSHOT: u8 = 0;
+/// Record applies changes to UTXO set
+const BATCH: u8 = 1;
+/// Number of batch records after which log is compacted into one snapshot
+static COMPACT_RECORDS: usize = 1000;
+static UTXO_FILE: &str = "utxo.dat";
+
+/// UTXO set of best block
+pub trait UtxoStore {
+    /// Hash of block whose state store holds
+    fn best_block(&self) -> Option<&[u8; 32]>;
+
+    /// Apply all UTXO changes of block as one batch and mark block as best.
+    /// Store is not changed if batch can not be written.
+    fn commit(&mut self, batch: UtxoBatch, best_block: [u8; 32]) -> io::Result<()>;
+
+    /// Replace whole UTXO set, used when best block moves to other branch.
+    /// Store is not changed if set can not be written.
+    fn reset(&mut self, pool: &UTXOPool, best_block: [u8; 32]) -> io::Result<()>;
+
+    /// Make committed changes durable
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Copy of whole UTXO set
+    fn to_pool(&self) -> io::Result<UTXOPool>;
+}
+
+/// UTXO set held only in memory
+pub struct MemoryUtxoStore {
+    pool: UTXOPool,
+    best_block: Option<[u8; 32]>,
+}
+
+impl MemoryUtxoStore {
+    pub fn new() -> Self {
+        MemoryUtxoStore {
+            pool: UTXOPool::new(),
+            best_block: None,
+        }
+    }
+}
+
+impl UtxoStore for MemoryUtxoStore {
+    fn best_block(&self) -> Option<&[u8; 32]> {
+        self.best_block.as_ref()
+    }
+
+    fn commit(&mut self, batch: UtxoBatch, best_block: [u8; 32]) -> io::Result<()> {
+        self.pool.apply(&batch);
+        self.best_block = Some(best_block);
+        Ok(())
+    }
+
+    fn reset(&mut self, pool: &UTXOPool, best_block: [u8; 32]) -> io::Result<()> {
+        self.pool = pool.clone();
+        self.best_block = Some(best_block);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn to_pool(&self) -> io::Result<UTXOPool> {
+        Ok(self.pool.clone())
+    }
+}
+
+/// When file store writes committed blocks changes to disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushPolicy {
+    EveryBlock,
+    /// Keep changes in memory and write them as one batch every N blocks
+    EveryBlocks(usize),
+}
+
+/// File-backed UTXO set. Set is not kept in memory, only changes of blocks
+/// committed since last flush are. Changes are appended to log file as
+/// checksummed records. Record is applied completely or not at all on open,
+/// torn record at the end of log is dropped. From time to time log is
+/// compacted into single snapshot record, whole set is read back from log then.
+pub struct FileUtxoStore {
+    path: PathBuf,
+    file: File,
+    /// Best block of committed changes, including not flushed ones
+    best_block: Option<[u8; 32]>,
+    policy: FlushPolicy,
+    /// Changes of blocks committed since last flush
+    pending: UtxoBatch,
+    pending_blocks: usize,
+    /// Batch records written since last snapshot
+    records: usize,
+}
+
+impl FileUtxoStore {
+    pub fn open(dir: &Path, policy: FlushPolicy) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(UTXO_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut best_block = None;
+        let mut records = 0;
+        let mut pos = 0;
+
+        while let Some((kind, record_best_block, _, len)) = read_record(&data[pos..]) {
+            if kind == SNAPSHOT {
+                records = 0;
+            } else {
+                records += 1;
+            }
+            best_block = Some(record_best_block);
+            pos += len;
+        }
+        file.set_len(pos as u64)?;
+
+        Ok(FileUtxoStore {
+            path,
+            file,
+            best_block,
+            policy,
+            pending: UtxoBatch::new(),
+            pending_blocks: 0,
+            records,
+        })
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(record)?;
+        self.file.sync_data()
+    }
+
+    /// Replace log with single snapshot record of whole set, atomically
+    fn write_snapshot(&mut self, pool: &UTXOPool, best_block: &[u8; 32]) -> io::Result<()> {
+        let mut batch = UtxoBatch::new();
+
+        for (utxo, entry) in pool.iter() {
+            batch.add(utxo.clone(), entry.clone());
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&encode_record(SNAPSHOT, best_block, &batch))?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.records = 0;
+        Ok(())
+    }
+
+    /// UTXO set written to log, without pending changes
+    fn read_pool(&self) -> io::Result<UTXOPool> {
+        let mut data = Vec::new();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+        let mut pool = UTXOPool::new();
+        let mut pos = 0;
+
+        while let Some((kind, _, batch, len)) = read_record(&data[pos..]) {
+            if kind == SNAPSHOT {
+                pool = UTXOPool::new();
+            }
+            pool.apply(&batch);
+            pos += len;
+        }
+        Ok(pool)
+    }
+
+    /// Write changes as one record, compact log when it has enough records
+    fn write_batch(&mut self, batch: &UtxoBatch, best_block: &[u8; 32]) -> io::Result<()> {
+        self.write_record(&encode_record(BATCH, best_block, batch))?;
+        self.records += 1;
+
+        if self.records >= COMPACT_RECORDS {
+            let pool = self.read_pool()?;
+            return self.write_snapshot(&pool, best_block);
+        }
+        Ok(())
+    }
+}
+
+impl UtxoStore for FileUtxoStore {
+    fn best_block(&self) -> Option<&[u8; 32]> {
+        self.best_block.as_ref()
+    }
+
+    fn commit(&mut self, batch: UtxoBatch, best_block: [u8; 32]) -> io::Result<()> {
+        let mut pending = self.pending.clone();
+        pending.merge(batch);
+
+        let flush = match self.policy {
+            FlushPolicy::EveryBlock => true,
+            FlushPolicy::EveryBlocks(blocks) => self.pending_blocks + 1 >= blocks,
+        };
+        if flush {
+            self.write_batch(&pending, &best_block)?;
+            self.pending = UtxoBatch::new();
+            self.pending_blocks = 0;
+        } else {
+            self.pending = pending;
+            self.pending_blocks += 1;
+        }
+        self.best_block = Some(best_block);
+        Ok(())
+    }
+
+    fn reset(&mut self, pool: &UTXOPool, best_block: [u8; 32]) -> io::Result<()> {
+        self.write_snapshot(pool, &best_block)?;
+        self.pending = UtxoBatch::new();
+        self.pending_blocks = 0;
+        self.best_block = Some(best_block);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending_blocks == 0 {
+            return Ok(());
+        }
+        let batch = self.pending.clone();
+        let best_block = self.best_block.unwrap();
+        self.write_batch(&batch, &best_block)?;
+        self.pending = UtxoBatch::new();
+        self.pending_blocks = 0;
+        Ok(())
+    }
+
+    fn to_pool(&self) -> io::Result<UTXOPool> {
+        let mut pool = self.read_pool()?;
+        pool.apply(&self.pending);
+        Ok(pool)
+    }
+}
+
+impl Drop for FileUtxoStore {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn encode_record(kind: u8, best_block: &[u8; 32], batch: &UtxoBatch) -> Vec<u8> {
+    let mut payload = vec![kind];
+    payload.extend(best_block.iter());
+    put_u32(&mut payload, batch.added().len() as u32);
+
+    for (utxo, entry) in batch.added() {
+        put_utxo(&mut payload, utxo);
+        put_utxo_entry(&mut payload, entry);
+    }
+    put_u32(&mut payload, batch.removed().len() as u32);
+
+    for utxo in batch.removed() {
+        put_utxo(&mut payload, utxo);
+    }
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+    record.extend(RECORD_MAGIC.iter());
+    put_u32(&mut record, payload.len() as u32);
+    record.extend(checksum(&payload).iter());
+    record.extend(payload);
+    record
+}
+
+/// Return record kind, best block, changes and record length
+/// if data starts with complete valid record
+fn read_record(data: &[u8]) -> Option<(u8, [u8; 32], UtxoBatch, usize)> {
+    if data.len() < HEADER_SIZE || data[..4] != RECORD_MAGIC {
+        return None;
+    }
+    let len = Reader::new(&data[4..8]).u32()? as usize;
+
+    if data.len() < HEADER_SIZE + len {
+        return None;
+    }
+    let payload = &data[HEADER_SIZE..HEADER_SIZE + len];
+
+    if checksum(payload) != data[8..HEADER_SIZE] {
+        return None;
+    }
+    let mut reader = Reader::new(payload);
+    let kind = reader.u8()?;
+    let best_block = reader.hash()?;
+    let mut batch = UtxoBatch::new();
+
+    for _ in 0..reader.u32()? {
+        let utxo = reader.utxo()?;
+        batch.add(utxo, reader.utxo_entry()?);
+    }
+    for _ in 0..reader.u32()? {
+        batch.remove(reader.utxo()?);
+    }
+    if !reader.is_empty() {
+        return None;
+    }
+    Some((kind, best_block, batch, HEADER_SIZE + len))
+}
+
+#[cfg(test)]
+mod utxo_store_tests {
+    use super::*;
+    use std::env;
+    use std::mem;
+    use transaction::TransactionOutput;
+    use utxo::UTXOEntry;
+    use utxo::UTXO;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("utxo_store_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn init_entry(value: f64) -> UTXOEntry {
+        UTXOEntry {
+            tx_out: TransactionOutput {
+                value,
+                address: b"address".to_vec(),
+            },
+            height: 1,
+            coinbase: false,
+        }
+    }
+
+    /// Batch which adds output of transaction `index` and spends output of previous one
+    fn init_batch(index: u8) -> UtxoBatch {
+        let mut batch = UtxoBatch::new();
+        batch.add(UTXO::new([index; 32], 0), init_entry(index as f64));
+
+        if index > 0 {
+            batch.remove(UTXO::new([index - 1; 32], 0));
+        }
+        batch
+    }
+
+    #[test]
+    fn flush_policy_test() {
+        let dir = test_dir("flush_policy");
+        let mut store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlocks(2)).unwrap();
+        store.commit(init_batch(0), [1; 32]).unwrap();
+        assert_eq!(Some(&[1; 32]), store.best_block());
+        assert_eq!(1, store.to_pool().unwrap().len());
+
+        // crash before flush, nothing is written
+        mem::forget(store);
+        let mut store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlocks(2)).unwrap();
+        assert_eq!(None, store.best_block());
+        assert!(store.to_pool().unwrap().is_empty());
+
+        // second block writes both as one batch
+        store.commit(init_batch(0), [1; 32]).unwrap();
+        store.commit(init_batch(1), [2; 32]).unwrap();
+        mem::forget(store);
+        let store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlock).unwrap();
+        assert_eq!(Some(&[2; 32]), store.best_block());
+        let pool = store.to_pool().unwrap();
+        assert_eq!(1, pool.len());
+        assert_eq!(Some(&init_entry(1.0)), pool.get_entry(&UTXO::new([1; 32], 0)));
+
+        // pending changes are written on drop
+        let mut store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlocks(10)).unwrap();
+        store.commit(init_batch(2), [3; 32]).unwrap();
+        drop(store);
+        let store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlock).unwrap();
+        assert_eq!(Some(&[3; 32]), store.best_block());
+        assert!(store.to_pool().unwrap().contains(&UTXO::new([2; 32], 0)));
+    }
+
+    #[test]
+    fn recovery_test() {
+        let dir = test_dir("recovery");
+        let mut store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlock).unwrap();
+        store.commit(init_batch(0), [1; 32]).unwrap();
+        store.commit(init_batch(1), [2; 32]).unwrap();
+        drop(store);
+
+        // last batch is torn, none of its changes are applied
+        let path = dir.join(UTXO_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+        let mut store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlock).unwrap();
+        assert_eq!(Some(&[1; 32]), store.best_block());
+        let pool = store.to_pool().unwrap();
+        assert_eq!(1, pool.len());
+        assert!(pool.contains(&UTXO::new([0; 32], 0)));
+
+        // torn tail is dropped and new batches follow valid ones
+        store.commit(init_batch(1), [2; 32]).unwrap();
+        drop(store);
+        let store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlock).unwrap();
+        assert_eq!(Some(&[2; 32]), store.best_block());
+        let pool = store.to_pool().unwrap();
+        assert_eq!(1, pool.len());
+        assert!(pool.contains(&UTXO::new([1; 32], 0)));
+    }
+
+    #[test]
+    fn reset_test() {
+        let dir = test_dir("reset");
+        let mut store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlocks(10)).unwrap();
+        store.commit(init_batch(0), [1; 32]).unwrap();
+        let mut pool = UTXOPool::new();
+        pool.apply(&init_batch(5));
+        store.reset(&pool, [5; 32]).unwrap();
+        mem::forget(store);
+
+        // snapshot replaces whole set and pending changes
+        let store = FileUtxoStore::open(&dir, FlushPolicy::EveryBlock).unwrap();
+        assert_eq!(Some(&[5; 32]), store.best_block());
+        assert_eq!(pool, store.to_pool().unwrap());
+    }
+}
@@ -0,0 +1,618 @@
+use block::Block;
+use codec::checksum;
+use codec::invalid_data;
+use codec::put_u32;
+use codec::put_u64;
+use codec::put_utxo;
+use codec::put_utxo_entry;
+use codec::Decode;
+use codec::Encode;
+use codec::Reader;
+use crypto::double_sha256;
+use crypto::sign;
+use events::ChainEvent;
+use events::ChainListener;
+use openssl::rsa::Rsa;
+use params::ChainParams;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use transaction::Transaction;
+use transaction_pool::FeeRate;
+use transaction_pool::RemovalReason;
+use undo::BlockUndo;
+use utxo::UTXOEntry;
+use utxo::UTXO;
+
+/// Marks beginning of wallet file
+static WALLET_MAGIC: [u8; 4] = [0x77, 0x6c, 0x6c, 0x74];
+/// Wallet file header: magic, payload length and payload checksum
+const HEADER_SIZE: usize = 12;
+/// Size of generated RSA keys in bits
+static KEY_BITS: u32 = 2048;
+
+/// Reason why wallet could not create transaction
+#[derive(Debug, PartialEq)]
+pub enum WalletError {
+    /// Amount is not positive finite number
+    InvalidAmount,
+    /// Spendable coins do not cover amount and fee
+    InsufficientFunds,
+    /// Wallet has no key for change output
+    NoKeys,
+    /// Private key could not sign input
+    SigningFailed,
+}
+
+/// Coins of wallet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Balance {
+    /// Active chain outputs which can be spent in next block
+    /// and are not spent by pending transactions
+    pub confirmed: f64,
+    /// Outputs of pending transactions paid to wallet
+    pub unconfirmed: f64,
+    /// Coinbase outputs which can not be spent yet
+    pub immature: f64,
+}
+
+/// Keys and coins of user. Wallet follows chain through chain events,
+/// so it should be subscribed to chain with `Blockchain::subscribe`.
+/// It keeps active chain outputs paid to its addresses and pending
+/// transactions: wallet transactions in pool, created by wallet but not
+/// added to pool yet, or left without block on reorg. Chain returns
+/// transactions of disconnected blocks to pool itself, those rejected
+/// by pool stay pending until they are sent again or abandoned.
+pub struct Wallet {
+    /// RSA private keys in PEM format by address, public key in PEM format
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+    /// Addresses in order of key creation, the first one gets change
+    addresses: Vec<Vec<u8>>,
+    /// Active chain outputs paid to wallet addresses
+    coins: HashMap<UTXO, UTXOEntry>,
+    pending: HashMap<[u8; 32], Transaction>,
+    /// Height of active chain tip
+    height: usize,
+    coinbase_maturity: usize,
+}
+
+impl Wallet {
+    pub fn new(params: &ChainParams) -> Self {
+        Wallet {
+            keys: HashMap::new(),
+            addresses: Vec::new(),
+            coins: HashMap::new(),
+            pending: HashMap::new(),
+            height: 0,
+            coinbase_maturity: params.coinbase_maturity,
+        }
+    }
+
+    /// Generate key and return its address
+    pub fn generate_key(&mut self) -> Vec<u8> {
+        let rsa = Rsa::generate(KEY_BITS).expect("Failed to generate RSA key");
+        self.import_key(&rsa.private_key_to_pem().unwrap()).unwrap()
+    }
+
+    /// Add RSA private key in PEM format and return its address.
+    /// Outputs paid to it before are found only by chain events
+    /// delivered after import, on next subscription.
+    pub fn import_key(&mut self, private_key: &[u8]) -> Option<Vec<u8>> {
+        let rsa = Rsa::private_key_from_pem(private_key).ok()?;
+        let address = rsa.public_key_to_pem().ok()?;
+
+        if !self.keys.contains_key(&address) {
+            self.keys.insert(address.clone(), private_key.to_vec());
+            self.addresses.push(address.clone());
+        }
+        Some(address)
+    }
+
+    pub fn addresses(&self) -> &Vec<Vec<u8>> {
+        &self.addresses
+    }
+
+    pub fn is_mine(&self, address: &[u8]) -> bool {
+        self.keys.contains_key(address)
+    }
+
+    pub fn balance(&self) -> Balance {
+        let spent = self.pending_spent();
+        let mut balance = Balance {
+            confirmed: 0.0,
+            unconfirmed: 0.0,
+            immature: 0.0,
+        };
+        for (utxo, entry) in self.coins.iter() {
+            if !entry.is_mature(self.height + 1, self.coinbase_maturity) {
+                balance.immature += entry.tx_out.value;
+            } else if !spent.contains(utxo) {
+                balance.confirmed += entry.tx_out.value;
+            }
+        }
+        for (hash, tx) in self.pending.iter() {
+            for (index, tx_out) in tx.get_outputs().iter().enumerate() {
+                if self.is_mine(&tx_out.address) && !spent.contains(&UTXO::new(*hash, index)) {
+                    balance.unconfirmed += tx_out.value;
+                }
+            }
+        }
+        balance
+    }
+
+    /// Pending transactions, they are sent to pool again after reorg
+    pub fn pending_txs(&self) -> Vec<&Transaction> {
+        self.pending.values().collect()
+    }
+
+    /// Pay amount to address from confirmed coins, largest coins first.
+    /// Fee is fee rate times size of signed transaction, rest of inputs
+    /// goes to change output of the first wallet address. Transaction is
+    /// pending until it is confirmed, so its coins are not spent twice.
+    pub fn create_tx(
+        &mut self,
+        address: Vec<u8>,
+        amount: f64,
+        fee_rate: FeeRate,
+    ) -> Result<Transaction, WalletError> {
+        if !amount.is_finite() || amount <= 0.0 {
+            return Err(WalletError::InvalidAmount);
+        }
+        let change_address = match self.addresses.first() {
+            Some(address) => address.clone(),
+            None => return Err(WalletError::NoKeys),
+        };
+        let spent = self.pending_spent();
+        let mut coins: Vec<(&UTXO, &UTXOEntry)> = self.coins
+            .iter()
+            .filter(|&(utxo, entry)| {
+                !spent.contains(utxo) && entry.is_mature(self.height + 1, self.coinbase_maturity)
+            })
+            .collect();
+        // largest first, order of equal coins does not depend on map order
+        coins.sort_by(|&(a, a_entry), &(b, b_entry)| {
+            b_entry
+                .tx_out
+                .value
+                .partial_cmp(&a_entry.tx_out.value)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| (a.hash, a.index).cmp(&(b.hash, b.index)))
+        });
+        let mut selected = Vec::new();
+        let mut total = 0.0;
+
+        for (utxo, entry) in coins {
+            selected.push((utxo.clone(), entry.tx_out.address.clone()));
+            total += entry.tx_out.value;
+            // change value does not change transaction size
+            let outputs = vec![(amount, address.clone()), (0.0, change_address.clone())];
+            let size = self.sign_tx(&selected, &outputs)?.size();
+            let fee = fee_rate.0 * size as f64;
+
+            if total < amount + fee {
+                continue;
+            }
+            let mut outputs = vec![(amount, address.clone())];
+
+            if total > amount + fee {
+                outputs.push((total - amount - fee, change_address));
+            }
+            let tx = self.sign_tx(&selected, &outputs)?;
+            self.pending.insert(tx.hash(), tx.clone());
+            return Ok(tx);
+        }
+        Err(WalletError::InsufficientFunds)
+    }
+
+    /// Forget pending transaction, for example rejected by pool,
+    /// so its coins can be spent again
+    pub fn abandon_tx(&mut self, hash: &[u8; 32]) -> bool {
+        self.pending.remove(hash).is_some()
+    }
+
+    /// Write keys, coins and pending transactions to file, file is replaced
+    /// atomically. Private keys are stored unencrypted.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, self.height as u64);
+        put_u32(&mut payload, self.addresses.len() as u32);
+
+        for address in self.addresses.iter() {
+            let private_key = &self.keys[address];
+            put_u32(&mut payload, private_key.len() as u32);
+            payload.extend(private_key.iter());
+        }
+        put_u32(&mut payload, self.coins.len() as u32);
+
+        for (utxo, entry) in self.coins.iter() {
+            put_utxo(&mut payload, utxo);
+            put_utxo_entry(&mut payload, entry);
+        }
+        put_u32(&mut payload, self.pending.len() as u32);
+
+        for tx in self.pending.values() {
+            tx.encode(&mut payload);
+        }
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&WALLET_MAGIC)?;
+            let mut header = Vec::new();
+            put_u32(&mut header, payload.len() as u32);
+            header.extend(checksum(&payload).iter());
+            file.write_all(&header)?;
+            file.write_all(&payload)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Read wallet written by `save`
+    pub fn load(path: &Path, params: &ChainParams) -> io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        if data.len() < HEADER_SIZE || data[..4] != WALLET_MAGIC {
+            return Err(invalid_data("Invalid wallet magic"));
+        }
+        let len = Reader::new(&data[4..8]).u32().unwrap() as usize;
+
+        if data.len() != HEADER_SIZE + len {
+            return Err(invalid_data("Invalid wallet length"));
+        }
+        let payload = &data[HEADER_SIZE..];
+
+        if checksum(payload) != data[8..HEADER_SIZE] {
+            return Err(invalid_data("Wallet checksum mismatch"));
+        }
+        Wallet::decode(payload, params).ok_or_else(|| invalid_data("Invalid wallet"))
+    }
+
+    fn decode(payload: &[u8], params: &ChainParams) -> Option<Self> {
+        let mut reader = Reader::new(payload);
+        let mut wallet = Wallet::new(params);
+        wallet.height = reader.u64()? as usize;
+
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            wallet.import_key(reader.bytes(len)?)?;
+        }
+        for _ in 0..reader.u32()? {
+            let utxo = reader.utxo()?;
+            wallet.coins.insert(utxo, reader.utxo_entry()?);
+        }
+        for _ in 0..reader.u32()? {
+            let tx = Transaction::decode(&mut reader)?;
+            wallet.pending.insert(tx.hash(), tx);
+        }
+        if !reader.is_empty() {
+            return None;
+        }
+        Some(wallet)
+    }
+
+    /// Transaction spending given outputs, every input signed by key of its address
+    fn sign_tx(
+        &self,
+        inputs: &[(UTXO, Vec<u8>)],
+        outputs: &[(f64, Vec<u8>)],
+    ) -> Result<Transaction, WalletError> {
+        let mut tx = Transaction::new();
+
+        for &(ref utxo, _) in inputs {
+            tx.add_input_tx(utxo.hash, utxo.index);
+        }
+        for &(value, ref address) in outputs {
+            tx.add_output_tx(value, address.clone());
+        }
+        for (index, &(_, ref address)) in inputs.iter().enumerate() {
+            let msg = double_sha256(&tx.raw_data_to_sign(index));
+            let signature = sign(&self.keys[address], &msg).ok_or(WalletError::SigningFailed)?;
+            tx.add_signature(signature, index);
+        }
+        tx.finalize();
+        Ok(tx)
+    }
+
+    /// Outputs spent by pending transactions
+    fn pending_spent(&self) -> HashSet<UTXO> {
+        self.pending
+            .values()
+            .flat_map(|tx| tx.get_inputs().iter())
+            .map(|input| UTXO::new(input.prev_tx_hash, input.output_index))
+            .collect()
+    }
+
+    /// Transaction pays to wallet or spends its coins
+    fn is_relevant(&self, tx: &Transaction) -> bool {
+        tx.get_outputs().iter().any(|tx_out| self.is_mine(&tx_out.address))
+            || tx.get_inputs().iter().any(|input| {
+                let utxo = UTXO::new(input.prev_tx_hash, input.output_index);
+                self.coins.contains_key(&utxo) || self.pending.contains_key(&utxo.hash)
+            })
+    }
+
+    fn connect(&mut self, block: &Block, height: usize, undo: &BlockUndo) {
+        let mut spent = HashSet::new();
+        self.add_outputs(block.coinbase(), height);
+
+        // transactions may spend outputs of earlier ones, so apply them in order
+        for (tx, tx_undo) in block.txs().iter().zip(undo.txs.iter()) {
+            self.pending.remove(&tx.hash());
+
+            for output in &tx_undo.spent {
+                self.coins.remove(&output.utxo);
+                spent.insert(output.utxo.clone());
+            }
+            self.add_outputs(tx, height);
+        }
+        // pending transactions spending the same outputs can not be confirmed
+        self.pending.retain(|_, tx| {
+            !tx.get_inputs()
+                .iter()
+                .any(|input| spent.contains(&UTXO::new(input.prev_tx_hash, input.output_index)))
+        });
+        self.height = height;
+    }
+
+    /// Undo block changes, wallet transactions of block become pending again
+    fn disconnect(&mut self, block: &Block, height: usize, undo: &BlockUndo) {
+        // later transactions may spend outputs of earlier ones, so revert them first
+        for (tx, tx_undo) in block.txs().iter().zip(undo.txs.iter()).rev() {
+            self.remove_outputs(tx);
+            let mut relevant = false;
+
+            for output in &tx_undo.spent {
+                if self.is_mine(&output.entry.tx_out.address) {
+                    self.coins.insert(output.utxo.clone(), output.entry.clone());
+                    relevant = true;
+                }
+            }
+            if relevant || self.is_relevant(tx) {
+                self.pending.insert(tx.hash(), tx.clone());
+            }
+        }
+        self.remove_outputs(block.coinbase());
+        self.height = height - 1;
+    }
+
+    fn remove_outputs(&mut self, tx: &Transaction) {
+        for index in 0..tx.outputs_len() {
+            self.coins.remove(&UTXO::new(tx.hash(), index));
+        }
+    }
+
+    fn add_outputs(&mut self, tx: &Transaction, height: usize) {
+        for (index, tx_out) in tx.get_outputs().iter().enumerate() {
+            if self.is_mine(&tx_out.address) {
+                let entry = UTXOEntry {
+                    tx_out: tx_out.clone(),
+                    height,
+                    coinbase: tx.is_coinbase() == 1,
+                };
+                self.coins.insert(UTXO::new(tx.hash(), index), entry);
+            }
+        }
+    }
+}
+
+impl ChainListener for Wallet {
+    fn on_event(&mut self, event: &ChainEvent) {
+        match *event {
+            ChainEvent::BlockConnected {
+                block,
+                height,
+                undo,
+            } => self.connect(block, height, undo),
+            ChainEvent::BlockDisconnected {
+                block,
+                height,
+                undo,
+            } => self.disconnect(block, height, undo),
+            ChainEvent::TipChanged { height, .. } => self.height = height,
+            ChainEvent::TxAddedToPool { tx } => {
+                if self.is_relevant(tx) {
+                    self.pending.insert(tx.hash(), tx.clone());
+                }
+            }
+            ChainEvent::TxRemovedFromPool { tx, reason } => {
+                // confirmed transactions are handled with their block
+                if reason != RemovalReason::Confirmed {
+                    self.pending.remove(&tx.hash());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod wallet_tests {
+    use super::*;
+    use blockchain::Blockchain;
+    use std::cell::RefCell;
+    use std::env;
+    use std::rc::Rc;
+    use testutil::TestKey;
+
+    fn init_block(
+        prev_hash: &[u8; 32],
+        address: &[u8],
+        txs: Vec<Transaction>,
+        height: usize,
+    ) -> Block {
+        let mut block = Block::new(prev_hash.clone(), address.to_vec(), 25.0, height);
+
+        for tx in txs {
+            block.add_tx(tx);
+        }
+        block.finalize();
+        block
+    }
+
+    #[test]
+    fn reorg_test() {
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 2;
+        let wallet = Rc::new(RefCell::new(Wallet::new(&params)));
+        let alice = wallet.borrow_mut().generate_key();
+        let genesis = init_block(&[1; 32], &alice, Vec::new(), 0);
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params.clone());
+        blockchain.subscribe(Box::new(wallet.clone())).unwrap();
+        assert_eq!(25.0, wallet.borrow().balance().immature);
+
+        let block1 = init_block(genesis.hash(), b"miner1", Vec::new(), 1);
+        assert!(blockchain.add_block(block1.clone()));
+        assert_eq!(25.0, wallet.borrow().balance().confirmed);
+
+        // fee covers size, the rest of coin is change
+        let err = wallet.borrow_mut().create_tx(b"bob".to_vec(), 30.0, FeeRate(0.0));
+        assert_eq!(Err(WalletError::InsufficientFunds), err);
+        let tx = wallet.borrow_mut().create_tx(b"bob".to_vec(), 10.0, FeeRate(0.001)).unwrap();
+        let fee = 0.001 * tx.size() as f64;
+        assert_eq!(2, tx.outputs_len());
+        assert_eq!(alice, tx.get_output(1).address);
+        assert!((tx.get_output(1).value - (15.0 - fee)).abs() < 1e-9);
+        let balance = wallet.borrow().balance();
+        assert_eq!(0.0, balance.confirmed);
+        assert_eq!(tx.get_output(1).value, balance.unconfirmed);
+        assert!(blockchain.add_tx(tx.clone()));
+
+        let block2 = init_block(block1.hash(), b"miner2", vec![tx.clone()], 2);
+        assert!(blockchain.add_block(block2.clone()));
+        assert!(wallet.borrow().pending_txs().is_empty());
+        assert_eq!(tx.get_output(1).value, wallet.borrow().balance().confirmed);
+
+        // state survives restart
+        let path = env::temp_dir().join("wallet_save_load.dat");
+        wallet.borrow().save(&path).unwrap();
+        let loaded = Wallet::load(&path, &params).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(wallet.borrow().balance(), loaded.balance());
+        assert_eq!(&vec![alice.clone()], loaded.addresses());
+
+        // longer fork without payment brings back spent coin and pending payment
+        let fork2 = init_block(block1.hash(), b"miner3", Vec::new(), 2);
+        let fork3 = init_block(fork2.hash(), b"miner4", Vec::new(), 3);
+        assert!(blockchain.add_block(fork2.clone()));
+        assert!(blockchain.add_block(fork3.clone()));
+        assert_eq!(1, wallet.borrow().pending_txs().len());
+        assert!(blockchain.tx_pool().contains(&tx.hash()));
+        let balance = wallet.borrow().balance();
+        assert_eq!(0.0, balance.confirmed);
+        assert_eq!(tx.get_output(1).value, balance.unconfirmed);
+        assert!(wallet.borrow_mut().abandon_tx(&tx.hash()));
+        assert_eq!(25.0, wallet.borrow().balance().confirmed);
+    }
+
+    #[test]
+    fn coinbase_reorg_test() {
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 1;
+        let wallet = Rc::new(RefCell::new(Wallet::new(&params)));
+        let alice = wallet.borrow_mut().generate_key();
+        let genesis = init_block(&[1; 32], b"genesis", Vec::new(), 0);
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params.clone());
+        blockchain.subscribe(Box::new(wallet.clone())).unwrap();
+
+        let block1 = init_block(genesis.hash(), &alice, Vec::new(), 1);
+        let block2 = init_block(block1.hash(), b"miner2", Vec::new(), 2);
+        assert!(blockchain.add_block(block1.clone()));
+        assert!(blockchain.add_block(block2.clone()));
+        assert_eq!(25.0, wallet.borrow().balance().confirmed);
+        let tx = wallet.borrow_mut().create_tx(b"bob".to_vec(), 10.0, FeeRate(0.0)).unwrap();
+        assert!(blockchain.add_tx(tx.clone()));
+
+        // fork without block 1 takes reward away, pool drops its spend
+        let fork1 = init_block(genesis.hash(), b"other1", Vec::new(), 1);
+        let fork2 = init_block(fork1.hash(), b"other2", Vec::new(), 2);
+        let fork3 = init_block(fork2.hash(), b"other3", Vec::new(), 3);
+        assert!(blockchain.add_block(fork1));
+        assert!(blockchain.add_block(fork2));
+        assert!(blockchain.add_block(fork3));
+        assert!(blockchain.tx_pool().is_empty());
+        assert!(wallet.borrow().pending_txs().is_empty());
+        let balance = wallet.borrow().balance();
+        assert_eq!(0.0, balance.confirmed + balance.unconfirmed + balance.immature);
+    }
+
+    #[test]
+    fn chained_txs_test() {
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 1;
+        let key = TestKey::new(0);
+        let wallet = Rc::new(RefCell::new(Wallet::new(&params)));
+        let alice = wallet.borrow_mut().import_key(&key.private_key).unwrap();
+        let genesis = init_block(&[1; 32], &alice, Vec::new(), 0);
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params.clone());
+        blockchain.subscribe(Box::new(wallet.clone())).unwrap();
+
+        // child spends change of parent in the same block
+        let coin = UTXO::new(genesis.coinbase().hash(), 0);
+        let parent = key.spend(&[coin], &[(24.0, &alice[..])]);
+        let change = UTXO::new(parent.hash(), 0);
+        let child = key.spend(&[change], &[(10.0, b"bob"), (13.0, &alice[..])]);
+        let block1 = init_block(genesis.hash(), b"miner1", Vec::new(), 1);
+        let block2 = init_block(block1.hash(), b"miner2", vec![parent.clone(), child.clone()], 2);
+        assert!(blockchain.add_block(block1.clone()));
+        assert!(blockchain.add_block(block2));
+        let balance = wallet.borrow().balance();
+        assert_eq!(13.0, balance.confirmed);
+        assert_eq!(0.0, balance.unconfirmed);
+
+        // fork without the block makes both pending, only genesis coin is confirmed again
+        let fork2 = init_block(block1.hash(), b"other2", Vec::new(), 2);
+        let fork3 = init_block(fork2.hash(), b"other3", Vec::new(), 3);
+        assert!(blockchain.add_block(fork2));
+        assert!(blockchain.add_block(fork3));
+        assert_eq!(2, wallet.borrow().pending_txs().len());
+        let balance = wallet.borrow().balance();
+        assert_eq!(0.0, balance.confirmed);
+        assert_eq!(13.0, balance.unconfirmed);
+        assert!(wallet.borrow_mut().abandon_tx(&child.hash()));
+        let balance = wallet.borrow().balance();
+        assert_eq!(0.0, balance.confirmed);
+        assert_eq!(24.0, balance.unconfirmed);
+    }
+
+    #[test]
+    fn save_load_test() {
+        let mut params = ChainParams::new();
+        params.coinbase_maturity = 1;
+        let wallet = Rc::new(RefCell::new(Wallet::new(&params)));
+        let alice = wallet.borrow_mut().generate_key();
+        let genesis = init_block(&[1; 32], &alice, Vec::new(), 0);
+        let mut blockchain = Blockchain::with_params(genesis.clone(), params.clone());
+        blockchain.subscribe(Box::new(wallet.clone())).unwrap();
+        assert!(blockchain.add_block(init_block(genesis.hash(), &alice, Vec::new(), 1)));
+        let tx = wallet.borrow_mut().create_tx(b"bob".to_vec(), 10.0, FeeRate(0.0)).unwrap();
+
+        let path = env::temp_dir().join("wallet_pending.dat");
+        wallet.borrow().save(&path).unwrap();
+        let mut loaded = Wallet::load(&path, &params).unwrap();
+        assert_eq!(wallet.borrow().balance(), loaded.balance());
+        assert_eq!(vec![&tx], loaded.pending_txs());
+
+        // loaded key signs, coin spent by pending transaction is not used again
+        let other = loaded.create_tx(b"carol".to_vec(), 20.0, FeeRate(0.0)).unwrap();
+        assert!(blockchain.add_tx(tx.clone()));
+        assert!(blockchain.add_tx(other.clone()));
+        let err = loaded.create_tx(b"carol".to_vec(), 1.0, FeeRate(0.0));
+        assert_eq!(Err(WalletError::InsufficientFunds), err);
+
+        // damaged file is rejected
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let err = Wallet::load(&path, &params).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+extern crate openssl;
+
+use openssl::sha::sha256;
+use std::char;
+use std::collections::BTreeMap;
+use std::io;
+use std::str;
+
+/// Number of address hash bytes shown as address fingerprint
+const FINGERPRINT_SIZE: usize = 20;
+
+/// JSON value of human-readable dumps. Object keeps order of its fields.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Addresses met in dump by their fingerprints. Dump shows only short
+/// fingerprints in place of addresses and ends with this table,
+/// so it can be parsed back.
+pub struct AddressTable {
+    addresses: BTreeMap<String, Vec<u8>>,
+}
+
+/// Type dumped into JSON
+pub trait ToJson {
+    fn to_json(&self, addresses: &mut AddressTable) -> Json;
+
+    /// Pretty printed JSON document with address table
+    fn dump(&self) -> String {
+        self.document().pretty()
+    }
+
+    /// JSON value ended with table of addresses it refers to, as written by `dump`
+    fn document(&self) -> Json {
+        let mut addresses = AddressTable::new();
+        let mut json = self.to_json(&mut addresses);
+
+        if let Json::Object(ref mut fields) = json {
+            fields.push(("addresses".to_string(), addresses.to_json()));
+        }
+        json
+    }
+}
+
+/// Type loaded from JSON dump
+pub trait FromJson: Sized {
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self>;
+
+    /// Load value from JSON document written by `dump` or by hand
+    fn load(text: &str) -> io::Result<Self> {
+        let json = Json::parse(text)?;
+        let addresses = match json.get("addresses") {
+            Some(table) => AddressTable::from_json(table)
+                .ok_or_else(|| invalid_data("Invalid JSON address table"))?,
+            None => AddressTable::new(),
+        };
+        Self::from_json(&json, &addresses).ok_or_else(|| invalid_data("Unexpected JSON content"))
+    }
+}
+
+impl Json {
+    pub fn parse(text: &str) -> io::Result<Json> {
+        let mut parser = Parser {
+            data: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value();
+        parser.skip_whitespace();
+
+        match value {
+            Some(ref value) if parser.pos == parser.data.len() => Ok(value.clone()),
+            _ => Err(invalid_data(&format!("Invalid JSON at byte {}", parser.pos))),
+        }
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Bytes as hex string
+    pub fn hex(bytes: &[u8]) -> Json {
+        Json::String(to_hex(bytes))
+    }
+
+    /// Field of object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields
+                .iter()
+                .find(|&&(ref name, _)| name == key)
+                .map(|&(_, ref value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Non-negative integer number
+    pub fn as_usize(&self) -> Option<usize> {
+        let value = self.as_f64()?;
+
+        if value < 0.0 || value.fract() != 0.0 || value > (1u64 << 53) as f64 {
+            return None;
+        }
+        Some(value as usize)
+    }
+
+    /// Integer number in range of `i32`
+    pub fn as_i32(&self) -> Option<i32> {
+        let value = self.as_f64()?;
+
+        if value.fract() != 0.0
+            || value < i32::min_value() as f64
+            || value > i32::max_value() as f64
+        {
+            return None;
+        }
+        Some(value as i32)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Bytes written as hex string
+    pub fn as_hex(&self) -> Option<Vec<u8>> {
+        from_hex(self.as_str()?)
+    }
+
+    pub fn as_hash(&self) -> Option<[u8; 32]> {
+        let bytes = self.as_hex()?;
+
+        if bytes.len() != 32 {
+            return None;
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&bytes);
+        Some(hash)
+    }
+
+    /// JSON text indented by two spaces
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, Some(0));
+        out.push('\n');
+        out
+    }
+
+    /// JSON text in one line without spaces
+    pub fn compact(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, None);
+        out
+    }
+
+    /// Write value at indent level, or in one line if there is no indent
+    fn write(&self, out: &mut String, indent: Option<usize>) {
+        let inner = indent.map(|indent| indent + 1);
+
+        match *self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(value) => out.push_str(if value { "true" } else { "false" }),
+            // JSON has no NaN and infinity
+            Json::Number(value) if !value.is_finite() => out.push_str("null"),
+            Json::Number(value) => out.push_str(&value.to_string()),
+            Json::String(ref value) => write_string(out, value),
+            Json::Array(ref values) if values.is_empty() => out.push_str("[]"),
+            Json::Array(ref values) => {
+                out.push('[');
+
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        out.push(',');
+                    }
+                    push_indent(out, inner);
+                    value.write(out, inner);
+                }
+                push_indent(out, indent);
+                out.push(']');
+            }
+            Json::Object(ref fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Object(ref fields) => {
+                out.push('{');
+
+                for (index, &(ref name, ref value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        out.push(',');
+                    }
+                    push_indent(out, inner);
+                    write_string(out, name);
+                    out.push_str(if indent.is_some() { ": " } else { ":" });
+                    value.write(out, inner);
+                }
+                push_indent(out, indent);
+                out.push('}');
+            }
+        }
+    }
+}
+
+impl AddressTable {
+    pub fn new() -> Self {
+        AddressTable {
+            addresses: BTreeMap::new(),
+        }
+    }
+
+    /// Remember address and return its fingerprint
+    pub fn insert(&mut self, address: &[u8]) -> String {
+        let fingerprint = fingerprint(address);
+        self.addresses.insert(fingerprint.clone(), address.to_vec());
+        fingerprint
+    }
+
+    /// Address with given fingerprint
+    pub fn get(&self, fingerprint: &str) -> Option<&Vec<u8>> {
+        self.addresses.get(fingerprint)
+    }
+
+    /// Address whose fingerprint is JSON string
+    pub fn address(&self, json: &Json) -> Option<Vec<u8>> {
+        self.get(json.as_str()?).cloned()
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::Object(
+            self.addresses
+                .iter()
+                .map(|(fingerprint, address)| (fingerprint.clone(), Json::String(to_hex(address))))
+                .collect(),
+        )
+    }
+
+    /// Table is rejected if some address does not match its fingerprint
+    pub fn from_json(json: &Json) -> Option<Self> {
+        let mut table = AddressTable::new();
+
+        match *json {
+            Json::Object(ref fields) => for &(ref fingerprint, ref address) in fields {
+                if table.insert(&address.as_hex()?) != *fingerprint {
+                    return None;
+                }
+            },
+            _ => return None,
+        }
+        Some(table)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Short hex hash of address
+pub fn fingerprint(address: &[u8]) -> String {
+    to_hex(&sha256(address)[..FINGERPRINT_SIZE])
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Start new line at indent level, nothing for one line text
+fn push_indent(out: &mut String, indent: Option<usize>) {
+    if let Some(indent) = indent {
+        out.push('\n');
+
+        for _ in 0..indent {
+            out.push_str("  ");
+        }
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Recursive descent parser, `pos` points to first unparsed byte
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+
+        match *self.data.get(self.pos)? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => self.array(),
+            b'{' => self.object(),
+            _ => self.number(),
+        }
+    }
+
+    fn literal(&mut self, text: &str, value: Json) -> Option<Json> {
+        if !self.data[self.pos..].starts_with(text.as_bytes()) {
+            return None;
+        }
+        self.pos += text.len();
+        Some(value)
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+
+        while let Some(&byte) = self.data.get(self.pos) {
+            match byte {
+                b'-' | b'+' | b'.' | b'e' | b'E' => self.pos += 1,
+                byte if (byte as char).is_digit(10) => self.pos += 1,
+                _ => break,
+            }
+        }
+        let text = str::from_utf8(&self.data[start..self.pos]).ok()?;
+
+        // JSON number starts with minus or digit, parse accepts more
+        match text.chars().next() {
+            Some(c) if c == '-' || c.is_digit(10) => text.parse().ok().map(Json::Number),
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+
+        loop {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self.data.get(self.pos)?;
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.code_unit()?;
+
+                            // character outside of basic plane is escaped as surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                if self.data.get(self.pos..self.pos + 2)? != b"\\u" {
+                                    return None;
+                                }
+                                self.pos += 2;
+                                let low = self.code_unit()?;
+
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return None;
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code)?
+                        }
+                        _ => return None,
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+                }
+                byte if byte < 0x20 => return None,
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).ok()
+    }
+
+    /// Four hex digits of `\u` escape
+    fn code_unit(&mut self) -> Option<u32> {
+        let code = self.data.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        u32::from_str_radix(str::from_utf8(code).ok()?, 16).ok()
+    }
+
+    fn array(&mut self) -> Option<Json> {
+        self.pos += 1;
+        let mut values = Vec::new();
+
+        if self.next_is(b']') {
+            return Some(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+
+            if self.next_is(b']') {
+                return Some(Json::Array(values));
+            }
+            if !self.next_is(b',') {
+                return None;
+            }
+        }
+    }
+
+    fn object(&mut self) -> Option<Json> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+
+        if self.next_is(b'}') {
+            return Some(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+
+            if self.data.get(self.pos) != Some(&b'"') {
+                return None;
+            }
+            let name = self.string()?;
+
+            if !self.next_is(b':') {
+                return None;
+            }
+            fields.push((name, self.value()?));
+
+            if self.next_is(b'}') {
+                return Some(Json::Object(fields));
+            }
+            if !self.next_is(b',') {
+                return None;
+            }
+        }
+    }
+
+    /// Skip whitespace and consume byte if it is next one
+    fn next_is(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+
+        if self.data.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&byte) = self.data.get(self.pos) {
+            match byte {
+                b' ' | b'\t' | b'\n' | b'\r' => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod json_tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let text = r#" {"a": [1, -2.5e1, true, null], "b\n\"": "A\t", "c": {}} "#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(Some(-25.0), json.get("a").unwrap().as_array().unwrap()[1].as_f64());
+        assert_eq!(Some("A\t"), json.get("b\n\"").unwrap().as_str());
+        assert_eq!(json, Json::parse(&json.pretty()).unwrap());
+        let compact = r#"{"a":[1,-25,true,null],"b\n\"":"A\t","c":{}}"#;
+        assert_eq!(compact, json.compact());
+
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("[1] 2").is_err());
+        assert!(Json::parse("+1").is_err());
+
+        let json = Json::parse(r#""\ud83d\ude00 \u00e9""#).unwrap();
+        assert_eq!(Some("\u{1f600} \u{e9}"), json.as_str());
+        assert!(Json::parse(r#""\ud83d""#).is_err());
+        assert!(Json::parse(r#""\ud83d\u0041""#).is_err());
+        assert!(Json::parse(r#""\ude00""#).is_err());
+    }
+
+    #[test]
+    fn number_test() {
+        assert_eq!(Some(7), Json::Number(7.0).as_usize());
+        assert_eq!(None, Json::Number(-7.0).as_usize());
+        assert_eq!(Some(-7), Json::Number(-7.0).as_i32());
+        assert_eq!(None, Json::Number(7.5).as_i32());
+        assert_eq!(None, Json::Number(2147483648.0).as_i32());
+        assert_eq!(None, Json::Number(-2147483649.0).as_i32());
+    }
+
+    #[test]
+    fn address_table_test() {
+        let mut addresses = AddressTable::new();
+        let address = addresses.insert(b"address");
+        let table = AddressTable::from_json(&addresses.to_json()).unwrap();
+        assert_eq!(Some(b"address".to_vec()), table.address(&Json::String(address)));
+
+        // address must match its fingerprint
+        let json = Json::object(vec![(&fingerprint(b"other"), Json::hex(b"address"))]);
+        assert!(AddressTable::from_json(&json).is_none());
+    }
+}
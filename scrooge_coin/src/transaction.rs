@@ -1,8 +1,13 @@
 extern crate hex;
+extern crate json_dump;
 extern crate openssl;
 
 use std::mem;
 use self::openssl::sha;
+use self::json_dump::AddressTable;
+use self::json_dump::FromJson;
+use self::json_dump::Json;
+use self::json_dump::ToJson;
 use super::utxo::UTXO;
 
 pub struct Transaction {
@@ -177,6 +182,85 @@ impl TransactionOutput {
     }
 }
 
+impl ToJson for Transaction {
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        let inputs = self.input_txs
+            .iter()
+            .map(|tx_in| tx_in.to_json(addresses))
+            .collect();
+        let outputs = self.output_txs
+            .iter()
+            .map(|tx_out| tx_out.to_json(addresses))
+            .collect();
+        Json::object(vec![
+            ("hash", Json::hex(&self.hash)),
+            ("inputs", Json::Array(inputs)),
+            ("outputs", Json::Array(outputs)),
+        ])
+    }
+}
+
+impl FromJson for Transaction {
+    /// Hash may be left out, if it is given it must match transaction
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self> {
+        let mut tx = Transaction::new();
+
+        for tx_in in json.get("inputs")?.as_array()? {
+            tx.add_input_tx(TransactionInput::from_json(tx_in, addresses)?);
+        }
+        for tx_out in json.get("outputs")?.as_array()? {
+            tx.add_output_tx(TransactionOutput::from_json(tx_out, addresses)?);
+        }
+        tx.finalize();
+
+        if let Some(hash) = json.get("hash") {
+            if hash.as_hex()? != tx.hash {
+                return None;
+            }
+        }
+        Some(tx)
+    }
+}
+
+impl ToJson for TransactionInput {
+    fn to_json(&self, _addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("prev_tx_hash", Json::hex(&self.prev_tx_hash)),
+            ("output_index", Json::Number(self.output_index as f64)),
+            ("signature", Json::hex(&self.signature)),
+        ])
+    }
+}
+
+impl FromJson for TransactionInput {
+    fn from_json(json: &Json, _addresses: &AddressTable) -> Option<Self> {
+        let prev_tx_hash = json.get("prev_tx_hash")?.as_hex()?;
+        let output_index = json.get("output_index")?.as_i32()?;
+        let mut tx_in = TransactionInput::new(prev_tx_hash, output_index);
+
+        if let Some(signature) = json.get("signature") {
+            tx_in.signature = signature.as_hex()?;
+        }
+        Some(tx_in)
+    }
+}
+
+impl ToJson for TransactionOutput {
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("value", Json::Number(self.value)),
+            ("address", Json::String(addresses.insert(&self.address))),
+        ])
+    }
+}
+
+impl FromJson for TransactionOutput {
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<Self> {
+        let value = json.get("value")?.as_f64()?;
+        Some(TransactionOutput::new(value, addresses.address(json.get("address")?)?))
+    }
+}
+
 #[cfg(test)]
 mod transaction_data_tests {
     use super::*;
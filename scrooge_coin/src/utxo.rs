@@ -1,4 +1,10 @@
+extern crate json_dump;
+
 use std::collections::HashMap;
+use self::json_dump::AddressTable;
+use self::json_dump::FromJson;
+use self::json_dump::Json;
+use self::json_dump::ToJson;
 use super::transaction::TransactionOutput;
 
 #[derive(Eq, PartialEq, Hash)]
@@ -18,6 +24,12 @@ pub struct UTXOPool {
 }
 
 impl UTXOPool {
+    pub fn new() -> UTXOPool {
+        UTXOPool {
+            utxo_map: HashMap::new(),
+        }
+    }
+
     pub fn add_UTXO(&mut self, utxo: UTXO, tx_out: TransactionOutput) {
         self.utxo_map.insert(utxo, tx_out);
     }
@@ -43,3 +55,91 @@ impl UTXOPool {
         self.utxo_map.contains_key(utxo)
     }
 }
+
+impl ToJson for UTXO {
+    fn to_json(&self, _addresses: &mut AddressTable) -> Json {
+        Json::object(vec![
+            ("hash", Json::hex(&self.hash)),
+            ("index", Json::Number(self.index as f64)),
+        ])
+    }
+}
+
+impl FromJson for UTXO {
+    fn from_json(json: &Json, _addresses: &AddressTable) -> Option<UTXO> {
+        let hash = json.get("hash")?.as_hex()?;
+        Some(UTXO::new(hash, json.get("index")?.as_i32()?))
+    }
+}
+
+impl ToJson for UTXOPool {
+    /// Outputs are sorted by transaction hash and index, so equal pools
+    /// give equal dumps
+    fn to_json(&self, addresses: &mut AddressTable) -> Json {
+        let mut utxos: Vec<_> = self.utxo_map.iter().collect();
+        utxos.sort_by_key(|&(utxo, _)| (utxo.hash.clone(), utxo.index));
+        let utxos = utxos
+            .into_iter()
+            .map(|(utxo, tx_out)| {
+                Json::object(vec![
+                    ("hash", Json::hex(&utxo.hash)),
+                    ("index", Json::Number(utxo.index as f64)),
+                    ("value", Json::Number(tx_out.value)),
+                    ("address", Json::String(addresses.insert(&tx_out.address))),
+                ])
+            })
+            .collect();
+        Json::object(vec![("utxos", Json::Array(utxos))])
+    }
+}
+
+impl FromJson for UTXOPool {
+    fn from_json(json: &Json, addresses: &AddressTable) -> Option<UTXOPool> {
+        let mut pool = UTXOPool::new();
+
+        for utxo_json in json.get("utxos")?.as_array()? {
+            let utxo = UTXO::from_json(utxo_json, addresses)?;
+            let tx_out = TransactionOutput::from_json(utxo_json, addresses)?;
+
+            if pool.contains(&utxo) {
+                return None;
+            }
+            pool.add_UTXO(utxo, tx_out);
+        }
+        Some(pool)
+    }
+}
+
+#[cfg(test)]
+mod utxo_json_tests {
+    use super::*;
+    use super::super::transaction::Transaction;
+    use super::super::transaction::TransactionInput;
+
+    #[test]
+    fn dump_load_test() {
+        let mut tx = Transaction::new();
+        tx.add_input_tx(TransactionInput::new(vec![3; 32], 1));
+        tx.add_signature(vec![5; 256], 0);
+        tx.add_output_tx(TransactionOutput::new(2.5, b"address".to_vec()));
+        tx.finalize();
+        let dump = tx.dump();
+        let loaded = Transaction::load(&dump).unwrap();
+        assert_eq!(tx.get_hash(), loaded.get_hash());
+        assert_eq!(dump, loaded.dump());
+
+        let mut pool = UTXOPool::new();
+        pool.add_UTXO(UTXO::new(tx.get_hash(), 0), tx.get_outputs()[0].clone());
+        pool.add_UTXO(UTXO::new(vec![1; 32], 2), TransactionOutput::new(1.0, b"other".to_vec()));
+        let dump = pool.dump();
+        let loaded = UTXOPool::load(&dump).unwrap();
+        assert!(loaded.contains(&UTXO::new(vec![1; 32], 2)));
+        assert_eq!(2.5, loaded.get_tx_out(&UTXO::new(tx.get_hash(), 0)).value);
+        assert_eq!(dump, loaded.dump());
+
+        // addresses are shown by fingerprints only
+        assert!(!dump.contains("\"address\": \"6164"));
+        assert!(UTXOPool::load(&dump.replace("\"addresses\"", "\"other\"")).is_err());
+        assert!(UTXOPool::load(&dump.replace("\"index\": 2", "\"index\": 2147483648")).is_err());
+    }
+}